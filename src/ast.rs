@@ -0,0 +1,346 @@
+//! The tree the parser builds from a script's tokens, which the compiler then turns
+//! into bytecode.
+//!
+//! Nodes borrow the tokens they came from, so errors and instructions can point back
+//! at the source. A node that couldn't be parsed is an `Expr::Error`, or an
+//! `Expr::Incomplete` holding the parts before the error; the error has already been
+//! recorded by the parser.
+
+use crate::scanner::Token;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Number(f64),
+    String(String),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Pow,
+    Mod,
+    GreaterThan,
+    GreaterThanEq,
+    LessThan,
+    LessThanEq,
+    Equal,
+    NotEqual,
+    And,
+    Or,
+}
+
+/// What an assignment sets
+#[derive(Debug)]
+pub enum Target<'t> {
+    Variable(&'t Token),
+    // variable[index] = value
+    Subscript {
+        variable: &'t Token,
+        bracket: &'t Token,
+        index: Box<Expr<'t>>,
+    },
+}
+
+#[derive(Debug)]
+pub enum Expr<'t> {
+    Literal {
+        token: &'t Token,
+        value: Literal,
+    },
+    Variable(&'t Token),
+    Assign {
+        target: Target<'t>,
+        value: Box<Expr<'t>>,
+    },
+    Unary {
+        op: UnaryOp,
+        token: &'t Token,
+        operand: Box<Expr<'t>>,
+    },
+    Binary {
+        op: BinaryOp,
+        token: &'t Token,
+        left: Box<Expr<'t>>,
+        right: Box<Expr<'t>>,
+    },
+    Grouping(Box<Expr<'t>>),
+    Map {
+        token: &'t Token,
+        entries: Vec<(Expr<'t>, Expr<'t>)>,
+    },
+    Call {
+        callee: Box<Expr<'t>>,
+        paren: &'t Token,
+        args: Vec<Expr<'t>>,
+    },
+    // @name(args), run by the system the script is running on
+    SystemCall {
+        name: &'t Token,
+        paren: &'t Token,
+        args: Vec<Expr<'t>>,
+    },
+    // receiver.method(args)
+    MethodCall {
+        receiver: Box<Expr<'t>>,
+        dot: &'t Token,
+        method: &'t Token,
+        args: Vec<Expr<'t>>,
+    },
+    Subscript {
+        object: Box<Expr<'t>>,
+        bracket: &'t Token,
+        index: Box<Expr<'t>>,
+    },
+    // value in a, b, c
+    In {
+        value: Box<Expr<'t>>,
+        token: &'t Token,
+        list: Vec<Expr<'t>>,
+    },
+    Lambda(Box<Function<'t>>),
+    Match(Box<Match<'t>>),
+    // the parts of an expression that were parsed before a syntax error, still compiled
+    // so that the names in them are checked
+    Incomplete(Vec<Expr<'t>>),
+    Error,
+}
+
+#[derive(Debug)]
+pub enum Stmt<'t> {
+    Expression(Expr<'t>),
+    If(If<'t>),
+    While {
+        token: &'t Token,
+        condition: Expr<'t>,
+        body: Vec<Stmt<'t>>,
+    },
+    For(For<'t>),
+    Try(Try<'t>),
+    Function(Function<'t>),
+    // the file is compiled before the script that imports it, see main.rs
+    Import {
+        token: &'t Token,
+        path: &'t Token,
+    },
+    Break(&'t Token),
+    Continue(&'t Token),
+    Return(&'t Token),
+}
+
+#[derive(Debug)]
+pub struct If<'t> {
+    pub token: &'t Token, // the 'if' or 'elseif'
+    pub condition: Expr<'t>,
+    pub then_branch: Vec<Stmt<'t>>,
+    pub else_branch: Option<Vec<Stmt<'t>>>,
+    pub else_if: Option<Box<If<'t>>>,
+}
+
+#[derive(Debug)]
+pub struct For<'t> {
+    pub token: &'t Token,
+    pub variable: &'t Token,
+    pub start: Expr<'t>,
+    pub end: Expr<'t>,
+    pub step: f64,
+    pub body: Vec<Stmt<'t>>,
+}
+
+#[derive(Debug)]
+pub struct Try<'t> {
+    pub token: &'t Token,
+    pub body: Vec<Stmt<'t>>,
+    pub catch: &'t Token,
+    pub variable: Option<&'t Token>, // holds the error
+    pub handler: Vec<Stmt<'t>>,
+}
+
+/// A named function, or an anonymous one (`fn(x) x * 2 end`) when name is None
+#[derive(Debug)]
+pub struct Function<'t> {
+    pub token: &'t Token,
+    pub name: Option<&'t Token>,
+    pub params: Vec<&'t Token>,
+    pub body: Vec<Stmt<'t>>,
+}
+
+#[derive(Debug)]
+pub struct Match<'t> {
+    pub token: &'t Token,
+    pub value: Expr<'t>,
+    pub arms: Vec<Arm<'t>>,
+    pub end: &'t Token,
+}
+
+#[derive(Debug)]
+pub enum Arm<'t> {
+    When {
+        token: &'t Token,
+        pattern: Pattern<'t>,
+        body: Vec<Stmt<'t>>,
+    },
+    Else {
+        token: &'t Token,
+        body: Vec<Stmt<'t>>,
+    },
+}
+
+/// What the value of a match is compared with in a 'when'
+#[derive(Debug)]
+pub enum Pattern<'t> {
+    // when 3, when > 3 etc. The op is one of the comparisons
+    Compare(BinaryOp, Expr<'t>),
+    // when 1 to 5
+    Between(Expr<'t>, Expr<'t>),
+    // when 1, 2, 3
+    In(Vec<Expr<'t>>),
+}
+
+fn block_identifiers<'t>(statements: &[Stmt<'t>], names: &mut Vec<&'t Token>) {
+    for statement in statements {
+        statement.identifiers(names);
+    }
+}
+
+impl<'t> Stmt<'t> {
+    /// Adds every identifier in the statement to names, in the order they are written
+    pub fn identifiers(&self, names: &mut Vec<&'t Token>) {
+        match self {
+            Stmt::Expression(expr) => expr.identifiers(names),
+            Stmt::If(statement) => statement.identifiers(names),
+            Stmt::While {
+                condition, body, ..
+            } => {
+                condition.identifiers(names);
+                block_identifiers(body, names);
+            }
+            Stmt::For(statement) => {
+                names.push(statement.variable);
+                statement.start.identifiers(names);
+                statement.end.identifiers(names);
+                block_identifiers(&statement.body, names);
+            }
+            Stmt::Try(statement) => {
+                block_identifiers(&statement.body, names);
+                names.extend(statement.variable);
+                block_identifiers(&statement.handler, names);
+            }
+            Stmt::Function(function) => function.identifiers(names),
+            Stmt::Import { .. } | Stmt::Break(_) | Stmt::Continue(_) | Stmt::Return(_) => (),
+        }
+    }
+}
+
+impl<'t> If<'t> {
+    fn identifiers(&self, names: &mut Vec<&'t Token>) {
+        self.condition.identifiers(names);
+        block_identifiers(&self.then_branch, names);
+        if let Some(else_branch) = &self.else_branch {
+            block_identifiers(else_branch, names);
+        }
+        if let Some(else_if) = &self.else_if {
+            else_if.identifiers(names);
+        }
+    }
+}
+
+impl<'t> Function<'t> {
+    fn identifiers(&self, names: &mut Vec<&'t Token>) {
+        names.extend(self.name);
+        names.extend(&self.params);
+        block_identifiers(&self.body, names);
+    }
+}
+
+impl<'t> Expr<'t> {
+    /// Adds every identifier in the expression to names, in the order they are written
+    pub fn identifiers(&self, names: &mut Vec<&'t Token>) {
+        match self {
+            Expr::Literal { .. } | Expr::Error => (),
+            Expr::Variable(token) => names.push(token),
+            Expr::Assign { target, value } => {
+                match target {
+                    Target::Variable(token) => names.push(token),
+                    Target::Subscript {
+                        variable, index, ..
+                    } => {
+                        names.push(variable);
+                        index.identifiers(names);
+                    }
+                }
+                value.identifiers(names);
+            }
+            Expr::Unary { operand, .. } => operand.identifiers(names),
+            Expr::Binary { left, right, .. } => {
+                left.identifiers(names);
+                right.identifiers(names);
+            }
+            Expr::Grouping(expr) => expr.identifiers(names),
+            Expr::Map { entries, .. } => {
+                for (key, value) in entries {
+                    key.identifiers(names);
+                    value.identifiers(names);
+                }
+            }
+            Expr::Call { callee, args, .. } => {
+                callee.identifiers(names);
+                args.iter().for_each(|arg| arg.identifiers(names));
+            }
+            Expr::SystemCall { name, args, .. } => {
+                names.push(name);
+                args.iter().for_each(|arg| arg.identifiers(names));
+            }
+            Expr::MethodCall {
+                receiver,
+                method,
+                args,
+                ..
+            } => {
+                receiver.identifiers(names);
+                names.push(method);
+                args.iter().for_each(|arg| arg.identifiers(names));
+            }
+            Expr::Subscript { object, index, .. } => {
+                object.identifiers(names);
+                index.identifiers(names);
+            }
+            Expr::In { value, list, .. } => {
+                value.identifiers(names);
+                list.iter().for_each(|expr| expr.identifiers(names));
+            }
+            Expr::Incomplete(parts) => parts.iter().for_each(|expr| expr.identifiers(names)),
+            Expr::Lambda(function) => function.identifiers(names),
+            Expr::Match(statement) => {
+                statement.value.identifiers(names);
+                for arm in &statement.arms {
+                    match arm {
+                        Arm::When { pattern, body, .. } => {
+                            match pattern {
+                                Pattern::Compare(_, expr) => expr.identifiers(names),
+                                Pattern::Between(from, to) => {
+                                    from.identifiers(names);
+                                    to.identifiers(names);
+                                }
+                                Pattern::In(list) => {
+                                    list.iter().for_each(|expr| expr.identifiers(names))
+                                }
+                            }
+                            block_identifiers(body, names);
+                        }
+                        Arm::Else { body, .. } => block_identifiers(body, names),
+                    }
+                }
+            }
+        }
+    }
+}
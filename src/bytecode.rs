@@ -0,0 +1,865 @@
+//! Reading and writing compiled scripts (.vbc files), so they can be run without
+//! tokenizing and compiling the source.
+//!
+//! A file is the header `VBC` + a version byte, followed by the instructions, the line
+//! number of each instruction, the function names, the global names and the files the
+//! code came from.
+//! Numbers are little endian and strings are a u32 length followed by utf-8 bytes.
+
+use crate::compiler::{OpCode, Operator, VarType};
+use crate::scanner::Span;
+use crate::vm::Vm;
+
+const MAGIC: &[u8; 3] = b"VBC";
+
+/// Bump this whenever an OpCode or the order of Vm::NATIVES changes, as both are
+/// stored by index.
+pub const VERSION: u8 = 4;
+
+/// A compiled script
+pub struct Program {
+    pub instructions: Vec<OpCode>,
+    pub line_numbers: Vec<u32>,
+    pub function_names: Vec<(usize, String)>, // start of function, name
+    pub global_names: Vec<String>,            // indexed by the global index
+    pub files: Vec<(usize, Option<String>)>,  // see Compiler::files
+    // see Compiler::spans, not written as there is no source to show them against
+    pub spans: Vec<Span>,
+}
+
+pub fn write(program: &Program) -> Vec<u8> {
+    let mut w = Writer { bytes: Vec::new() };
+    w.bytes.extend_from_slice(MAGIC);
+    w.u8(VERSION);
+
+    w.u32(program.instructions.len() as u32);
+    for op in &program.instructions {
+        w.op(op);
+    }
+    w.u32(program.line_numbers.len() as u32);
+    for line_number in &program.line_numbers {
+        w.u32(*line_number);
+    }
+    w.u32(program.function_names.len() as u32);
+    for (start, name) in &program.function_names {
+        w.usize(*start);
+        w.string(name);
+    }
+    w.u32(program.global_names.len() as u32);
+    for name in &program.global_names {
+        w.string(name);
+    }
+    w.u32(program.files.len() as u32);
+    for (start, name) in &program.files {
+        w.usize(*start);
+        match name {
+            Some(name) => {
+                w.bool(true);
+                w.string(name);
+            }
+            None => w.bool(false),
+        }
+    }
+    w.bytes
+}
+
+pub fn read(bytes: &[u8]) -> Result<Program, String> {
+    if bytes.len() < 4 || &bytes[..3] != MAGIC {
+        return Err(String::from("not a Very Basic bytecode file"));
+    }
+    if bytes[3] != VERSION {
+        return Err(format!(
+            "bytecode version {} can't be run by this version of Very Basic (expected {}), rebuild the script",
+            bytes[3], VERSION
+        ));
+    }
+    let mut r = Reader { bytes, pos: 4 };
+
+    let count = r.u32()?;
+    let mut instructions = Vec::new();
+    for _ in 0..count {
+        instructions.push(r.op()?);
+    }
+    let count = r.u32()?;
+    let mut line_numbers = Vec::new();
+    for _ in 0..count {
+        line_numbers.push(r.u32()?);
+    }
+    if line_numbers.len() != instructions.len() {
+        return Err(String::from("bytecode file is corrupt"));
+    }
+    let count = r.u32()?;
+    let mut function_names = Vec::new();
+    for _ in 0..count {
+        function_names.push((r.usize()?, r.string()?));
+    }
+    let count = r.u32()?;
+    let mut global_names = Vec::new();
+    for _ in 0..count {
+        global_names.push(r.string()?);
+    }
+    check_instructions(&instructions, global_names.len())?;
+    let count = r.u32()?;
+    let mut files = Vec::new();
+    for _ in 0..count {
+        let start = r.usize()?;
+        let name = if r.bool()? { Some(r.string()?) } else { None };
+        files.push((start, name));
+    }
+
+    Ok(Program {
+        instructions,
+        line_numbers,
+        spans: Vec::new(),
+        function_names,
+        global_names,
+        files,
+    })
+}
+
+// The Vm trusts the instructions it runs, so every index and jump in a file is
+// checked before it gets there
+fn check_instructions(instructions: &[OpCode], global_count: usize) -> Result<(), String> {
+    let len = instructions.len();
+    // the Vm makes a slot for each global name
+    let global = |index: usize| index < global_count;
+    // a jump can go to the end, which finishes the script
+    let jump = |ip: usize, to_jump: i64| {
+        let target = (ip as i64 + 1).checked_add(to_jump);
+        target.is_some_and(|target| target >= 0 && target as usize <= len)
+    };
+    // a call starts running at the instruction after pointer - 1
+    let function = |pointer: usize| pointer > 0 && pointer < len;
+    for (ip, op) in instructions.iter().enumerate() {
+        let valid = match op {
+            OpCode::SetGlobal(index) | OpCode::GetGlobal(index) => global(*index as usize),
+            OpCode::SubscriptSet(VarType::Global(index)) => global(*index),
+            OpCode::Native(index) | OpCode::CallNative(index, _) => *index < Vm::NATIVES.len(),
+            OpCode::CallNativeMut(index, _, var) => {
+                *index < Vm::MUT_NATIVES.len()
+                    && match var {
+                        VarType::Global(index) => global(*index),
+                        _ => true,
+                    }
+            }
+            // host functions are registered by a program embedding the Vm, there
+            // are none when running a file
+            OpCode::Host(_) | OpCode::CallHost(_, _) => false,
+            OpCode::Func(pointer, _)
+            | OpCode::Closure(pointer, _, _)
+            | OpCode::Invoke(pointer, _) => function(*pointer),
+            OpCode::Jump(to_jump) => jump(ip, *to_jump as i64),
+            OpCode::JumpIfFalse(to_jump) | OpCode::Try(to_jump) => {
+                i64::try_from(*to_jump).is_ok_and(|to_jump| jump(ip, to_jump))
+            }
+            OpCode::FuncPlaceholder(_, _) | OpCode::InvokePlaceholder(_, _) => false,
+            _ => true,
+        };
+        if !valid {
+            return Err(format!(
+                "bytecode file is corrupt, instruction {ip} is {op:?}"
+            ));
+        }
+    }
+    check_stack(instructions).map_err(|ip| match instructions.get(ip) {
+        Some(op) => format!("bytecode file is corrupt, instruction {ip} is {op:?}"),
+        None => String::from("bytecode file is corrupt"),
+    })
+}
+
+/// How many values are on the stack above the frame pointer when an instruction runs
+/// and whether it runs inside a function.
+#[derive(Clone, Copy, PartialEq)]
+struct StackState {
+    depth: usize,
+    in_function: bool,
+}
+
+/// Follows every path through the top level code and through each function, so a
+/// file can't pop more than is on the stack or use a local that isn't there. The
+/// Vm indexes the stack without checks. Paths that meet must have the same depth, as
+/// the compiler makes them. Returns the instruction that's wrong.
+fn check_stack(instructions: &[OpCode]) -> Result<(), usize> {
+    let len = instructions.len();
+    let mut states: Vec<Option<StackState>> = vec![None; len];
+    // instruction to check, where it came from and the stack when it runs
+    let mut to_check = vec![(
+        0,
+        0,
+        StackState {
+            depth: 0,
+            in_function: false,
+        },
+    )];
+    // a function starts with its parameters and then its captured values above the
+    // frame pointer
+    let mut entries: Vec<(usize, usize)> = Vec::new();
+    for (ip, op) in instructions.iter().enumerate() {
+        let (pointer, depth) = match op {
+            OpCode::Func(pointer, arity) => (*pointer, *arity as usize),
+            OpCode::Closure(pointer, arity, captured) => {
+                (*pointer, *arity as usize + *captured as usize)
+            }
+            OpCode::Invoke(pointer, argc) => (*pointer, *argc as usize),
+            _ => continue,
+        };
+        match entries.iter().find(|(p, _)| *p == pointer) {
+            Some((_, entry_depth)) if *entry_depth != depth => return Err(ip),
+            Some(_) => {}
+            None => {
+                entries.push((pointer, depth));
+                to_check.push((
+                    pointer,
+                    ip,
+                    StackState {
+                        depth,
+                        in_function: true,
+                    },
+                ));
+            }
+        }
+    }
+    while let Some((ip, from, state)) = to_check.pop() {
+        if ip == len {
+            // running off the end finishes the script, which must leave nothing behind
+            if state.in_function || state.depth != 0 {
+                return Err(from);
+            }
+            continue;
+        }
+        match states[ip] {
+            Some(seen) if seen == state => continue,
+            Some(_) => return Err(ip),
+            None => states[ip] = Some(state),
+        }
+        let depth = state.depth;
+        let local = |index: usize, below: usize| index + below < depth;
+        // values the instruction needs on the stack and the depth it leaves
+        let (needed, after, valid) = match &instructions[ip] {
+            OpCode::ConstantNum(_)
+            | OpCode::ConstantStr(_)
+            | OpCode::ConstantBool(_)
+            | OpCode::ConstantString(_)
+            | OpCode::GetGlobal(_)
+            | OpCode::Func(_, _)
+            | OpCode::Native(_)
+            | OpCode::Host(_)
+            | OpCode::Push => (0, depth + 1, true),
+            OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::GreaterThan
+            | OpCode::GreaterThanEq
+            | OpCode::LessThan
+            | OpCode::LessThanEq
+            | OpCode::Equal
+            | OpCode::NotEqual
+            | OpCode::And
+            | OpCode::Or
+            | OpCode::Mod
+            | OpCode::Pow
+            | OpCode::Subscript => (2, depth.wrapping_sub(1), true),
+            OpCode::Negate | OpCode::Not | OpCode::SetGlobal(_) => (1, depth, true),
+            OpCode::Call(argc) => (*argc as usize + 1, depth.wrapping_sub(*argc as usize), true),
+            OpCode::In(argc) => (*argc as usize + 1, depth.wrapping_sub(*argc as usize), true),
+            OpCode::CallNative(_, argc)
+            | OpCode::CallSystem(_, argc, _)
+            | OpCode::CallHost(_, argc)
+            | OpCode::Invoke(_, argc) => (
+                *argc as usize,
+                (depth + 1).wrapping_sub(*argc as usize),
+                true,
+            ),
+            OpCode::CallNativeMut(_, argc, var) => (
+                *argc as usize + 1,
+                depth.wrapping_sub(*argc as usize),
+                match var {
+                    VarType::Local(index) => local(*index, *argc as usize + 1),
+                    _ => true,
+                },
+            ),
+            OpCode::Closure(_, _, captured) => (
+                *captured as usize,
+                (depth + 1).wrapping_sub(*captured as usize),
+                true,
+            ),
+            OpCode::Pop | OpCode::Pop2 | OpCode::JumpIfFalse(_) => (1, depth.wrapping_sub(1), true),
+            OpCode::SetLocal(index) => (1, depth, local(*index, 0)),
+            OpCode::GetLocal(index) | OpCode::AddLocal(index, _) => {
+                (0, depth + 1, local(*index, 0))
+            }
+            // the first define of a local pushes a copy of its value, which becomes
+            // the local when the value below is popped
+            OpCode::DefineLocal(index) => {
+                (1, if index + 1 >= depth { depth + 1 } else { depth }, true)
+            }
+            OpCode::SubscriptSet(var) => (
+                2,
+                depth.wrapping_sub(2),
+                match var {
+                    VarType::Local(index) => local(*index, 2),
+                    _ => true,
+                },
+            ),
+            OpCode::Map(len) => (
+                *len as usize * 2,
+                (depth + 1).wrapping_sub(*len as usize * 2),
+                true,
+            ),
+            // the value being matched stays on the stack
+            OpCode::Match(Operator::Between) => (3, depth.wrapping_sub(1), true),
+            OpCode::Match(Operator::In(argc)) => (
+                *argc as usize + 1,
+                (depth + 1).wrapping_sub(*argc as usize),
+                true,
+            ),
+            OpCode::Match(_) => (2, depth, true),
+            OpCode::Jump(_) | OpCode::Try(_) | OpCode::EndTry | OpCode::Return => (0, depth, true),
+            OpCode::FuncPlaceholder(_, _) | OpCode::InvokePlaceholder(_, _) => (0, depth, false),
+        };
+        if !valid || depth < needed {
+            return Err(ip);
+        }
+        let state = StackState {
+            depth: after,
+            in_function: state.in_function,
+        };
+        match instructions[ip] {
+            OpCode::Jump(to_jump) => {
+                to_check.push(((ip as i64 + 1 + to_jump as i64) as usize, ip, state))
+            }
+            OpCode::JumpIfFalse(to_jump) => {
+                to_check.push((ip + 1, ip, state));
+                to_check.push((ip + 1 + to_jump, ip, state));
+            }
+            // an error goes to the catch with the error pushed
+            OpCode::Try(to_jump) => {
+                to_check.push((ip + 1, ip, state));
+                to_check.push((
+                    ip + 1 + to_jump,
+                    ip,
+                    StackState {
+                        depth: depth + 1,
+                        in_function: state.in_function,
+                    },
+                ));
+            }
+            OpCode::Return => {}
+            _ => to_check.push((ip + 1, ip, state)),
+        }
+    }
+    Ok(())
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn usize(&mut self, value: usize) {
+        self.bytes.extend_from_slice(&(value as u64).to_le_bytes());
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f64(&mut self, value: f64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+
+    fn var_type(&mut self, var: &VarType) {
+        match var {
+            VarType::Local(index) => {
+                self.u8(0);
+                self.usize(*index);
+            }
+            VarType::Global(index) => {
+                self.u8(1);
+                self.usize(*index);
+            }
+            VarType::None => self.u8(2),
+        }
+    }
+
+    fn operator(&mut self, op: &Operator) {
+        match op {
+            Operator::GreaterThan => self.u8(0),
+            Operator::GreaterThanEq => self.u8(1),
+            Operator::LessThan => self.u8(2),
+            Operator::LessThanEq => self.u8(3),
+            Operator::Equal => self.u8(4),
+            Operator::NotEqual => self.u8(5),
+            Operator::Between => self.u8(6),
+            Operator::In(argc) => {
+                self.u8(7);
+                self.u8(*argc);
+            }
+        }
+    }
+
+    fn op(&mut self, op: &OpCode) {
+        match op {
+            OpCode::ConstantNum(n) => {
+                self.u8(0);
+                self.f64(*n);
+            }
+            OpCode::ConstantStr(s) => {
+                self.u8(1);
+                self.string(s);
+            }
+            OpCode::ConstantBool(b) => {
+                self.u8(2);
+                self.bool(*b);
+            }
+            OpCode::Add => self.u8(3),
+            OpCode::Subtract => self.u8(4),
+            OpCode::Negate => self.u8(5),
+            OpCode::Multiply => self.u8(6),
+            OpCode::Divide => self.u8(7),
+            OpCode::GreaterThan => self.u8(8),
+            OpCode::GreaterThanEq => self.u8(9),
+            OpCode::LessThan => self.u8(10),
+            OpCode::LessThanEq => self.u8(11),
+            OpCode::Equal => self.u8(12),
+            OpCode::NotEqual => self.u8(13),
+            OpCode::Not => self.u8(14),
+            OpCode::And => self.u8(15),
+            OpCode::Or => self.u8(16),
+            OpCode::Mod => self.u8(17),
+            OpCode::Pow => self.u8(18),
+            OpCode::SetGlobal(index) => {
+                self.u8(19);
+                self.u32(*index);
+            }
+            OpCode::GetGlobal(index) => {
+                self.u8(20);
+                self.u32(*index);
+            }
+            OpCode::Call(argc) => {
+                self.u8(21);
+                self.u32(*argc);
+            }
+            OpCode::Native(index) => {
+                self.u8(22);
+                self.usize(*index);
+            }
+            OpCode::Func(pointer, arity) => {
+                self.u8(23);
+                self.usize(*pointer);
+                self.u8(*arity);
+            }
+            OpCode::Closure(pointer, arity, captures) => {
+                self.u8(24);
+                self.usize(*pointer);
+                self.u8(*arity);
+                self.u8(*captures);
+            }
+            OpCode::FuncPlaceholder(name, line_number) => {
+                self.u8(25);
+                self.string(name);
+                self.u32(*line_number);
+            }
+            OpCode::CallNativeMut(index, argc, var) => {
+                self.u8(26);
+                self.usize(*index);
+                self.u32(*argc);
+                self.var_type(var);
+            }
+            OpCode::CallSystem(name, argc, line_number) => {
+                self.u8(27);
+                self.string(name);
+                self.u32(*argc);
+                self.u32(*line_number);
+            }
+            OpCode::CallNative(index, argc) => {
+                self.u8(28);
+                self.usize(*index);
+                self.u32(*argc);
+            }
+            OpCode::Invoke(pointer, argc) => {
+                self.u8(29);
+                self.usize(*pointer);
+                self.u32(*argc);
+            }
+            OpCode::InvokePlaceholder(name, argc) => {
+                self.u8(30);
+                self.string(name);
+                self.u32(*argc);
+            }
+            OpCode::Pop => self.u8(31),
+            OpCode::Pop2 => self.u8(32),
+            OpCode::Push => self.u8(33),
+            OpCode::SetLocal(index) => {
+                self.u8(34);
+                self.usize(*index);
+            }
+            OpCode::DefineLocal(index) => {
+                self.u8(35);
+                self.usize(*index);
+            }
+            OpCode::GetLocal(index) => {
+                self.u8(36);
+                self.usize(*index);
+            }
+            OpCode::JumpIfFalse(to_jump) => {
+                self.u8(37);
+                self.usize(*to_jump);
+            }
+            OpCode::Jump(to_jump) => {
+                self.u8(38);
+                self.i32(*to_jump);
+            }
+            OpCode::Subscript => self.u8(39),
+            OpCode::SubscriptSet(var) => {
+                self.u8(40);
+                self.var_type(var);
+            }
+            OpCode::Map(entries) => {
+                self.u8(41);
+                self.u32(*entries);
+            }
+            OpCode::In(argc) => {
+                self.u8(42);
+                self.u8(*argc);
+            }
+            OpCode::Match(op) => {
+                self.u8(43);
+                self.operator(op);
+            }
+            OpCode::Try(to_jump) => {
+                self.u8(44);
+                self.usize(*to_jump);
+            }
+            OpCode::EndTry => self.u8(45),
+            OpCode::Return => self.u8(46),
+            OpCode::Host(index) => {
+                self.u8(47);
+                self.usize(*index);
+            }
+            OpCode::CallHost(index, argc) => {
+                self.u8(48);
+                self.usize(*index);
+                self.u32(*argc);
+            }
+            OpCode::ConstantString(s) => {
+                self.u8(49);
+                self.string(s);
+            }
+            OpCode::AddLocal(index, n) => {
+                self.u8(50);
+                self.usize(*index);
+                self.f64(*n);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        if self.pos + len > self.bytes.len() {
+            return Err(String::from("bytecode file is truncated"));
+        }
+        let slice = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn usize(&mut self) -> Result<usize, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()) as usize)
+    }
+
+    fn i32(&mut self) -> Result<i32, String> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        match String::from_utf8(self.take(len)?.to_vec()) {
+            Ok(s) => Ok(s),
+            Err(_) => Err(String::from("bytecode file is corrupt")),
+        }
+    }
+
+    fn var_type(&mut self) -> Result<VarType, String> {
+        match self.u8()? {
+            0 => Ok(VarType::Local(self.usize()?)),
+            1 => Ok(VarType::Global(self.usize()?)),
+            2 => Ok(VarType::None),
+            _ => Err(String::from("bytecode file is corrupt")),
+        }
+    }
+
+    fn operator(&mut self) -> Result<Operator, String> {
+        let op = match self.u8()? {
+            0 => Operator::GreaterThan,
+            1 => Operator::GreaterThanEq,
+            2 => Operator::LessThan,
+            3 => Operator::LessThanEq,
+            4 => Operator::Equal,
+            5 => Operator::NotEqual,
+            6 => Operator::Between,
+            7 => Operator::In(self.u8()?),
+            _ => return Err(String::from("bytecode file is corrupt")),
+        };
+        Ok(op)
+    }
+
+    fn op(&mut self) -> Result<OpCode, String> {
+        let op = match self.u8()? {
+            0 => OpCode::ConstantNum(self.f64()?),
+            1 => OpCode::ConstantStr(self.string()?),
+            2 => OpCode::ConstantBool(self.bool()?),
+            3 => OpCode::Add,
+            4 => OpCode::Subtract,
+            5 => OpCode::Negate,
+            6 => OpCode::Multiply,
+            7 => OpCode::Divide,
+            8 => OpCode::GreaterThan,
+            9 => OpCode::GreaterThanEq,
+            10 => OpCode::LessThan,
+            11 => OpCode::LessThanEq,
+            12 => OpCode::Equal,
+            13 => OpCode::NotEqual,
+            14 => OpCode::Not,
+            15 => OpCode::And,
+            16 => OpCode::Or,
+            17 => OpCode::Mod,
+            18 => OpCode::Pow,
+            19 => OpCode::SetGlobal(self.u32()?),
+            20 => OpCode::GetGlobal(self.u32()?),
+            21 => OpCode::Call(self.u32()?),
+            22 => OpCode::Native(self.usize()?),
+            23 => OpCode::Func(self.usize()?, self.u8()?),
+            24 => OpCode::Closure(self.usize()?, self.u8()?, self.u8()?),
+            25 => OpCode::FuncPlaceholder(self.string()?, self.u32()?),
+            26 => OpCode::CallNativeMut(self.usize()?, self.u32()?, self.var_type()?),
+            27 => OpCode::CallSystem(self.string()?, self.u32()?, self.u32()?),
+            28 => OpCode::CallNative(self.usize()?, self.u32()?),
+            29 => OpCode::Invoke(self.usize()?, self.u32()?),
+            30 => OpCode::InvokePlaceholder(self.string()?, self.u32()?),
+            31 => OpCode::Pop,
+            32 => OpCode::Pop2,
+            33 => OpCode::Push,
+            34 => OpCode::SetLocal(self.usize()?),
+            35 => OpCode::DefineLocal(self.usize()?),
+            36 => OpCode::GetLocal(self.usize()?),
+            37 => OpCode::JumpIfFalse(self.usize()?),
+            38 => OpCode::Jump(self.i32()?),
+            39 => OpCode::Subscript,
+            40 => OpCode::SubscriptSet(self.var_type()?),
+            41 => OpCode::Map(self.u32()?),
+            42 => OpCode::In(self.u8()?),
+            43 => OpCode::Match(self.operator()?),
+            44 => OpCode::Try(self.usize()?),
+            45 => OpCode::EndTry,
+            46 => OpCode::Return,
+            47 => OpCode::Host(self.usize()?),
+            48 => OpCode::CallHost(self.usize()?, self.u32()?),
+            49 => OpCode::ConstantString(self.string()?),
+            50 => OpCode::AddLocal(self.usize()?, self.f64()?),
+            _ => return Err(String::from("bytecode file is corrupt")),
+        };
+        Ok(op)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, write, Program};
+    use crate::compiler::{OpCode, Operator, VarType};
+
+    #[test]
+    fn test_round_trip() {
+        let program = Program {
+            instructions: vec![
+                OpCode::Jump(3),
+                OpCode::AddLocal(2, -0.5),
+                OpCode::Match(Operator::In(3)),
+                OpCode::Return,
+                OpCode::ConstantNum(1.5),
+                OpCode::ConstantStr(String::from("héllo")),
+                OpCode::SubscriptSet(VarType::Global(7)),
+                OpCode::ConstantString(String::from("ab")),
+                OpCode::Closure(1, 2, 1),
+                OpCode::Pop,
+                OpCode::ConstantBool(false),
+                OpCode::JumpIfFalse(1),
+                OpCode::Jump(-9),
+                OpCode::Return,
+            ],
+            line_numbers: vec![1, 1, 1, 0, 2, 2, 2, 3, 3, 3, 4, 4, 5, 0],
+            function_names: vec![(1, String::from("<lambda>"))],
+            global_names: (0..8).map(|i| format!("g{i}")).collect(),
+            files: vec![(0, Some(String::from("lib.vbas"))), (5, None)],
+            spans: Vec::new(),
+        };
+        let bytes = write(&program);
+        let copy = read(&bytes).unwrap();
+        assert_eq!(
+            format!("{:?}", copy.instructions),
+            format!("{:?}", program.instructions)
+        );
+        assert_eq!(copy.line_numbers, program.line_numbers);
+        assert_eq!(copy.function_names, program.function_names);
+        assert_eq!(copy.global_names, program.global_names);
+        assert_eq!(copy.files, program.files);
+    }
+
+    #[test]
+    fn test_bad_header() {
+        assert!(read(b"not bytecode").is_err());
+        assert!(read(b"VBC").is_err());
+        let mut bytes = write(&Program {
+            instructions: vec![OpCode::Return],
+            line_numbers: vec![0],
+            function_names: Vec::new(),
+            global_names: Vec::new(),
+            files: Vec::new(),
+            spans: Vec::new(),
+        });
+        assert!(read(&bytes[..bytes.len() - 2]).is_err());
+        bytes[3] = super::VERSION + 1;
+        assert!(read(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_bad_indices() {
+        let bad = [
+            OpCode::Native(usize::MAX),
+            OpCode::CallNative(100_000, 1),
+            OpCode::CallNativeMut(100_000, 1, VarType::Local(0)),
+            OpCode::CallNativeMut(0, 1, VarType::Global(50)),
+            OpCode::CallHost(0, 1),
+            OpCode::GetGlobal(u32::MAX),
+            OpCode::SetGlobal(1),
+            OpCode::SubscriptSet(VarType::Global(3)),
+            OpCode::Jump(3),
+            OpCode::Jump(-3),
+            OpCode::JumpIfFalse(usize::MAX),
+            OpCode::Try(2),
+            OpCode::Func(0, 0),
+            OpCode::Closure(3, 0, 0),
+            OpCode::Invoke(7, 0),
+        ];
+        for op in bad {
+            let message = format!("{op:?}");
+            let bytes = write(&Program {
+                instructions: vec![OpCode::ConstantNum(1.0), op, OpCode::Pop],
+                line_numbers: vec![1, 1, 1],
+                function_names: Vec::new(),
+                global_names: vec![String::from("x")],
+                files: Vec::new(),
+                spans: Vec::new(),
+            });
+            assert_eq!(
+                read(&bytes).err(),
+                Some(format!(
+                    "bytecode file is corrupt, instruction 1 is {message}"
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn test_bad_stack() {
+        let bad = [
+            // pops from an empty stack
+            (vec![OpCode::Pop], 0),
+            // a local that isn't there
+            (vec![OpCode::GetLocal(99)], 0),
+            (
+                vec![OpCode::ConstantNum(1.0), OpCode::SetLocal(1), OpCode::Pop],
+                1,
+            ),
+            // leaves a value on the stack
+            (vec![OpCode::ConstantNum(1.0)], 0),
+            // the two paths reach the last constant with different depths
+            (
+                vec![
+                    OpCode::ConstantBool(true),
+                    OpCode::JumpIfFalse(1),
+                    OpCode::ConstantNum(1.0),
+                    OpCode::ConstantNum(2.0),
+                    OpCode::Pop,
+                ],
+                3,
+            ),
+            // the function has 2 parameters but is invoked with 1
+            (
+                vec![
+                    OpCode::Jump(2),
+                    OpCode::GetLocal(1),
+                    OpCode::Return,
+                    OpCode::ConstantNum(1.0),
+                    OpCode::Invoke(1, 1),
+                    OpCode::Pop,
+                    OpCode::Func(1, 2),
+                    OpCode::Pop,
+                ],
+                6,
+            ),
+            // the function runs off the end of the code
+            (
+                vec![OpCode::Func(2, 0), OpCode::Pop, OpCode::ConstantNum(1.0)],
+                2,
+            ),
+        ];
+        for (instructions, ip) in bad {
+            let message = format!("{:?}", instructions[ip]);
+            let bytes = write(&Program {
+                line_numbers: vec![1; instructions.len()],
+                instructions,
+                function_names: Vec::new(),
+                global_names: Vec::new(),
+                files: Vec::new(),
+                spans: Vec::new(),
+            });
+            assert_eq!(
+                read(&bytes).err(),
+                Some(format!(
+                    "bytecode file is corrupt, instruction {ip} is {message}"
+                ))
+            );
+        }
+    }
+}
@@ -1,8 +1,15 @@
-use crate::scanner::{precedence, Token, TokenType};
+use crate::ast::{
+    Arm, BinaryOp, Expr, For, Function, If, Literal, Match, Pattern, Stmt, Target, Try, UnaryOp,
+};
+use crate::diagnostics;
+use crate::parser::{Parser, SyntaxError};
+use crate::scanner::{Span, Token, TokenType};
+use crate::vm::VARIADIC;
 use crate::Vm;
 use colored::Colorize;
+use std::collections::HashMap;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Operator {
     GreaterThan,
     GreaterThanEq,
@@ -28,11 +35,12 @@ impl Operator {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum OpCode {
     ConstantNum(f64),
     ConstantStr(String),
     ConstantBool(bool),
+    ConstantString(String), // strings joined by the optimizer, pushed as an owned String like Add makes
     Add,
     Subtract,
     Negate,
@@ -53,11 +61,14 @@ pub enum OpCode {
     GetGlobal(u32),
     Call(u32),
     Native(usize),
-    Func(usize, u8), //pointer, arity
+    Host(usize),
+    Func(usize, u8),        //pointer, arity
+    Closure(usize, u8, u8), //pointer, arity, number of captured values
     FuncPlaceholder(String, u32),
     CallNativeMut(usize, u32, VarType),
     CallSystem(String, u32, u32),
     CallNative(usize, u32),
+    CallHost(usize, u32),
     Invoke(usize, u32),
     InvokePlaceholder(String, u32),
     Pop,
@@ -66,23 +77,27 @@ pub enum OpCode {
     SetLocal(usize),
     DefineLocal(usize),
     GetLocal(usize),
+    AddLocal(usize, f64), // local = local + number, made by the optimizer from 4 instructions
     JumpIfFalse(usize),
     Jump(i32),
     Subscript,
     SubscriptSet(VarType),
+    Map(u32),
     In(u8),
     Match(Operator),
+    Try(usize),
+    EndTry,
     Return,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum VarType {
     Local(usize),
     Global(usize),
     None,
 }
 
-pub fn print_instr(instructions: Vec<OpCode>) {
+pub fn print_instr(instructions: &[OpCode]) {
     let mut addr = 0;
     for i in instructions {
         let x = match i {
@@ -95,11 +110,13 @@ pub fn print_instr(instructions: Vec<OpCode>) {
             OpCode::CallSystem(name, argc, _) => format!("{} SYS  {} {}", addr, name, argc),
             OpCode::ConstantNum(num) => format!("{:05} NUM  {}", addr, num),
             OpCode::ConstantStr(str) => format!("{:05} STR  {}", addr, str),
+            OpCode::ConstantString(str) => format!("{:05} STRS {}", addr, str),
             OpCode::DefineLocal(num) => format!("{:05} DEF  {}", addr, num),
             OpCode::Divide => format!("{:05} DIV", addr),
             OpCode::Equal => format!("{:05} EQ", addr),
             OpCode::GetGlobal(name) => format!("{:05} GLOB {}", addr, name),
             OpCode::GetLocal(index) => format!("{:05} LOC  {}", addr, index),
+            OpCode::AddLocal(index, num) => format!("{:05} ADDL {} {}", addr, index, num),
             OpCode::GreaterThan => format!("{:05} GT", addr),
             OpCode::GreaterThanEq => format!("{:05} GTEQ", addr),
             OpCode::Jump(ptr) => format!("{:05} JUMP {}", addr, ptr),
@@ -125,19 +142,27 @@ pub fn print_instr(instructions: Vec<OpCode>) {
             OpCode::Subtract => format!("{:05} SUB", addr),
             OpCode::ConstantBool(val) => format!("{:05} BOOL {}", addr, val),
             OpCode::Func(ptr, arity) => format!("{:05} FUNC {} {}", addr, ptr, arity),
+            OpCode::Closure(ptr, arity, captures) => {
+                format!("{:05} CLOS {} {} {}", addr, ptr, arity, captures)
+            }
             OpCode::Native(index) => format!("{:05} NAT  {}", addr, index),
+            OpCode::Host(index) => format!("{:05} HOST {}", addr, index),
+            OpCode::CallHost(index, argc) => format!("{:05} CALH {} {}", addr, index, argc),
             OpCode::FuncPlaceholder(_, _) => panic!("ERROR FuncPlaceholder left in"),
             OpCode::InvokePlaceholder(_, _) => panic!("ERROR InvokePlaceholder left in"),
             OpCode::In(args) => format!("{:05} IN   {}", addr, args),
+            OpCode::Map(len) => format!("{:05} MAP  {}", addr, len),
+            OpCode::Try(ptr) => format!("{:05} TRY  {}", addr, ptr),
+            OpCode::EndTry => format!("{:05} ENDT", addr),
         };
         addr += 1;
         println!("{}", x);
     }
 }
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Variable {
     depth: u8,
-    name: String,
+    pub name: String,
 }
 
 impl Variable {
@@ -146,15 +171,87 @@ impl Variable {
     }
 }
 
+// jumps out of a while or for loop, patched once the end of the loop is known
+struct Loop {
+    depth: u8,
+    try_depth: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+// where an instruction came from, for error messages
+#[derive(Clone, Copy)]
+struct Position {
+    line_number: u32,
+    span: Span,
+}
+
+impl From<&Token> for Position {
+    fn from(token: &Token) -> Self {
+        Position {
+            line_number: token.line_number,
+            span: token.span,
+        }
+    }
+}
+
+impl From<u32> for Position {
+    fn from(line_number: u32) -> Self {
+        Position {
+            line_number,
+            span: Span::default(),
+        }
+    }
+}
+
+/// An error found while compiling, which has also been printed
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub message: String,
+    pub line_number: u32, // 0 when the error isn't about one line
+    pub span: Span,
+    pub file: Option<String>, // None for the main script
+}
+
+/// Where a function or global variable is defined, for tools like the language server
+#[derive(Debug, Clone, PartialEq)]
+pub struct Definition {
+    pub name: String,
+    pub line_number: u32,
+    pub span: Span,
+    pub file: Option<String>,        // None for the main script
+    pub params: Option<Vec<String>>, // None for a global variable
+}
+
 pub struct Compiler<'a> {
     instructions: &'a mut Vec<OpCode>,
     line_numbers: &'a mut Vec<u32>,
+    // the span of the token each instruction came from, alongside line_numbers
+    pub spans: Vec<Span>,
     tokens: &'a Vec<TokenType>,
-    variables: Vec<Variable>,
+    pub variables: Vec<Variable>,
     pub functions: Vec<(String, u8, usize)>,
-    token_pointer: usize,
     pub in_error: bool,
+    pub errors: Vec<CompileError>,
+    // errors are printed as they are found unless this is false
+    pub print_errors: bool,
+    // set from an error until the end of the statement it's in, so one mistake
+    // doesn't cause a string of errors after it. The parser has one of its own.
+    panic_mode: bool,
     depth: u8,
+    loops: Vec<Loop>,
+    try_depth: usize,
+    // where the code for each file starts, imported files first and the main script (None) last
+    pub files: Vec<(usize, Option<String>)>,
+    // names of functions registered by the program embedding Very Basic, see Vm::host_functions
+    pub host_functions: Vec<String>,
+    // calls to functions that aren't defined yet: the FuncPlaceholder and the number
+    // of arguments, checked once the function is found in second_pass
+    forward_calls: HashMap<usize, usize>,
+    // the main script, and the source of each file in files, for showing errors
+    pub source: &'a str,
+    sources: Vec<&'a str>,
+    pub definitions: Vec<Definition>,
 }
 
 fn is_native(name: &str) -> Result<usize, usize> {
@@ -168,6 +265,28 @@ fn is_native(name: &str) -> Result<usize, usize> {
     Err(1)
 }
 
+// The error for calling a function with the wrong number of arguments, if it is
+fn arity_error(name: &str, min: u8, max: u8, arguments: usize) -> Option<String> {
+    if arguments >= min as usize && arguments <= max as usize {
+        return None;
+    }
+    let expected = if min == max {
+        format!("{min}")
+    } else if max == VARIADIC {
+        format!("at least {min}")
+    } else {
+        format!("{min} to {max}")
+    };
+    let plural = if expected.ends_with(" 1") || expected == "1" {
+        ""
+    } else {
+        "s"
+    };
+    Some(format!(
+        "{name}() takes {expected} argument{plural} but was given {arguments}"
+    ))
+}
+
 fn is_native_mut(name: &str) -> Result<usize, usize> {
     if let Some(i) = Vm::MUT_NATIVES.into_iter().position(|x| x.1 == name) {
         return Ok(i);
@@ -175,8 +294,39 @@ fn is_native_mut(name: &str) -> Result<usize, usize> {
     Err(1)
 }
 
-impl Compiler<'_> {
-    pub fn new<'a>(
+fn binary_opcode(op: BinaryOp) -> OpCode {
+    match op {
+        BinaryOp::Add => OpCode::Add,
+        BinaryOp::Subtract => OpCode::Subtract,
+        BinaryOp::Multiply => OpCode::Multiply,
+        BinaryOp::Divide => OpCode::Divide,
+        BinaryOp::Pow => OpCode::Pow,
+        BinaryOp::Mod => OpCode::Mod,
+        BinaryOp::GreaterThan => OpCode::GreaterThan,
+        BinaryOp::GreaterThanEq => OpCode::GreaterThanEq,
+        BinaryOp::LessThan => OpCode::LessThan,
+        BinaryOp::LessThanEq => OpCode::LessThanEq,
+        BinaryOp::Equal => OpCode::Equal,
+        BinaryOp::NotEqual => OpCode::NotEqual,
+        BinaryOp::And => OpCode::And,
+        BinaryOp::Or => OpCode::Or,
+    }
+}
+
+// the comparison in a 'when', the parser only uses the comparison operators
+fn match_operator(op: BinaryOp) -> Operator {
+    match op {
+        BinaryOp::GreaterThan => Operator::GreaterThan,
+        BinaryOp::GreaterThanEq => Operator::GreaterThanEq,
+        BinaryOp::LessThan => Operator::LessThan,
+        BinaryOp::LessThanEq => Operator::LessThanEq,
+        BinaryOp::NotEqual => Operator::NotEqual,
+        _ => Operator::Equal,
+    }
+}
+
+impl<'a> Compiler<'a> {
+    pub fn new(
         tokens: &'a Vec<TokenType>,
         instructions: &'a mut Vec<OpCode>,
         line_numbers: &'a mut Vec<u32>,
@@ -186,15 +336,32 @@ impl Compiler<'_> {
             line_numbers,
             tokens,
             variables: Vec::new(),
-            token_pointer: 0,
             in_error: false,
+            errors: Vec::new(),
+            print_errors: true,
+            panic_mode: false,
             depth: 0,
             functions: Vec::new(),
+            loops: Vec::new(),
+            try_depth: 0,
+            files: Vec::new(),
+            host_functions: Vec::new(),
+            forward_calls: HashMap::new(),
+            spans: Vec::new(),
+            source: "",
+            sources: Vec::new(),
+            definitions: Vec::new(),
         }
     }
 
-    fn add_instr(&mut self, op: OpCode, line_number: u32) -> usize {
-        self.line_numbers.push(line_number);
+    fn host_function(&self, name: &str) -> Option<usize> {
+        self.host_functions.iter().position(|x| x == name)
+    }
+
+    fn add_instr(&mut self, op: OpCode, at: impl Into<Position>) -> usize {
+        let at = at.into();
+        self.line_numbers.push(at.line_number);
+        self.spans.push(at.span);
         self.instructions.push(op);
         self.instructions.len() - 1
     }
@@ -207,151 +374,142 @@ impl Compiler<'_> {
         true
     }
 
-    fn number(&mut self, token: &Token) {
-        let number = match token.lexeme.parse::<f64>() {
-            Ok(v) => v,
-            Err(_) => {
-                self.compile_error("Could not parse number", token);
-                return;
+    fn expression(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal { token, value } => {
+                let op = match value {
+                    Literal::Number(number) => OpCode::ConstantNum(*number),
+                    Literal::String(string) => OpCode::ConstantStr(string.clone()),
+                    Literal::Bool(value) => OpCode::ConstantBool(*value),
+                };
+                self.add_instr(op, *token);
             }
-        };
-        self.add_instr(OpCode::ConstantNum(number), token.line_number);
-    }
-
-    fn string(&mut self, token: &Token) {
-        self.add_instr(OpCode::ConstantStr(token.lexeme.clone()), token.line_number);
-    }
-
-    fn bool(&mut self, token: &Token) {
-        self.add_instr(
-            OpCode::ConstantBool(token.lexeme == "true"),
-            token.line_number,
-        );
-    }
-
-    fn grouping(&mut self, token: &Token) {
-        self.expression();
-
-        let end_token = &self.tokens[self.token_pointer];
-        if let TokenType::RightParan(_) = end_token {
-            self.advance();
-        } else {
-            //dbg!(end_token);
-            self.compile_error("Expected )", token);
-        }
-    }
-
-    fn in_operator(&mut self, token: &Token) -> bool {
-        //dbg!("In the in operator!");
-        self.expression();
-        let mut args: u8 = 1;
-        loop {
-            if let TokenType::Comma(_) = &self.tokens[self.token_pointer] {
-                self.advance();
-                self.expression();
-                args += 1;
-            } else {
-                self.add_instr(OpCode::In(args), token.line_number);
-                break;
+            Expr::Variable(token) => self.variable(token),
+            Expr::Assign { target, value } => match target {
+                Target::Variable(token) => self.assignment(token, value),
+                Target::Subscript {
+                    variable,
+                    bracket,
+                    index,
+                } => self.subscript_set(variable, bracket, index, value, true),
+            },
+            Expr::Unary { op, token, operand } => {
+                self.expression(operand);
+                let op = match op {
+                    UnaryOp::Negate => OpCode::Negate,
+                    UnaryOp::Not => OpCode::Not,
+                };
+                self.add_instr(op, *token);
             }
-        }
-        true
-    }
-
-    fn subscript(&mut self, token: &Token) -> bool {
-        let mut can_set = true;
-        // save the variable name
-        let variable = if let TokenType::Identifier(t) = &self.tokens[self.token_pointer - 2] {
-            t
-        } else {
-            can_set = false;
-            token
-        };
-
-        let vartype = self.check_variable(variable.lexeme.clone());
-
-        //dbg!(&variable);
-        // get the index of the array
-        self.expression();
-        if let TokenType::RightBracket(_) = &self.tokens[self.token_pointer] {
-            if let TokenType::Equals(_) = &self.tokens[self.token_pointer + 1] {
-                if !can_set {
-                    self.compile_error(
-                        "Cannot set value of subscript on something that is not a variable!",
-                        token,
-                    );
-                    return false;
+            Expr::Binary {
+                op,
+                token,
+                left,
+                right,
+            } => {
+                self.expression(left);
+                self.expression(right);
+                self.add_instr(binary_opcode(*op), *token);
+            }
+            Expr::Grouping(expr) => self.expression(expr),
+            Expr::Map { token, entries } => {
+                for (key, value) in entries {
+                    self.expression(key);
+                    self.expression(value);
                 }
-
-                if let VarType::None = vartype {
-                    self.compile_error("variable not found", variable);
-                    return false;
+                self.add_instr(OpCode::Map(entries.len() as u32), *token);
+            }
+            Expr::Call {
+                callee,
+                paren,
+                args,
+            } => self.call(callee, paren, args),
+            Expr::SystemCall { name, paren, args } => {
+                for arg in args {
+                    self.expression(arg);
                 }
-
-                // subscript set
-                self.advance(); // over ]
-                self.advance(); // over =
-                self.expression();
-                self.add_instr(OpCode::SubscriptSet(vartype), token.line_number);
-            } else {
-                self.add_instr(OpCode::Subscript, token.line_number);
-                self.advance();
+                let op =
+                    OpCode::CallSystem(name.lexeme.clone(), args.len() as u32, paren.line_number);
+                self.add_instr(op, *paren);
             }
-        } else {
-            self.compile_error("Missing ]", token);
-            return false;
+            Expr::MethodCall {
+                receiver,
+                dot,
+                method,
+                args,
+            } => self.method_call(receiver, dot, method, args),
+            Expr::Subscript {
+                object,
+                bracket,
+                index,
+            } => {
+                self.expression(object);
+                self.expression(index);
+                self.add_instr(OpCode::Subscript, *bracket);
+            }
+            Expr::In { value, token, list } => {
+                self.expression(value);
+                for expr in list {
+                    self.expression(expr);
+                }
+                self.add_instr(OpCode::In(list.len() as u8), *token);
+            }
+            Expr::Lambda(function) => self.lambda(function),
+            Expr::Match(statement) => self.pattern_match(statement),
+            Expr::Incomplete(parts) => {
+                for expr in parts {
+                    self.expression(expr);
+                }
+            }
+            Expr::Error => (),
         }
-        true
     }
 
-    fn call(&mut self, token: &Token) -> bool {
-        if self.token_pointer >= self.tokens.len() {
-            self.compile_error("Unexpected end of file after '('", token);
-            return false;
-        }
-        // get the name of the function
-        let name: String;
-        let system_call = if let TokenType::Identifier(t) = &self.tokens[self.token_pointer - 2] {
-            name = t.lexeme.clone();
-            t.lexeme.starts_with("@")
-        } else {
-            self.compile_error("Expect funtion name before '('", token);
-            return false;
+    fn call(&mut self, callee: &Expr, paren: &Token, args: &[Expr]) {
+        self.expression(callee);
+        // the instruction that got the function, when it's called by name
+        let function = match callee {
+            Expr::Variable(name) => self.instructions.len().checked_sub(1).map(|i| (i, name)),
+            _ => None,
         };
+        for arg in args {
+            self.expression(arg);
+        }
+        if let Some((callee, name)) = function {
+            self.check_call(callee, &name.lexeme, args.len(), paren);
+        }
+        self.add_instr(OpCode::Call(args.len() as u32), paren);
+    }
 
-        let mut arguments = 0;
-        loop {
-            match &self.tokens[self.token_pointer] {
-                TokenType::RightParan(_) => {
-                    self.advance();
-                    if system_call {
-                        self.add_instr(
-                            OpCode::CallSystem(name, arguments, token.line_number),
-                            token.line_number,
-                        );
-                    } else {
-                        self.add_instr(OpCode::Call(arguments), token.line_number);
-                    }
-                    return true;
-                }
-                TokenType::Comma(_) => {
-                    if !self.advance() {
-                        self.compile_error("Expected )", token);
-                        return false;
-                    }
-                }
-                TokenType::Eof => {
-                    self.compile_error("Expected )", token);
-                    return false;
-                }
-                _ => {
-                    self.expression();
-                    arguments += 1;
-                }
+    // checks the number of arguments in a call to a built in or user function by name.
+    // Variables holding functions can only be checked when they are called.
+    fn check_call(&mut self, callee: usize, name: &str, arguments: usize, token: &Token) {
+        let message = match &self.instructions[callee] {
+            OpCode::Native(index) => {
+                let (_, _, min, max, _) = Vm::NATIVES[*index];
+                arity_error(name, min, max, arguments)
             }
+            OpCode::Func(_, arity) => arity_error(name, *arity, *arity, arguments),
+            OpCode::FuncPlaceholder(_, _) => {
+                self.forward_calls.insert(callee, arguments);
+                None
+            }
+            _ => None,
+        };
+        if let Some(message) = message {
+            // point at the name of the function
+            self.compile_error_at(&message, token.line_number, callee);
+            // the call itself is fine, so there's nothing to skip
+            self.panic_mode = false;
         }
     }
 
+    // an error in a call that is otherwise fine, so compiling carries on from here
+    fn call_error(&mut self, message: &str, token: &Token) {
+        self.compile_error(message, token);
+        self.panic_mode = false;
+    }
+
     fn add_variable(&mut self, name: String, line_number: u32) -> (VarType, bool) {
         let index: VarType;
         let mut added = false;
@@ -429,622 +587,413 @@ impl Compiler<'_> {
         added
     }
 
-    fn for_variable(&mut self, token: &Token) -> usize {
-        //check to see if we are setting a variable
-        if let TokenType::Equals(_) = self.tokens[self.token_pointer] {
-            self.advance();
-        } else {
-            panic!("Invalid use of for: TODO: Handle this!");
-        };
-
-        self.expression();
-        //let (index, added) = self.add_variable(token.lexeme.clone());
-
+    // a new local in the current scope, returns its index
+    fn define_local(&mut self, name: &str) -> usize {
         self.variables
-            .push(Variable::new(token.lexeme.clone(), self.depth));
-
+            .push(Variable::new(name.to_string(), self.depth));
         let start = self.variables.iter().position(|x| x.depth > 0).unwrap();
-        let index = self.variables.len() - start - 1;
-        self.add_instr(OpCode::DefineLocal(index), token.line_number);
-        self.add_instr(OpCode::Pop2, token.line_number);
-
-        index
+        self.variables.len() - start - 1
     }
 
-    fn variable(&mut self, token: &Token, can_assign: bool) {
-        // This means system call.
-        if token.lexeme.starts_with("@") {
-            //check to see if this is a function call
-            if let TokenType::LeftParan(_) = self.tokens[self.token_pointer] {
-                return;
-            };
-            // not a function call - compile error
-            self.compile_error("Expect '(' after system call", token);
-            return;
-        }
-
-        //check to see if we are setting a variable
-        let matched_equal = if let TokenType::Equals(_) = self.tokens[self.token_pointer] {
-            self.advance();
-            true
-        } else {
-            false
-        };
-
-        if can_assign && matched_equal {
-            // Setting a variable
-            self.expression();
-            let (index, added) = self.add_variable(token.lexeme.clone(), token.line_number);
-            match index {
-                VarType::Local(index) => {
-                    if added {
-                        self.add_instr(OpCode::DefineLocal(index), token.line_number);
-                    } else {
-                        self.add_instr(OpCode::SetLocal(index), token.line_number);
-                    }
-                }
-                VarType::Global(index) => {
-                    self.add_instr(OpCode::SetGlobal(index as u32), token.line_number);
-                }
-                _ => {
-                    return; //compile error
-                            //panic!("Programming Error VarType is None");
-                }
-            }
-        } else {
-            // Getting value from a variable
-            if let Some(index) = self
-                .variables
-                .iter()
-                .rev()
-                .position(|x| x.name == token.lexeme)
-            {
-                let index = self.variables.len() - 1 - index;
-                if self.variables[index].depth == 0 {
-                    self.add_instr(OpCode::GetGlobal(index as u32), token.line_number);
+    fn assignment(&mut self, token: &Token, value: &Expr) {
+        self.expression(value);
+        let (index, added) = self.add_variable(token.lexeme.clone(), token.line_number);
+        match index {
+            VarType::Local(index) => {
+                if added {
+                    self.add_instr(OpCode::DefineLocal(index), token);
                 } else {
-                    let start = self.variables.iter().position(|x| x.depth > 0).unwrap();
-                    let index = index - start;
-                    self.add_instr(OpCode::GetLocal(index), token.line_number);
+                    self.add_instr(OpCode::SetLocal(index), token);
                 }
-            } else {
-                if let Ok(index) = is_native(token.lexeme.as_str()) {
-                    self.add_instr(OpCode::Native(index), token.line_number);
-                } else if let Some(index) = self.functions.iter().position(|x| x.0 == token.lexeme)
-                {
-                    let f = &self.functions[index];
-                    self.add_instr(OpCode::Func(f.2, f.1), token.line_number);
-                } else {
-                    self.add_instr(
-                        OpCode::FuncPlaceholder(token.lexeme.clone(), token.line_number),
-                        token.line_number,
-                    );
+            }
+            VarType::Global(index) => {
+                if added {
+                    self.add_definition(token, None);
                 }
-                // compile error - can't find variable
-                //let message = format!("Variable {} not found", token.lexeme);
-                //self.compile_error(&message, token);
+                self.add_instr(OpCode::SetGlobal(index as u32), token);
             }
+            VarType::None => (), // compile error
         }
     }
-    // 0 1 2 3 4 5
-    // 0 0 0 1 1 1
-    // x y z x y z
-    // x = 1
-    fn run_infix(&mut self, token: &TokenType) -> bool {
-        match token {
-            TokenType::Plus(t) => {
-                self.parse_precedence(t.precedence + 1);
-                self.add_instr(OpCode::Add, t.line_number);
-                true
-            }
-            TokenType::Minus(t) => {
-                self.parse_precedence(t.precedence + 1);
-                self.add_instr(OpCode::Subtract, t.line_number);
-                true
-            }
-            TokenType::Times(t) => {
-                self.parse_precedence(t.precedence + 1);
-                self.add_instr(OpCode::Multiply, t.line_number);
-                true
-            }
-            TokenType::Divide(t) => {
-                self.parse_precedence(t.precedence + 1);
-                self.add_instr(OpCode::Divide, t.line_number);
-                true
-            }
-            TokenType::Hat(t) => {
-                self.parse_precedence(t.precedence + 1);
-                self.add_instr(OpCode::Pow, t.line_number);
-                true
-            }
-            TokenType::Mod(t) => {
-                self.parse_precedence(t.precedence + 1);
-                self.add_instr(OpCode::Mod, t.line_number);
-                true
-            }
-            TokenType::GreaterThan(t) => {
-                self.parse_precedence(t.precedence + 1);
-                self.add_instr(OpCode::GreaterThan, t.line_number);
-                true
-            }
-            TokenType::GreaterThanOrEqual(t) => {
-                self.parse_precedence(t.precedence + 1);
-                self.add_instr(OpCode::GreaterThanEq, t.line_number);
-                true
-            }
-            TokenType::LessThan(t) => {
-                self.parse_precedence(t.precedence + 1);
-                self.add_instr(OpCode::LessThan, t.line_number);
-                true
-            }
-            TokenType::LessThanOrEqual(t) => {
-                self.parse_precedence(t.precedence + 1);
-                self.add_instr(OpCode::LessThanEq, t.line_number);
-                true
-            }
-            TokenType::Equality(t) => {
-                self.parse_precedence(t.precedence + 1);
-                self.add_instr(OpCode::Equal, t.line_number);
-                true
-            }
-            TokenType::NotEquals(t) => {
-                self.parse_precedence(t.precedence + 1);
-                self.add_instr(OpCode::NotEqual, t.line_number);
-                true
-            }
-            TokenType::And(t) => {
-                self.parse_precedence(t.precedence + 1);
-                self.add_instr(OpCode::And, t.line_number);
-                true
-            }
-            TokenType::Or(t) => {
-                self.parse_precedence(t.precedence + 1);
-                self.add_instr(OpCode::Or, t.line_number);
-                true
-            }
-            TokenType::LeftParan(t) => self.call(t),
-            TokenType::Dot(t) => self.dot(t),
-            TokenType::LeftBracket(t) => self.subscript(t),
-            TokenType::In(t) => self.in_operator(t),
-            _ => false,
+
+    // variable[index] = value. Its value is the variable as it was before the change
+    fn subscript_set(
+        &mut self,
+        variable: &Token,
+        bracket: &Token,
+        index: &Expr,
+        value: &Expr,
+        value_used: bool,
+    ) {
+        let vartype = self.check_variable(variable.lexeme.clone());
+        if let VarType::None = vartype {
+            self.compile_error("variable not found", variable);
+            return;
+        }
+        // SubscriptSet leaves this copy on the stack as the value. When nothing uses the
+        // value it's left out, so the array isn't shared and is changed without a copy
+        if value_used {
+            self.variable(variable);
         }
+        self.expression(index);
+        self.expression(value);
+        self.add_instr(OpCode::SubscriptSet(vartype), bracket);
     }
 
-    fn get_precedence(&mut self, token: &TokenType) -> u8 {
-        match token {
-            TokenType::Plus(t)
-            | TokenType::Minus(t)
-            | TokenType::Times(t)
-            | TokenType::Divide(t)
-            | TokenType::GreaterThan(t)
-            | TokenType::LessThan(t)
-            | TokenType::GreaterThanOrEqual(t)
-            | TokenType::LessThanOrEqual(t)
-            | TokenType::Equality(t)
-            | TokenType::NotEquals(t)
-            | TokenType::LeftParan(t)
-            | TokenType::And(t)
-            | TokenType::Or(t)
-            | TokenType::Hat(t)
-            | TokenType::Mod(t)
-            | TokenType::LeftBracket(t)
-            | TokenType::In(t)
-            | TokenType::Match(t)
-            | TokenType::Dot(t) => t.precedence,
-
-            _ => precedence::NONE,
+    // Getting value from a variable
+    fn variable(&mut self, token: &Token) {
+        // the name of a system call, which is part of the CallSystem
+        if token.lexeme.starts_with('@') {
+            return;
         }
-    }
 
-    fn advance(&mut self) -> bool {
-        self.token_pointer += 1;
-        self.token_pointer < self.tokens.len()
+        if let Some(index) = self
+            .variables
+            .iter()
+            .rev()
+            .position(|x| x.name == token.lexeme)
+        {
+            let index = self.variables.len() - 1 - index;
+            if self.variables[index].depth == 0 {
+                self.add_instr(OpCode::GetGlobal(index as u32), token);
+            } else {
+                let start = self.variables.iter().position(|x| x.depth > 0).unwrap();
+                let index = index - start;
+                self.add_instr(OpCode::GetLocal(index), token);
+            }
+        } else if let Ok(index) = is_native(token.lexeme.as_str()) {
+            self.add_instr(OpCode::Native(index), token);
+        } else if let Some(index) = self.host_function(&token.lexeme) {
+            self.add_instr(OpCode::Host(index), token);
+        } else if let Some(index) = self.functions.iter().position(|x| x.0 == token.lexeme) {
+            let f = &self.functions[index];
+            self.add_instr(OpCode::Func(f.2, f.1), token);
+        } else {
+            // a function defined further on, or an error found in second_pass
+            self.add_instr(
+                OpCode::FuncPlaceholder(token.lexeme.clone(), token.line_number),
+                token,
+            );
+        }
     }
 
     fn compile_error(&mut self, message: &str, token: &Token) {
-        if self.in_error {
+        self.compile_error_span(message, token.line_number, token.span);
+    }
+
+    fn compile_error_span(&mut self, message: &str, line_number: u32, span: Span) {
+        if self.panic_mode {
             return;
         }
-        eprintln!(
-            "Compile error: {}, line {}",
-            message.red(),
-            token.line_number
-        );
-        self.in_error = true;
+        let index = self.instructions.len();
+        if self.print_errors {
+            let location = self.location(line_number, index);
+            eprintln!("Compile error: {}, {}", message.red(), location);
+            self.print_excerpt(line_number, span, index);
+        }
+        self.add_error(message, line_number, span, index);
     }
 
     fn compile_error_line(&mut self, message: &str, line_number: u32) {
-        if self.in_error {
-            return;
-        }
-        eprintln!("Compile error: {}, line {}", message.red(), line_number);
-        self.in_error = true;
+        self.compile_error_at(message, line_number, self.instructions.len());
     }
 
-    fn compile_error_message(&mut self, message: &str) {
-        if self.in_error {
+    // index is the instruction the error is for, which tells us which file it came from
+    fn compile_error_at(&mut self, message: &str, line_number: u32, index: usize) {
+        if self.panic_mode {
             return;
         }
-        eprintln!("Compile error: {}", message.red());
+        let span = self.spans.get(index).copied().unwrap_or_default();
+        if self.print_errors {
+            let location = self.location(line_number, index);
+            eprintln!("Compile error: {}, {}", message.red(), location);
+            self.print_excerpt(line_number, span, index);
+        }
+        self.add_error(message, line_number, span, index);
+    }
+
+    fn add_error(&mut self, message: &str, line_number: u32, span: Span, index: usize) {
+        let file = self.file_at(index);
+        self.errors.push(CompileError {
+            message: message.to_string(),
+            line_number,
+            span,
+            file,
+        });
         self.in_error = true;
+        self.panic_mode = true;
     }
 
-    fn parse_precedence(&mut self, precedence: u8) {
-        if !self.advance() {
-            return;
+    // the parser has already skipped what came after the error, so each one is reported
+    fn syntax_error(&mut self, error: &SyntaxError) {
+        self.panic_mode = false;
+        if error.line_number == 0 {
+            self.compile_error_message(&error.message);
+        } else {
+            self.compile_error_span(&error.message, error.line_number, error.span);
         }
+    }
 
-        // run prefix rule
-        let token = &self.tokens[self.token_pointer - 1];
-        //dbg!(&token);
-        let can_assign = precedence <= precedence::ASSIGNMENT;
-        match token {
-            TokenType::Number(t) => self.number(t),
-            TokenType::String(t) => self.string(t),
-            TokenType::Bool(t) => self.bool(t),
-            TokenType::Minus(t) => {
-                self.parse_precedence(precedence::UNARY);
-                self.add_instr(OpCode::Negate, t.line_number);
-            }
-            TokenType::Not(t) => {
-                self.parse_precedence(precedence::UNARY);
-                self.add_instr(OpCode::Not, t.line_number);
-            }
-            TokenType::LeftParan(t) => self.grouping(t),
-            TokenType::Identifier(t) => self.variable(t, can_assign),
-            TokenType::Match(t) => {
-                self.pattern_match(t);
-            }
-            _ => {
-                let result = token.get_token();
-                if let Some(t) = result {
-                    let message = format!("Unexpected statement '{}'", t.lexeme);
-                    self.compile_error(message.as_str(), t);
-                } else {
-                    dbg!(&token);
-                    panic!("Unexpected Token Type");
-                }
-            }
-        };
-
-        if self.in_error {
-            return;
+    // the file instruction index is in, None for the main script
+    fn file_at(&self, index: usize) -> Option<String> {
+        match self.files.iter().rev().find(|f| f.0 <= index) {
+            Some((_, name)) => name.clone(),
+            None => None,
         }
+    }
 
-        while self.token_pointer < self.tokens.len()
-            && precedence <= self.get_precedence(&self.tokens[self.token_pointer])
-        {
-            if !self.advance() {
-                break;
-            };
-            if !self.run_infix(&self.tokens[self.token_pointer - 1]) {
-                break;
-            }
+    fn add_definition(&mut self, token: &Token, params: Option<Vec<String>>) {
+        let file = self.file_at(self.instructions.len());
+        self.definitions.push(Definition {
+            name: token.lexeme.clone(),
+            line_number: token.line_number,
+            span: token.span,
+            file,
+            params,
+        });
+    }
+
+    fn print_excerpt(&self, line_number: u32, span: Span, index: usize) {
+        let source = match self.files.iter().rposition(|f| f.0 <= index) {
+            Some(file) => self.sources[file],
+            None => self.source,
+        };
+        if let Some(excerpt) = diagnostics::source_excerpt(source, line_number, span) {
+            eprintln!("{excerpt}");
         }
     }
 
-    fn expression(&mut self) {
-        self.parse_precedence(precedence::ASSIGNMENT);
+    fn location(&self, line_number: u32, index: usize) -> String {
+        match self.files.iter().rev().find(|f| f.0 <= index) {
+            Some((_, Some(name))) => format!("line {line_number} of {name}"),
+            _ => format!("line {line_number}"),
+        }
     }
 
-    fn expression_statement(&mut self) {
-        self.expression();
-        self.add_instr(OpCode::Pop, 0);
+    fn compile_error_message(&mut self, message: &str) {
+        if self.panic_mode {
+            return;
+        }
+        if self.print_errors {
+            eprintln!("Compile error: {}", message.red());
+        }
+        let index = self.instructions.len();
+        self.add_error(message, 0, Span::default(), index);
     }
 
-    fn pattern_match(&mut self, token: &Token) -> bool {
+    fn pattern_match(&mut self, statement: &Match) {
         let mut jump_indexes: Vec<usize> = Vec::new();
 
-        self.expression();
-
-        loop {
-            self.skip_eol();
-            let next_token = &self.tokens[self.token_pointer];
-            //dbg!(next_token);
-            match next_token {
-                TokenType::When(t) => {
-                    self.advance();
-                    // TODO: Here we check for optional pattern match type
-                    //self.add_instr(OpCode::GetLocal(0), token.line_number);
-                    //dbg!(&self.tokens[self.token_pointer]);
-                    let op = match &self.tokens[self.token_pointer] {
-                        TokenType::GreaterThan(_) => {
-                            self.advance();
-                            Operator::GreaterThan
-                        }
-                        TokenType::GreaterThanOrEqual(_) => {
-                            self.advance();
-                            Operator::GreaterThanEq
-                        }
-                        TokenType::LessThan(_) => {
-                            self.advance();
-                            Operator::LessThan
+        self.expression(&statement.value);
+        for arm in &statement.arms {
+            match arm {
+                Arm::When {
+                    token,
+                    pattern,
+                    body,
+                } => {
+                    match pattern {
+                        Pattern::Compare(op, expr) => {
+                            self.expression(expr);
+                            self.add_instr(OpCode::Match(match_operator(*op)), *token);
                         }
-                        TokenType::LessThanOrEqual(_) => {
-                            self.advance();
-                            Operator::LessThanEq
+                        Pattern::Between(from, to) => {
+                            self.expression(from);
+                            self.expression(to);
+                            self.add_instr(OpCode::Match(Operator::Between), *token);
                         }
-                        TokenType::NotEquals(_) | TokenType::Not(_) => {
-                            self.advance();
-                            Operator::NotEqual
-                        }
-                        _ => Operator::Equal,
-                    };
-
-                    self.expression();
-                    //dbg!(&self.tokens[self.token_pointer]);
-                    // Check for 'to'
-                    match &self.tokens[self.token_pointer] {
-                        TokenType::To(_) => {
-                            if let Operator::Equal = op {
-                            } else {
-                                self.compile_error("Unexpected 'to' in match", t);
-                                return false;
-                            };
-                            self.advance();
-                            self.expression();
-                            self.add_instr(OpCode::Match(Operator::Between), t.line_number);
-                        }
-                        TokenType::Comma(_) => {
-                            // in
-                            self.advance();
-                            self.expression();
-                            let mut args: u8 = 2;
-                            loop {
-                                if let TokenType::Comma(_) = &self.tokens[self.token_pointer] {
-                                    self.advance();
-                                    self.expression();
-                                    args += 1;
-                                } else {
-                                    self.add_instr(
-                                        OpCode::Match(Operator::In(args)),
-                                        token.line_number,
-                                    );
-                                    break;
-                                }
+                        Pattern::In(list) => {
+                            for expr in list {
+                                self.expression(expr);
                             }
+                            let op = OpCode::Match(Operator::In(list.len() as u8));
+                            self.add_instr(op, statement.token);
                         }
-                        _ => {
-                            self.add_instr(OpCode::Match(op), t.line_number);
-                        }
-                    };
-
-                    let jif_index = self.add_instr(OpCode::JumpIfFalse(0), t.line_number);
-                    self.add_instr(OpCode::Pop2, t.line_number); // get rid of the value we are comparing against
-                    if let TokenType::Then(_) = &self.tokens[self.token_pointer] {
-                        self.advance();
-                        self.begin_scope();
-                        self.block();
-                        self.end_scope();
-                    } else {
-                        self.compile_error("'then' missing in match", t);
-                        return false;
                     }
 
-                    jump_indexes.push(self.add_instr(OpCode::Jump(0), t.line_number));
+                    let jif_index = self.add_instr(OpCode::JumpIfFalse(0), *token);
+                    self.add_instr(OpCode::Pop2, *token); // get rid of the value we are comparing against
+                    self.scoped_block(body);
+
+                    jump_indexes.push(self.add_instr(OpCode::Jump(0), *token));
                     self.instructions[jif_index] =
                         OpCode::JumpIfFalse(self.instructions.len() - jif_index - 1);
                 }
-                TokenType::Else(t) => {
-                    self.add_instr(OpCode::Pop2, t.line_number); // get rid of the value we are comparing against
-                    self.advance();
-                    self.begin_scope();
-                    self.block();
-                    self.end_scope();
-                }
-                TokenType::End(t) => {
-                    self.advance();
-                    for i in jump_indexes {
-                        self.instructions[i] =
-                            OpCode::Jump((self.instructions.len() - i - 1) as i32);
-                    }
-                    //self.add_instr(OpCode::Pop2, t.line_number);
-                    //self.end_scope();
-                    self.add_instr(OpCode::Push, t.line_number);
-                    //self.add_instr(OpCode::Return, t.line_number);
-                    break;
-                }
-                _ => {
-                    self.compile_error("Invalid syntax in match", token);
-                    return false;
+                Arm::Else { token, body } => {
+                    self.add_instr(OpCode::Pop2, *token); // get rid of the value we are comparing against
+                    self.scoped_block(body);
                 }
             }
         }
-
-        true
+        for i in jump_indexes {
+            self.instructions[i] = OpCode::Jump((self.instructions.len() - i - 1) as i32);
+        }
+        self.add_instr(OpCode::Push, statement.end);
     }
 
-    fn if_statement(&mut self, if_token: &Token) {
-        self.advance();
-        self.expression();
-        let token = &self.tokens[self.token_pointer];
-        if let TokenType::Then(t) = token {
-            self.advance();
-            let if_index = self.add_instr(OpCode::JumpIfFalse(0), if_token.line_number);
-            self.begin_scope();
-            self.block();
-            self.end_scope();
-            let else_index = self.add_instr(OpCode::Jump(0), 0); // if_token.line_number);
-            self.instructions[if_index] =
-                OpCode::JumpIfFalse(self.instructions.len() - if_index - 1);
-            if let TokenType::Else(_) = self.tokens[self.token_pointer] {
-                self.advance();
-                self.begin_scope();
-                self.block();
-                self.end_scope();
-                self.instructions[else_index] = OpCode::Jump(
-                    (self.instructions.len() - else_index - 1)
-                        .try_into()
-                        .unwrap(),
-                );
-            }
-            if let TokenType::ElseIf(token) = &self.tokens[self.token_pointer] {
-                self.if_statement(&token);
-
-                self.instructions[else_index] = OpCode::Jump(
-                    (self.instructions.len() - else_index - 1)
-                        .try_into()
-                        .unwrap(),
-                );
-                return;
-            }
-            if let TokenType::End(_) = self.tokens[self.token_pointer] {
-                self.advance();
-            } else {
-                self.compile_error("If without end", t);
-            }
-        } else {
-            self.compile_error("If without then", if_token);
+    fn if_statement(&mut self, statement: &If) {
+        self.expression(&statement.condition);
+        let if_index = self.add_instr(OpCode::JumpIfFalse(0), statement.token);
+        self.scoped_block(&statement.then_branch);
+        let else_index = self.add_instr(OpCode::Jump(0), 0);
+        self.instructions[if_index] = OpCode::JumpIfFalse(self.instructions.len() - if_index - 1);
+        if let Some(else_branch) = &statement.else_branch {
+            self.scoped_block(else_branch);
+            self.instructions[else_index] =
+                OpCode::Jump((self.instructions.len() - else_index - 1) as i32);
+        }
+        if let Some(else_if) = &statement.else_if {
+            self.if_statement(else_if);
+            self.instructions[else_index] =
+                OpCode::Jump((self.instructions.len() - else_index - 1) as i32);
         }
     }
 
-    fn while_statement(&mut self, while_token: &Token) {
-        //let loop_start: i32 = (self.instructions.len() - 1).try_into().unwrap();
-
+    fn while_statement(&mut self, token: &Token, condition: &Expr, body: &[Stmt]) {
         let loop_start = (self.instructions.len() as i32) - 1;
-        if !self.advance() {
-            return;
-        }
-        self.expression();
-        let jump_index = self.add_instr(OpCode::JumpIfFalse(0), while_token.line_number);
+        self.expression(condition);
+        let jump_index = self.add_instr(OpCode::JumpIfFalse(0), token);
 
-        self.begin_scope();
-        if !self.block() {
-            return;
-        }
-        self.end_scope();
+        self.begin_loop();
+        self.scoped_block(body);
 
         let len = self.instructions.len() as i32;
-        self.add_instr(
-            OpCode::Jump((loop_start - len).try_into().unwrap()),
-            while_token.line_number,
-        );
+        self.add_instr(OpCode::Jump(loop_start - len), token);
         self.instructions[jump_index] =
             OpCode::JumpIfFalse(self.instructions.len() - jump_index - 1);
-        let token = &self.tokens[self.token_pointer];
-        if let TokenType::End(_) = token {
-            self.advance();
-        } else {
-            dbg!(token);
-            self.compile_error("while without end", while_token)
-        }
+        let end = self.instructions.len();
+        self.end_loop((loop_start + 1) as usize, end);
     }
 
-    fn for_statement(&mut self, token: &Token) {
-        //dbg!(&self.tokens);
-        if !self.advance() {
-            self.compile_error("Invalid use of 'for' statement", token);
-            return;
-        }
+    fn for_statement(&mut self, statement: &For) {
+        let token = statement.token;
         self.begin_scope();
         // define the loop variable
-        let var_index =
-            if let TokenType::Identifier(variable_token) = &self.tokens[self.token_pointer] {
-                if !self.advance() {
-                    self.compile_error("Invalid use of 'for' statement", token);
-                    return;
-                }
-                self.for_variable(&variable_token)
-            } else {
-                self.compile_error("Invalid use of 'for' statement", token);
-                return;
-            };
+        self.expression(&statement.start);
+        let var_index = self.define_local(&statement.variable.lexeme);
+        self.add_instr(OpCode::DefineLocal(var_index), statement.variable);
+        self.add_instr(OpCode::Pop2, statement.variable);
 
         let loop_start: i32 = (self.instructions.len() - 1) as i32;
 
-        // to [expression]
-        if let TokenType::To(_) = &self.tokens[self.token_pointer] {
-            if !self.advance() {
-                self.compile_error("Invalid use of 'for' statement", token);
-                return;
-            }
-            self.expression();
-            // Step
-            let mut step: f64 = 1.0;
-            let mut step_down: bool = false;
-            if let TokenType::Step(_) = &self.tokens[self.token_pointer] {
-                self.advance();
-                step_down = if let TokenType::Minus(_) = &self.tokens[self.token_pointer] {
-                    self.advance();
-                    true
-                } else {
-                    false
-                };
-                if let TokenType::Number(number_token) = &self.tokens[self.token_pointer] {
-                    step = match number_token.lexeme.parse::<f64>() {
-                        Ok(v) => v,
-                        Err(_) => {
-                            self.compile_error("Could not parse number", token);
-                            return;
-                        }
-                    };
-                } else {
-                    self.compile_error("Step must be a number not equal to zero", token);
-                    return;
-                }
-            }
+        self.expression(&statement.end);
+        self.add_instr(OpCode::GetLocal(var_index), token);
+        if statement.step > 0.0 {
+            self.add_instr(OpCode::GreaterThanEq, token);
+        } else {
+            self.add_instr(OpCode::LessThanEq, token);
+        }
 
-            if step == 0.0 {
-                self.compile_error("Step cannot be zero", token);
-                return;
-            }
+        let jump_index = self.add_instr(OpCode::JumpIfFalse(0), token);
+        self.begin_loop();
+        // the body gets its own scope so its variables are dropped every iteration.
+        // The Pop after the increment replaces the value of its last statement
+        self.begin_scope();
+        self.statements(&statement.body, false);
+        self.end_scope();
+        let continue_target = self.instructions.len();
 
-            if step_down {
-                step = -step;
-            }
+        // inc the variable
+        self.add_instr(OpCode::GetLocal(var_index), token);
+        self.add_instr(OpCode::ConstantNum(statement.step), token);
+        self.add_instr(OpCode::Add, token);
+        self.add_instr(OpCode::SetLocal(var_index), token);
+        self.add_instr(OpCode::Pop, token);
 
-            self.add_instr(OpCode::GetLocal(var_index), token.line_number);
-            if step > 0.0 {
-                self.add_instr(OpCode::GreaterThanEq, token.line_number);
+        let len: i32 = self.instructions.len() as i32;
+        self.add_instr(OpCode::Jump(loop_start - len), token);
+        self.instructions[jump_index] =
+            OpCode::JumpIfFalse(self.instructions.len() - jump_index - 1);
+        let end = self.instructions.len();
+        self.end_loop(continue_target, end);
+
+        self.end_scope();
+    }
+
+    fn try_statement(&mut self, statement: &Try) {
+        let try_index = self.add_instr(OpCode::Try(0), statement.token);
+        self.try_depth += 1;
+        self.scoped_block(&statement.body);
+        self.try_depth -= 1;
+        self.add_instr(OpCode::EndTry, statement.token);
+        let jump_index = self.add_instr(OpCode::Jump(0), 0);
+
+        // the vm jumps here with the error on top of the stack
+        self.instructions[try_index] = OpCode::Try(self.instructions.len() - try_index - 1);
+
+        self.begin_scope();
+        if let Some(t) = statement.variable {
+            if is_native(t.lexeme.as_str()).is_ok() {
+                let message = format!(
+                    "Cannot define a variable with the same name as built in function {}",
+                    t.lexeme
+                );
+                self.compile_error(&message, t);
             } else {
-                self.add_instr(OpCode::LessThanEq, token.line_number);
+                let index = self.define_local(&t.lexeme);
+                self.add_instr(OpCode::DefineLocal(index), t);
             }
-
-            let jump_index = self.add_instr(OpCode::JumpIfFalse(0), token.line_number);
-            self.for_block();
-
-            // inc the variable
-            self.add_instr(OpCode::GetLocal(var_index), token.line_number);
-            self.add_instr(OpCode::ConstantNum(step), token.line_number);
-            self.add_instr(OpCode::Add, token.line_number);
-            self.add_instr(OpCode::SetLocal(var_index), token.line_number);
-            self.add_instr(OpCode::Pop, token.line_number);
-
-            self.advance();
-
-            //
-            let len: i32 = self.instructions.len() as i32;
-            self.add_instr(OpCode::Jump((loop_start - len) as i32), token.line_number);
-            self.instructions[jump_index] =
-                OpCode::JumpIfFalse(self.instructions.len() - jump_index - 1);
-            //self.add_instr(OpCode::Pop, token.line_number);
-            //
-        } else {
-            self.compile_error("Invalid use of 'for' statement", token);
-            return;
         }
-        // to 10 < x
-        //dbg!(&self.instructions);
-
+        self.add_instr(OpCode::Pop2, statement.catch);
+        self.block(&statement.handler);
         self.end_scope();
+
+        self.instructions[jump_index] =
+            OpCode::Jump((self.instructions.len() - jump_index - 1) as i32);
     }
 
-    fn skip_eol(&mut self) {
-        loop {
-            if let TokenType::Eol(_) = &self.tokens[self.token_pointer] {
-                self.advance();
-            } else {
-                return;
+    fn begin_loop(&mut self) {
+        self.loops.push(Loop {
+            depth: self.depth,
+            try_depth: self.try_depth,
+            breaks: Vec::new(),
+            continues: Vec::new(),
+        });
+    }
+
+    fn end_loop(&mut self, continue_target: usize, break_target: usize) {
+        if let Some(l) = self.loops.pop() {
+            for index in l.continues {
+                self.instructions[index] = OpCode::Jump(continue_target as i32 - index as i32 - 1);
+            }
+            for index in l.breaks {
+                self.instructions[index] = OpCode::Jump(break_target as i32 - index as i32 - 1);
             }
         }
     }
 
+    // break and continue: leave any scopes and try blocks opened inside the loop, then jump
+    fn loop_jump(&mut self, token: &Token, is_break: bool) {
+        let (depth, try_depth) = if let Some(l) = self.loops.last() {
+            (l.depth, l.try_depth)
+        } else {
+            let message = format!("'{}' outside of a loop", token.lexeme);
+            self.compile_error(&message, token);
+            return;
+        };
+
+        let vars_to_pop = self.variables.iter().filter(|x| x.depth > depth).count();
+        for _ in 0..vars_to_pop {
+            self.add_instr(OpCode::Pop2, token);
+        }
+        for _ in try_depth..self.try_depth {
+            self.add_instr(OpCode::EndTry, token);
+        }
+
+        let index = self.add_instr(OpCode::Jump(0), token);
+        let l = self.loops.last_mut().unwrap();
+        if is_break {
+            l.breaks.push(index);
+        } else {
+            l.continues.push(index);
+        }
+    }
+
     fn begin_scope(&mut self) {
         self.depth += 1;
     }
 
     fn end_scope(&mut self) {
-        //dbg!(&self.variables);
         if let Some(index) = self.variables.iter().position(|x| x.depth == self.depth) {
             let vars_to_pop = self.variables.len() - index;
             self.variables.truncate(index);
@@ -1056,269 +1005,274 @@ impl Compiler<'_> {
         self.depth -= 1;
     }
 
-    fn def_fn(&mut self, fn_token: &Token) {
-        if self.depth > 0 {
-            self.advance();
-            self.compile_error("Can't define a function within a function", fn_token);
-            return;
-        }
+    fn def_fn(&mut self, function: &Function) {
         let jump_index = self.add_instr(OpCode::Jump(0), 0);
         let fn_start = self.instructions.len();
         self.depth += 1;
-        self.advance();
+        let defined = self.fn_definition(function, fn_start);
 
-        // get function name
-        let name: String;
-        if let TokenType::Identifier(t) = &self.tokens[self.token_pointer] {
-            name = t.lexeme.clone();
-            self.advance();
-        } else {
-            self.compile_error("missing function name", fn_token);
-            return;
+        // remove the local variables as we are done with them
+        if let Some(index) = self.variables.iter().position(|x| x.depth == self.depth) {
+            self.variables.truncate(index);
         }
-        // consume (
-        if let TokenType::LeftParan(_) = &self.tokens[self.token_pointer] {
-            self.advance();
-        } else {
-            self.compile_error("missing '(' after function name", fn_token);
+
+        self.depth -= 1;
+        if !defined {
             return;
         }
 
+        let to_jump: i32 = (self.instructions.len() - fn_start).try_into().unwrap();
+
+        // patch jump so we jump over the function if not calling it
+        self.instructions[jump_index] = OpCode::Jump(to_jump);
+    }
+
+    // the params and body of a function, returns false if there is an error
+    fn fn_definition(&mut self, function: &Function, fn_start: usize) -> bool {
+        let Some(name) = function.name else {
+            return false;
+        };
         // define params and local variables
-        let mut arity: u8 = 0;
-        loop {
-            match &self.tokens[self.token_pointer] {
-                TokenType::RightParan(_) => {
-                    self.advance();
-                    break;
-                }
-                TokenType::Comma(_) => {
-                    if !self.advance() {
-                        self.compile_error("Expected )", fn_token);
-                        return;
-                    }
-                }
-                TokenType::Eof => {
-                    self.compile_error("Expected )", fn_token);
-                    return;
-                }
-                TokenType::Identifier(param) => {
-                    if !self.add_fn_param(param.lexeme.clone()) {
-                        return;
-                    }
-                    arity += 1;
-                    self.advance();
-                }
-                _ => {
-                    self.compile_error("Function parameter expected", fn_token);
-                    return;
-                }
+        for param in &function.params {
+            if !self.add_fn_param(param.lexeme.clone()) {
+                return false;
             }
         }
         // add the function here before compiling the body - that way we support recursion
-        if !self.add_fn(name, arity, fn_start) {
-            self.compile_error("Attempt to define the same function twice", fn_token);
-            return;
-        }
-
-        // The function body
-        let body_check = self.instructions.len();
-        self.block();
-        if body_check == self.instructions.len() {
-            self.compile_error("all functions must have a body", fn_token);
-            return;
-        }
-
-        // Check for end
-        if let TokenType::End(_) = &self.tokens[self.token_pointer] {
-            self.advance();
-        } else {
-            self.compile_error("Function without end", fn_token);
-            return;
+        let arity = function.params.len() as u8;
+        if !self.add_fn(name.lexeme.clone(), arity, fn_start) {
+            self.compile_error("Attempt to define the same function twice", function.token);
+            return false;
         }
+        let params = function.params.iter().map(|p| p.lexeme.clone()).collect();
+        self.add_definition(name, Some(params));
 
+        self.block(&function.body);
         // add return in case there isn't one
         self.add_instr(OpCode::Return, 0);
+        true
+    }
 
-        // remove the local variables as we are done with them
-        if let Some(index) = self.variables.iter().position(|x| x.depth == self.depth) {
-            self.variables.truncate(index);
-        }
+    // fn(params) body end
+    fn lambda(&mut self, function: &Function) {
+        // the body only sees globals, its params and the locals it captures
+        let globals = self.variables.iter().take_while(|x| x.depth == 0).count();
+        let enclosing = self.variables.split_off(globals);
+        let depth = std::mem::replace(&mut self.depth, 1);
+        let loops = std::mem::take(&mut self.loops);
+        let try_depth = std::mem::replace(&mut self.try_depth, 0);
 
-        self.depth -= 1;
+        let jump_index = self.add_instr(OpCode::Jump(0), 0);
+        let fn_start = self.instructions.len();
+        let arity = self.lambda_body(function, &enclosing, fn_start);
 
-        let to_jump: i32 = (self.instructions.len() - fn_start).try_into().unwrap();
+        self.variables.truncate(globals);
+        self.variables.extend(enclosing);
+        self.depth = depth;
+        self.loops = loops;
+        self.try_depth = try_depth;
 
-        // patch jump so we jump over the function if not calling it
+        let Some((arity, captures)) = arity else {
+            return;
+        };
+        self.instructions[jump_index] =
+            OpCode::Jump((self.instructions.len() - jump_index - 1) as i32);
 
-        self.instructions[jump_index] = OpCode::Jump(to_jump);
+        // copy the captured locals onto the stack, they become part of the value
+        for index in &captures {
+            self.add_instr(OpCode::GetLocal(*index), function.token);
+        }
+        if captures.is_empty() {
+            self.add_instr(OpCode::Func(fn_start, arity), function.token);
+        } else {
+            self.add_instr(
+                OpCode::Closure(fn_start, arity, captures.len() as u8),
+                function.token,
+            );
+        }
     }
 
-    fn block(&mut self) -> bool {
-        loop {
-            if let Some(token) = self.tokens.get(self.token_pointer) {
-                match token {
-                    TokenType::Else(_)
-                    | TokenType::ElseIf(_)
-                    | TokenType::End(_)
-                    | TokenType::When(_)
-                    | TokenType::Eof => break,
-                    _ => {
-                        self.statement();
-                    }
-                }
-            } else {
-                self.compile_error_message("Unexpected Error - Possibly missing 'end'");
-                return false;
+    // returns the arity and the enclosing locals to capture
+    fn lambda_body(
+        &mut self,
+        function: &Function,
+        enclosing: &[Variable],
+        fn_start: usize,
+    ) -> Option<(u8, Vec<usize>)> {
+        for param in &function.params {
+            if !self.add_fn_param(param.lexeme.clone()) {
+                return None;
             }
         }
-        true
-    }
 
-    fn for_block(&mut self) -> bool {
-        loop {
-            if let Some(token) = self.tokens.get(self.token_pointer) {
-                match token {
-                    TokenType::Next(_) | TokenType::Eof => break,
-                    _ => {
-                        self.statement();
-                    }
+        // find the enclosing locals used anywhere in the body, including nested lambdas
+        let mut names = Vec::new();
+        for statement in &function.body {
+            statement.identifiers(&mut names);
+        }
+        let mut captures: Vec<usize> = Vec::new();
+        for name in names {
+            if self
+                .variables
+                .iter()
+                .any(|x| x.depth > 0 && x.name == name.lexeme)
+            {
+                continue;
+            }
+            if let Some(index) = enclosing.iter().rposition(|x| x.name == name.lexeme) {
+                if !captures.contains(&index) {
+                    captures.push(index);
                 }
-            } else {
-                self.compile_error_message("For without next");
-                return false;
             }
         }
-        true
+        for index in &captures {
+            let name = enclosing[*index].name.clone();
+            self.variables.push(Variable::new(name, self.depth));
+        }
+        let arity = function.params.len() as u8;
+        self.functions
+            .push((String::from("<lambda>"), arity, fn_start));
+
+        self.block(&function.body);
+        self.add_instr(OpCode::Return, 0);
+
+        Some((arity, captures))
     }
 
-    fn dot(&mut self, token: &Token) -> bool {
-        if self.token_pointer >= self.tokens.len() {
-            self.compile_error("Unexpected end of file after '.'", token);
-            return false;
+    fn block(&mut self, statements: &[Stmt]) {
+        self.statements(statements, true);
+    }
+
+    // value_used is false when the code run after the last statement replaces its value
+    fn statements(&mut self, statements: &[Stmt], value_used: bool) {
+        for (i, statement) in statements.iter().enumerate() {
+            // the Pop of an expression statement replaces the value of the one before
+            let used = match statements.get(i + 1) {
+                Some(next) => !matches!(next, Stmt::Expression(_)),
+                None => value_used,
+            };
+            self.statement(statement, used);
         }
-        // get the name of the variable we are mutating
-        let variable = if let TokenType::Identifier(t) = &self.tokens[self.token_pointer - 2] {
-            self.check_variable(t.lexeme.clone())
-        } else {
-            // no variable
-            VarType::None
-        };
+    }
 
-        self.advance();
-        // get name of function
-        let func_name = if let TokenType::Identifier(t) = &self.tokens[self.token_pointer - 1] {
-            t.lexeme.clone()
-        } else {
-            self.compile_error("Syntax Error - Invalid call target", token);
-            return false;
+    fn scoped_block(&mut self, statements: &[Stmt]) {
+        self.begin_scope();
+        self.block(statements);
+        self.end_scope();
+    }
+
+    fn method_call(&mut self, receiver: &Expr, token: &Token, method: &Token, args: &[Expr]) {
+        self.expression(receiver);
+        // the variable a method like push changes, if it's called on one
+        let variable = match receiver {
+            Expr::Variable(t) => self.check_variable(t.lexeme.clone()),
+            _ => VarType::None,
         };
+        for arg in args {
+            self.expression(arg);
+        }
 
-        // check for left paran
-        match &self.tokens[self.token_pointer] {
-            TokenType::LeftParan(_) => {
-                self.advance();
-                self.method_call(token, func_name, variable)
+        let func_name = method.lexeme.clone();
+        let arguments = args.len() as u32;
+        //check if it's native
+        if let Ok(index) = is_native_mut(func_name.as_str()) {
+            let (_, _, min, max) = Vm::MUT_NATIVES[index];
+            if let Some(message) = arity_error(&func_name, min, max, arguments as usize) {
+                self.call_error(&message, token);
             }
-            _ => {
-                self.compile_error("Missing '(' in method call", token);
-                false
+            self.add_instr(OpCode::CallNativeMut(index, arguments, variable), token);
+        } else if let Ok(index) = is_native(func_name.as_str()) {
+            let (_, _, min, max, _) = Vm::NATIVES[index];
+            // the variable the method is called on is the first argument
+            let given = arguments as usize + 1;
+            if let Some(message) = arity_error(&func_name, min, max, given) {
+                self.call_error(&message, token);
             }
-        }
-    }
-
-    fn method_call(&mut self, token: &Token, func_name: String, variable: VarType) -> bool {
-        let mut arguments = 0;
-        loop {
-            match &self.tokens[self.token_pointer] {
-                TokenType::RightParan(_) => {
-                    self.advance();
-
-                    //check if it's native
-                    if let Ok(index) = is_native_mut(func_name.as_str()) {
-                        self.add_instr(
-                            OpCode::CallNativeMut(index, arguments, variable),
-                            token.line_number,
-                        );
-                    } else if let Ok(index) = is_native(func_name.as_str()) {
-                        self.add_instr(OpCode::CallNative(index, arguments + 1), token.line_number);
-                    } else {
-                        //let msg = format!("Method {} not found", func_name);
-                        //self.compile_error(&msg, token);
-
-                        // get index of fn
-                        if let Some(index) = self.functions.iter().position(|x| x.0 == func_name) {
-                            let f = &self.functions[index];
-                            if f.1 != (arguments + 1) as u8 {
-                                self.compile_error(
-                                    "Wrong number of arguments pass to function",
-                                    token,
-                                );
-                                return false;
-                            }
-                            self.add_instr(OpCode::Invoke(f.2, arguments + 1), token.line_number);
-                        } else {
-                            self.add_instr(
-                                OpCode::InvokePlaceholder(func_name, arguments + 1),
-                                token.line_number,
-                            );
-                            //let msg = format!("Function {} not found", func_name);
-                            //self.compile_error(&msg, token);
-                        }
-                    }
-
-                    return true;
-                }
-                TokenType::Comma(_) => {
-                    if !self.advance() {
-                        self.compile_error("Expected )", token);
-                        return false;
-                    }
-                }
-                TokenType::Eof => {
-                    self.compile_error("Expected )", token);
-                    return false;
-                }
-                _ => {
-                    self.expression();
-                    arguments += 1;
-                }
+            self.add_instr(OpCode::CallNative(index, arguments + 1), token);
+        } else if let Some(index) = self.host_function(&func_name) {
+            self.add_instr(OpCode::CallHost(index, arguments + 1), token);
+        } else if let Some(index) = self.functions.iter().position(|x| x.0 == func_name) {
+            let (_, arity, start) = self.functions[index];
+            let given = arguments as usize + 1;
+            if let Some(message) = arity_error(&func_name, arity, arity, given) {
+                self.call_error(&message, token);
             }
+            self.add_instr(OpCode::Invoke(start, arity as u32), token);
+        } else {
+            self.add_instr(OpCode::InvokePlaceholder(func_name, arguments + 1), token);
         }
     }
 
-    fn statement(&mut self) {
-        let token = &self.tokens[self.token_pointer];
-        match token {
-            TokenType::Eol(_) => {
-                self.advance();
+    // value_used is false if the statement's value can't become the return value
+    fn statement(&mut self, statement: &Stmt, value_used: bool) {
+        match statement {
+            Stmt::Expression(Expr::Assign {
+                target:
+                    Target::Subscript {
+                        variable,
+                        bracket,
+                        index,
+                    },
+                value,
+            }) if !value_used => self.subscript_set(variable, bracket, index, value, false),
+            Stmt::Expression(expr) => {
+                self.expression(expr);
+                self.add_instr(OpCode::Pop, 0);
             }
-            TokenType::If(t) => self.if_statement(t),
-            TokenType::While(t) => self.while_statement(t),
-            TokenType::Function(t) => self.def_fn(t),
-            TokenType::For(t) => self.for_statement(t),
-            //TokenType::Data(t) => self.data_statement(t),
-            TokenType::Return(t) => {
-                self.add_instr(OpCode::Return, t.line_number);
-                self.advance();
+            Stmt::If(statement) => self.if_statement(statement),
+            Stmt::While {
+                token,
+                condition,
+                body,
+            } => self.while_statement(token, condition, body),
+            Stmt::For(statement) => self.for_statement(statement),
+            Stmt::Try(statement) => self.try_statement(statement),
+            Stmt::Function(function) => self.def_fn(function),
+            // the imported file itself is loaded and compiled before this one, see main.rs
+            Stmt::Import { .. } => (),
+            Stmt::Break(token) => self.loop_jump(token, true),
+            Stmt::Continue(token) => self.loop_jump(token, false),
+            Stmt::Return(token) => {
+                self.add_instr(OpCode::Return, *token);
             }
-            _ => self.expression_statement(),
         }
+        // an error only stops the rest of the statement it's in
+        self.panic_mode = false;
     }
 
+    /// Compiles the main script. Errors are printed as they are found, and compiling
+    /// carries on after them so they can all be fixed at once. in_error is set if
+    /// there were any.
     pub fn compile(&mut self) {
-        while self.token_pointer < self.tokens.len() {
-            let token = &self.tokens[self.token_pointer];
-            match token {
-                TokenType::Eof => break,
-                _ => self.statement(),
-            };
-            if self.in_error {
-                return;
+        self.files.push((self.instructions.len(), None));
+        self.sources.push(self.source);
+        self.compile_statements(self.tokens);
+        self.second_pass();
+    }
+
+    // Compiles an imported file ahead of the script that imports it. Its functions are
+    // added to self.functions, so they can be called from the importing script.
+    pub fn compile_import(&mut self, tokens: &'a [TokenType], name: &str, source: &'a str) {
+        self.files
+            .push((self.instructions.len(), Some(name.to_string())));
+        self.sources.push(source);
+        self.compile_statements(tokens);
+    }
+
+    // Each statement is compiled once it's parsed, so errors come out in the order
+    // they are in the file
+    fn compile_statements(&mut self, tokens: &[TokenType]) {
+        let mut parser = Parser::new(tokens);
+        while !parser.at_end() {
+            let statement = parser.statement();
+            for error in std::mem::take(&mut parser.errors) {
+                self.syntax_error(&error);
+            }
+            self.panic_mode = false;
+            if let Some(statement) = statement {
+                self.statement(&statement, true);
             }
         }
-
-        self.second_pass();
     }
 
     pub fn second_pass(&mut self) {
@@ -1328,19 +1282,19 @@ impl Compiler<'_> {
             //OpCode::FuncPlaceholder
             if let OpCode::FuncPlaceholder(name, line_number) = inst {
                 if let Some(fi) = self.functions.iter().position(|x| x.0 == *name) {
-                    let f = &self.functions[fi];
-                    // if f.1 != *arguments as u8 {
-                    //     self.compile_error_line(
-                    //         "Wrong number of arguments pass to function",
-                    //         *line_number,
-                    //     );
-                    //     return;
-                    // }
+                    let (_, arity, start) = self.functions[fi];
+                    let message = match self.forward_calls.get(&index) {
+                        Some(arguments) => arity_error(name, arity, arity, *arguments),
+                        None => None,
+                    };
+                    if let Some(message) = message {
+                        self.compile_error_at(&message, self.line_numbers[index], index);
+                    }
 
-                    self.instructions[index] = OpCode::Func(f.2, f.1);
+                    self.instructions[index] = OpCode::Func(start, arity);
                 } else {
                     let message = format!("function {} not found", name);
-                    self.compile_error_line(&message, *line_number);
+                    self.compile_error_at(&message, *line_number, index);
 
                     // if !name.starts_with('@') {
                     //     let message = format!("function {} not found", name);
@@ -1350,22 +1304,20 @@ impl Compiler<'_> {
                 }
             } else if let OpCode::InvokePlaceholder(name, arguments) = inst {
                 if let Some(fi) = self.functions.iter().position(|x| x.0 == *name) {
-                    let f = &self.functions[fi];
-
-                    if f.1 != *arguments as u8 {
-                        self.compile_error_line(
-                            "Wrong number of arguments pass to function",
-                            self.line_numbers[index],
-                        );
-                        return;
+                    let (_, arity, start) = self.functions[fi];
+                    let message = arity_error(name, arity, arity, *arguments as usize);
+                    if let Some(message) = message {
+                        self.compile_error_at(&message, self.line_numbers[index], index);
                     }
 
-                    self.instructions[index] = OpCode::Invoke(f.2, f.1 as u32);
+                    self.instructions[index] = OpCode::Invoke(start, arity as u32);
                 } else {
                     let message = format!("function {} not found", name);
-                    self.compile_error_line(&message, self.line_numbers[index]);
+                    self.compile_error_at(&message, self.line_numbers[index], index);
                 }
             }
+            // each of these errors is separate, so report them all
+            self.panic_mode = false;
             index += 1;
         }
     }
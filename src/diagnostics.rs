@@ -0,0 +1,89 @@
+use colored::Colorize;
+
+use crate::scanner::{ScanError, Span};
+
+/// The line an error is on with the part it's about underlined, printed under the
+/// error message:
+///
+/// ```text
+///   |
+/// 3 | total = count / 0
+///   |               ^
+/// ```
+///
+/// Without a column (e.g. for a string over several lines) only the line is shown.
+pub fn excerpt(line: &str, line_number: u32, span: Span) -> String {
+    let number = line_number.to_string();
+    let gutter = " ".repeat(number.len());
+    let bar = "|".blue().bold();
+    let mut excerpt = format!("{gutter} {bar}\n{} {bar} {line}", number.blue().bold());
+
+    // columns count bytes, the carets are one per character
+    let start = (span.column as usize).wrapping_sub(1);
+    if span.column > 0 && line.is_char_boundary(start) {
+        // keep tabs so the carets line up with the line above
+        let padding: String = line[..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let length = line[start..]
+            .char_indices()
+            .take_while(|(i, _)| *i < span.end - span.start)
+            .count()
+            .max(1);
+        excerpt.push_str(&format!(
+            "\n{gutter} {bar} {padding}{}",
+            "^".repeat(length).red().bold()
+        ));
+    }
+    excerpt
+}
+
+/// The excerpt for a line of source code, or None when the line isn't there
+pub fn source_excerpt(source: &str, line_number: u32, span: Span) -> Option<String> {
+    let line = source.lines().nth((line_number as usize).checked_sub(1)?)?;
+    Some(excerpt(line, line_number, span))
+}
+
+/// Prints an error from scanner::tokenize, source is the code that was tokenized
+pub fn print_scan_error(error: &ScanError, source: &str) {
+    eprintln!(
+        "Tokenize Error: {}, line {}",
+        error.message.red(),
+        error.line_number
+    );
+    if let Some(excerpt) = source_excerpt(source, error.line_number, error.span) {
+        eprintln!("{excerpt}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{excerpt, source_excerpt};
+    use crate::scanner::Span;
+
+    #[test]
+    fn test_excerpt() {
+        colored::control::set_override(false);
+        assert_eq!(
+            excerpt("x = 1 / y", 12, Span::new(9, 8, 1)),
+            "   |\n12 | x = 1 / y\n   |         ^"
+        );
+        // tabs are kept, and the carets stop at the end of the line
+        assert_eq!(
+            excerpt("\tfoo(1", 1, Span::new(2, 1, 20)),
+            "  |\n1 | \tfoo(1\n  | \t^^^^^"
+        );
+        // the column counts the 2 bytes of é
+        assert_eq!(
+            excerpt("s = \"é\" + x", 1, Span::new(12, 11, 1)),
+            "  |\n1 | s = \"é\" + x\n  |           ^"
+        );
+        assert_eq!(
+            excerpt("print(\"a", 1, Span::new(0, 6, 3)),
+            "  |\n1 | print(\"a"
+        );
+        assert_eq!(source_excerpt("a\nb", 3, Span::default()), None);
+        assert_eq!(source_excerpt("a\nb", 0, Span::default()), None);
+    }
+}
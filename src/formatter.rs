@@ -0,0 +1,423 @@
+//! `vbas fmt`: re-indents a script and changes short-cuts to the usual keywords.
+//!
+//! Only the start of each line and a few keywords change. Everything else on a line,
+//! including comments and the insides of strings, is left as it was written.
+
+use crate::scanner::{self, ScanError, Span, Token, TokenType};
+
+const INDENT: &str = "    ";
+
+/// An error that stops a script being formatted, e.g. an `end` with nothing to end
+#[derive(Debug, PartialEq)]
+pub struct FormatError {
+    pub message: String,
+    pub line_number: u32,
+    pub span: Span,
+}
+
+impl std::fmt::Display for FormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} on line {}", self.message, self.line_number)
+    }
+}
+
+impl From<ScanError> for FormatError {
+    fn from(e: ScanError) -> Self {
+        FormatError {
+            message: e.message.to_string(),
+            line_number: e.line_number,
+            span: e.span,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    If,
+    While,
+    For,
+    Function,
+    Match,
+    Case, // from a 'when' (or the 'else') in a match to the next one
+    Try,
+    Bracket(char), // the closing bracket
+}
+
+// a block or bracket that hasn't been closed yet
+struct Block<'t> {
+    kind: Kind,
+    line: usize, // the line it starts on, counting from 0
+    token: &'t Token,
+}
+
+fn error(message: String, token: &Token) -> FormatError {
+    FormatError {
+        message,
+        line_number: token.line_number,
+        span: token.span,
+    }
+}
+
+// Each line with blocks starting on it indents the lines after it once, however many
+// blocks start on it
+fn levels(blocks: &[Block]) -> usize {
+    let mut levels = 0;
+    let mut line = None;
+    for block in blocks {
+        if line != Some(block.line) {
+            levels += 1;
+            line = Some(block.line);
+        }
+    }
+    levels
+}
+
+fn closing_keyword(kind: Kind) -> &'static str {
+    match kind {
+        Kind::For => "next",
+        Kind::Bracket(')') => ")",
+        Kind::Bracket(']') => "]",
+        Kind::Bracket(_) => "}",
+        _ => "end",
+    }
+}
+
+// Closes the innermost block, which has to be one the token closes
+fn close<'t>(blocks: &mut Vec<Block<'t>>, token: &'t Token) -> Result<usize, FormatError> {
+    let block = match blocks.pop() {
+        Some(block) => block,
+        None => {
+            let message = format!("'{}' without a block to close", token.lexeme);
+            return Err(error(message, token));
+        }
+    };
+    let closer = match token.lexeme.as_str() {
+        ";" => "end",
+        lexeme => lexeme,
+    };
+    let expected = closing_keyword(block.kind);
+    if closer != expected {
+        let message = format!(
+            "expected '{expected}' to close the '{}' on line {}, found '{}'",
+            block.token.lexeme, block.token.line_number, token.lexeme
+        );
+        return Err(error(message, token));
+    }
+    // an end after the cases finishes the match as well
+    if block.kind == Kind::Case {
+        blocks.pop();
+    }
+    Ok(levels(blocks))
+}
+
+// Keeps track of the open blocks as each token is read. For a token that closes a
+// block, or comes between two parts of one (like 'else'), returns how far a line
+// starting with it should be indented.
+fn read_token<'t>(
+    blocks: &mut Vec<Block<'t>>,
+    token_type: &'t TokenType,
+    line: usize,
+) -> Result<Option<usize>, FormatError> {
+    let token = match token_type.get_token() {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+    let top = blocks.last().map(|b| b.kind);
+    let open = |kind| Block { kind, line, token };
+
+    let opens = match token_type {
+        TokenType::If(_) => Kind::If,
+        TokenType::While(_) => Kind::While,
+        TokenType::For(_) => Kind::For,
+        TokenType::Function(_) => Kind::Function,
+        TokenType::Match(_) => Kind::Match,
+        TokenType::Try(_) => Kind::Try,
+        TokenType::LeftParan(_) => Kind::Bracket(')'),
+        TokenType::LeftBracket(_) => Kind::Bracket(']'),
+        TokenType::LeftBrace(_) => Kind::Bracket('}'),
+        TokenType::End(_)
+        | TokenType::Next(_)
+        | TokenType::RightParan(_)
+        | TokenType::RightBracket(_)
+        | TokenType::RightBrace(_) => return close(blocks, token).map(Some),
+        // the first 'when' starts the cases of a match, and so does an 'else' without one
+        TokenType::When(_) | TokenType::Else(_) if top == Some(Kind::Match) => {
+            blocks.push(open(Kind::Case));
+            return Ok(None);
+        }
+        TokenType::When(_) | TokenType::Else(_) | TokenType::ElseIf(_) | TokenType::Catch(_) => {
+            let part_of = match token_type {
+                TokenType::When(_) => Kind::Case,
+                TokenType::Else(_) if top == Some(Kind::Case) => Kind::Case,
+                TokenType::Catch(_) => Kind::Try,
+                _ => Kind::If,
+            };
+            if top != Some(part_of) {
+                let wanted = match part_of {
+                    Kind::Case => "match",
+                    Kind::Try => "try",
+                    _ => "if",
+                };
+                let message = format!("'{}' without a matching '{wanted}'", token.lexeme);
+                return Err(error(message, token));
+            }
+            // the lines after it are indented from this line rather than the block's start
+            let block = blocks.pop().unwrap();
+            let indent = levels(blocks);
+            blocks.push(Block { line, ..block });
+            return Ok(Some(indent));
+        }
+        _ => return Ok(None),
+    };
+    blocks.push(open(opens));
+    Ok(None)
+}
+
+// The changes to make to keywords: the start and end of each one, and what it's changed to
+fn keyword_changes(tokens: &[TokenType], chars: &[char]) -> Vec<(usize, usize, String)> {
+    let mut changes = Vec::new();
+    for (i, token_type) in tokens.iter().enumerate() {
+        match token_type {
+            // 'function' for named functions and 'fn' for anonymous ones
+            TokenType::Function(t) => {
+                let keyword = match tokens.get(i + 1) {
+                    Some(TokenType::Identifier(_)) => "function",
+                    Some(TokenType::LeftParan(_)) => "fn",
+                    _ => continue,
+                };
+                if t.lexeme != keyword {
+                    changes.push((t.span.start, t.span.end, keyword.to_string()));
+                }
+            }
+            TokenType::End(t) if t.lexeme == ";" => {
+                let space = |c: Option<&char>| c.is_none_or(|c| c.is_whitespace());
+                let mut end = String::from("end");
+                if !space(t.span.start.checked_sub(1).and_then(|i| chars.get(i))) {
+                    end.insert(0, ' ');
+                }
+                if !space(chars.get(t.span.end)) {
+                    end.push(' ');
+                }
+                changes.push((t.span.start, t.span.end, end));
+            }
+            _ => (),
+        }
+    }
+    changes
+}
+
+/// Formats a script. Each line is indented by four spaces for every block it's in,
+/// named functions use `function`, anonymous ones `fn`, and `;` becomes `end`.
+pub fn format(source: &str) -> Result<String, FormatError> {
+    let tokens = scanner::tokenize(source)?;
+    let chars: Vec<char> = source.chars().collect();
+
+    // where each line starts and ends, as positions in chars like the token spans
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, c) in chars.iter().enumerate() {
+        if *c == '\n' {
+            lines.push((start, i));
+            start = i + 1;
+        }
+    }
+    lines.push((start, chars.len()));
+    let line_of = |position: usize| lines.partition_point(|l| l.0 <= position) - 1;
+
+    let mut line_tokens: Vec<Vec<&TokenType>> = lines.iter().map(|_| Vec::new()).collect();
+    // lines inside a string that started on an earlier line are left alone, and so is
+    // the end of the line a string like that starts on
+    let mut in_string = vec![false; lines.len()];
+    let mut string_start = vec![false; lines.len()];
+    for token_type in &tokens {
+        let span = match token_type.get_token() {
+            Some(t) => t.span,
+            None => continue,
+        };
+        let first = line_of(span.start);
+        line_tokens[first].push(token_type);
+        if let TokenType::String(_) = token_type {
+            let last = line_of(span.end.saturating_sub(1).max(span.start));
+            if last > first {
+                string_start[first] = true;
+                in_string[first + 1..=last].fill(true);
+            }
+        }
+    }
+    let changes = keyword_changes(&tokens, &chars);
+
+    let mut formatted: Vec<String> = Vec::new();
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut continued = false; // the line before ended with a '+'
+    for (line, (start, end)) in lines.iter().enumerate() {
+        let mut indent = levels(&blocks) + continued as usize;
+        let mut leading = true;
+        for token_type in &line_tokens[line] {
+            let closing = read_token(&mut blocks, token_type, line)?;
+            match closing {
+                Some(level) if leading => indent = indent.min(level),
+                _ => leading = false,
+            }
+        }
+        if let Some(last) = line_tokens[line].last() {
+            continued = matches!(last, TokenType::Plus(_));
+        }
+
+        if in_string[line] {
+            formatted.push(chars[*start..*end].iter().collect());
+            continue;
+        }
+        let mut text = String::new();
+        let mut i = *start;
+        while i < *end {
+            match changes.iter().find(|c| c.0 == i) {
+                Some((_, change_end, keyword)) => {
+                    text.push_str(keyword);
+                    i = *change_end;
+                }
+                None => {
+                    text.push(chars[i]);
+                    i += 1;
+                }
+            }
+        }
+        let text = match string_start[line] {
+            true => text.trim_start(),
+            false => text.trim(),
+        };
+        if text.is_empty() {
+            formatted.push(String::new());
+        } else {
+            formatted.push(format!("{}{text}", INDENT.repeat(indent)));
+        }
+    }
+
+    // report the innermost block that isn't closed
+    if let Some(block) = blocks.iter().rev().find(|b| b.kind != Kind::Case) {
+        let message = format!("'{}' is never closed", block.token.lexeme);
+        return Err(error(message, block.token));
+    }
+
+    while formatted.last().is_some_and(|l| l.is_empty()) {
+        formatted.pop();
+    }
+    if formatted.is_empty() {
+        return Ok(String::new());
+    }
+    Ok(formatted.join("\n") + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format;
+
+    #[test]
+    fn test_format() {
+        let source = "\
+' adds numbers
+fn add(a,b)
+a+b ' the sum
+;
+for i = 1 to 3
+\tif i == 2 then
+  print(\"two\")
+   else
+print(add(i, 1));
+next
+
+
+";
+        let expected = "\
+' adds numbers
+function add(a,b)
+    a+b ' the sum
+end
+for i = 1 to 3
+    if i == 2 then
+        print(\"two\")
+    else
+        print(add(i, 1)) end
+next
+";
+        assert_eq!(format(source), Ok(String::from(expected)));
+        // formatting again changes nothing
+        assert_eq!(format(expected), Ok(String::from(expected)));
+    }
+
+    #[test]
+    fn test_format_blocks() {
+        let source = "\
+x = match i
+when 0 then 1
+when 2 to 3 then
+print(\"range\")
+else 7
+end
+double = function(x) x * 2 end
+m = {
+\"name\": \"Boardwalk\",
+\"price\": map(a, fn(x)
+x + 1
+end)
+}
+try
+throw(\"x\")
+catch err
+s = \"line one +
+  line two\"
+end
+total = 1 +
+2
+";
+        let expected = "\
+x = match i
+    when 0 then 1
+    when 2 to 3 then
+        print(\"range\")
+    else 7
+end
+double = fn(x) x * 2 end
+m = {
+    \"name\": \"Boardwalk\",
+    \"price\": map(a, fn(x)
+        x + 1
+    end)
+}
+try
+    throw(\"x\")
+catch err
+    s = \"line one +
+  line two\"
+end
+total = 1 +
+    2
+";
+        assert_eq!(format(source), Ok(String::from(expected)));
+        assert_eq!(format(expected), Ok(String::from(expected)));
+    }
+
+    #[test]
+    fn test_format_errors() {
+        let error = |source| format(source).unwrap_err().to_string();
+        assert_eq!(
+            error("print(1)\nend"),
+            "'end' without a block to close on line 2"
+        );
+        assert_eq!(
+            error("for i = 1 to 2\nend"),
+            "expected 'next' to close the 'for' on line 1, found 'end' on line 2"
+        );
+        assert_eq!(
+            error("while x\n  if y then 1\nend"),
+            "'while' is never closed on line 1"
+        );
+        assert_eq!(
+            error("x = 1\nelse"),
+            "'else' without a matching 'if' on line 2"
+        );
+        assert_eq!(
+            error("print(\"{a\")"),
+            "missing '}' in string interpolation on line 1"
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::scanner::{self, TokenType};
+
+/// A file pulled in with `import`, compiled ahead of the file that imports it
+pub struct Import {
+    pub name: String,
+    pub path: PathBuf,
+    pub contents: String,
+    pub tokens: Vec<TokenType>,
+}
+
+// Loads every file imported by a script, each one after the files it imports.
+// `loading` is the chain of files currently being loaded, used to detect cycles.
+fn load_imports(
+    tokens: &[TokenType],
+    dir: &Path,
+    loading: &mut Vec<PathBuf>,
+    imports: &mut Vec<Import>,
+) -> Result<(), String> {
+    for pair in tokens.windows(2) {
+        let name = if let [TokenType::Import(_), TokenType::String(t)] = pair {
+            &t.lexeme
+        } else {
+            continue;
+        };
+        let path = match fs::canonicalize(dir.join(name)) {
+            Ok(path) => path,
+            Err(e) => return Err(format!("could not import {name}: {e}")),
+        };
+        if loading.contains(&path) {
+            let chain: Vec<String> = loading
+                .iter()
+                .chain(std::iter::once(&path))
+                .map(|p| {
+                    p.file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .to_string()
+                })
+                .collect();
+            return Err(format!("import cycle {}", chain.join(" -> ")));
+        }
+        // each file is only compiled once
+        if imports.iter().any(|i| i.path == path) {
+            continue;
+        }
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) => return Err(format!("could not import {name}: {e}")),
+        };
+        let tokens = match scanner::tokenize(&contents) {
+            Ok(tokens) => tokens,
+            Err(msg) => return Err(format!("{msg} in {name}")),
+        };
+        loading.push(path.clone());
+        load_imports(&tokens, path.parent().unwrap(), loading, imports)?;
+        loading.pop();
+        imports.push(Import {
+            name: name.clone(),
+            path,
+            contents,
+            tokens,
+        });
+    }
+    Ok(())
+}
+
+/// Loads the files imported by a script. Paths are relative to the script, or the
+/// current directory if there isn't one.
+pub fn load_script_imports(
+    tokens: &[TokenType],
+    script_path: Option<&Path>,
+) -> Result<Vec<Import>, String> {
+    let mut loading = Vec::new();
+    let mut dir = PathBuf::from(".");
+    if let Some(path) = script_path.and_then(|p| fs::canonicalize(p).ok()) {
+        if let Some(parent) = path.parent() {
+            dir = parent.to_path_buf();
+        }
+        loading.push(path);
+    }
+    let mut imports = Vec::new();
+    load_imports(tokens, &dir, &mut loading, &mut imports)?;
+    Ok(imports)
+}
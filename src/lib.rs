@@ -0,0 +1,307 @@
+//! Very Basic as a library, for running scripts from a Rust program.
+//!
+//! [`Engine`] is the way in. Rust functions can be registered for scripts to call,
+//! and values passed in and out with the `From`/`TryFrom` conversions on [`ValueType`].
+//!
+//! ```
+//! use vbas::{Engine, ValueType};
+//!
+//! let mut engine = Engine::new();
+//! engine.register("double", |args| {
+//!     let n = f64::try_from(args[0].clone())?;
+//!     Ok(ValueType::from(n * 2.0))
+//! });
+//! engine.set_global("x", 21.0);
+//!
+//! let result = engine.run("y = double(x)\ny + 1").unwrap().unwrap();
+//! assert_eq!(f64::try_from(result), Ok(43.0));
+//! assert_eq!(f64::try_from(engine.global("y").unwrap().clone()), Ok(42.0));
+//! ```
+//!
+//! The modules below are what the `vbas` command line tool is built from. They may
+//! change between versions, `Engine` and `ValueType` won't.
+
+pub mod ast;
+pub mod bytecode;
+mod common;
+pub mod compiler;
+pub mod diagnostics;
+pub mod formatter;
+pub mod imports;
+pub mod optimizer;
+pub mod parser;
+pub mod scanner;
+pub mod vm;
+
+use std::{path::PathBuf, time::Duration};
+
+use compiler::{Compiler, Variable};
+pub use vm::{HostFunction, Sandbox, ValueType};
+use vm::{SourceFile, Vm};
+
+/// Compiles and runs scripts, with the host functions and global variables given to it
+pub struct Engine {
+    host_functions: Vec<(String, HostFunction)>,
+    globals: Vec<(String, ValueType<'static>)>,
+    exit_code: Option<i32>,
+    pub max_call_depth: usize,
+    /// What scripts may do outside the VM, everything is allowed by default
+    pub sandbox: Sandbox,
+    /// Stop scripts after this many instructions
+    pub max_instructions: Option<u64>,
+    /// Stop scripts after running for this long
+    pub timeout: Option<Duration>,
+    /// Where setting_get and setting_set keep their values
+    pub config_file: PathBuf,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Engine {
+            host_functions: Vec::new(),
+            globals: Vec::new(),
+            exit_code: None,
+            max_call_depth: vm::DEFAULT_MAX_CALL_DEPTH,
+            sandbox: Sandbox::allow_all(),
+            max_instructions: None,
+            timeout: None,
+            config_file: PathBuf::from("settings.json"),
+        }
+    }
+
+    /// Makes a Rust function callable from scripts by name, as `name(a, b)` or
+    /// `a.name(b)`. Built in functions with the same name take priority. Returning
+    /// Err raises a runtime error that the script can catch.
+    pub fn register<F>(&mut self, name: &str, function: F)
+    where
+        F: FnMut(Vec<ValueType<'static>>) -> Result<ValueType<'static>, String> + 'static,
+    {
+        let function: HostFunction = Box::new(function);
+        match self.host_functions.iter_mut().find(|f| f.0 == name) {
+            Some(existing) => existing.1 = function,
+            None => self.host_functions.push((name.to_string(), function)),
+        }
+    }
+
+    /// Sets a global variable the next script run can use
+    pub fn set_global(&mut self, name: &str, value: impl Into<ValueType<'static>>) {
+        let value = value.into();
+        match self.globals.iter_mut().find(|g| g.0 == name) {
+            Some(existing) => existing.1 = value,
+            None => self.globals.push((name.to_string(), value)),
+        }
+    }
+
+    /// The value of a global variable, including ones set by the last script run.
+    /// Globals holding a function defined by the script aren't kept, as the function
+    /// only exists while that script runs.
+    pub fn global(&self, name: &str) -> Option<&ValueType<'static>> {
+        self.globals.iter().find(|g| g.0 == name).map(|g| &g.1)
+    }
+
+    /// The code passed to quit() if the last script run called it. Scripts can't end
+    /// the program embedding them, quit only stops the script.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// Runs a script, returning the value of the last expression (if any). Errors are
+    /// also printed to stderr. Imports are relative to the current directory.
+    pub fn run(&mut self, source: &str) -> Result<Option<ValueType<'static>>, String> {
+        self.exit_code = None;
+        // calling one would jump into code that isn't there any more
+        self.globals.retain(|(_, value)| !has_function(value));
+        let tokens = scanner::tokenize(source).map_err(|e| e.to_string())?;
+        let imports = imports::load_script_imports(&tokens, None)?;
+
+        let mut instructions = Vec::new();
+        let mut line_numbers = Vec::new();
+        let mut compiler = Compiler::new(&tokens, &mut instructions, &mut line_numbers);
+        compiler.source = source;
+        compiler.host_functions = self.host_functions.iter().map(|f| f.0.clone()).collect();
+        // globals from set_global come first, so their index is their position
+        for (name, _) in &self.globals {
+            compiler.variables.push(Variable::new(name.clone(), 0));
+        }
+        for import in &imports {
+            compiler.compile_import(&import.tokens, &import.name, &import.contents);
+        }
+        compiler.compile();
+        if compiler.in_error {
+            return Err(String::from("Compile Error"));
+        }
+        let variables = std::mem::take(&mut compiler.variables);
+        let host_names = std::mem::take(&mut compiler.host_functions);
+        let spans = std::mem::take(&mut compiler.spans);
+        let function_names = compiler
+            .functions
+            .iter()
+            .map(|f| (f.2, f.0.clone()))
+            .collect();
+        let sources = imports.iter().map(|i| i.contents.as_str());
+        let source_files = compiler
+            .files
+            .iter()
+            .zip(sources.chain(std::iter::once(source)))
+            .map(|((start, name), source)| SourceFile {
+                start: *start,
+                name: name.clone(),
+                lines: source.lines().collect(),
+            })
+            .collect();
+
+        let mut vm = Vm::new(&mut line_numbers);
+        vm.config_file = self.config_file.clone();
+        vm.max_call_depth = self.max_call_depth;
+        vm.sandbox = self.sandbox.clone();
+        vm.max_instructions = self.max_instructions;
+        vm.timeout = self.timeout;
+        vm.function_names = function_names;
+        vm.global_names = variables.iter().map(|v| v.name.clone()).collect();
+        vm.source_files = source_files;
+        vm.spans = spans;
+        vm.host_functions = self.host_functions.drain(..).map(|f| f.1).collect();
+        vm.globals = self
+            .globals
+            .iter()
+            .map(|(_, value)| Some(value.clone()))
+            .collect();
+
+        let ok = vm.run(&instructions);
+        self.exit_code = vm.exit_code;
+
+        self.host_functions = host_names
+            .into_iter()
+            .zip(std::mem::take(&mut vm.host_functions))
+            .collect();
+        self.globals = variables
+            .iter()
+            .enumerate()
+            .filter_map(|(index, variable)| {
+                let value = vm.globals.get(index)?.as_ref()?;
+                Some((variable.name.clone(), value.detach()))
+            })
+            .filter(|(_, value)| !has_function(value))
+            .collect();
+        if !ok {
+            let message = vm.error_message().unwrap_or("Runtime Error");
+            return Err(message.to_string());
+        }
+        Ok(vm.return_value.map(|v| v.detach()))
+    }
+}
+
+// true if the value is or contains a function compiled from a script
+fn has_function(value: &ValueType) -> bool {
+    match value {
+        ValueType::Func(..) | ValueType::Closure(..) => true,
+        ValueType::Array(a) => a.iter().any(has_function),
+        ValueType::Map(m) => m.values().any(has_function),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Engine, ValueType};
+
+    #[test]
+    fn test_host_functions() {
+        let mut engine = Engine::new();
+        let mut calls = 0;
+        engine.register("count", move |_| {
+            calls += 1;
+            Ok(ValueType::from(calls as f64))
+        });
+        engine.register("greet", |args| {
+            let name = String::try_from(args[0].clone())?;
+            Ok(ValueType::from(format!("hello {name}")))
+        });
+        engine.register("fail", |_| Err(String::from("host failed")));
+
+        let result = engine.run("count() + count()").unwrap().unwrap();
+        assert_eq!(f64::try_from(result), Ok(3.0));
+        // host functions can be passed around, and called as methods
+        let result = engine
+            .run("array(\"a\", \"b\").map(greet)[1] + \"b\".greet()")
+            .unwrap()
+            .unwrap();
+        assert_eq!(String::try_from(result), Ok(String::from("hello bhello b")));
+        let result = engine.run("try fail() catch e e[\"message\"] end");
+        assert_eq!(
+            String::try_from(result.unwrap().unwrap()),
+            Ok(String::from("host failed"))
+        );
+        assert_eq!(
+            engine.run("fail()").err(),
+            Some(String::from("host failed"))
+        );
+        assert_eq!(
+            engine.run("greet(1)").err(),
+            Some(String::from("Expected a string but got number"))
+        );
+    }
+
+    #[test]
+    fn test_globals() {
+        let mut engine = Engine::new();
+        engine.set_global("numbers", vec![3.0, 1.0]);
+        engine.set_global("n", 2.0);
+        let result = engine
+            .run("sorted = sort(numbers)\nn * 2")
+            .unwrap()
+            .unwrap();
+        assert_eq!(f64::try_from(result), Ok(4.0));
+        let sorted = engine.global("sorted").unwrap().clone();
+        assert_eq!(Vec::<f64>::try_from(sorted), Ok(vec![1.0, 3.0]));
+        assert!(engine.run("").unwrap().is_none());
+        assert_eq!(
+            engine.run("undefined()").err(),
+            Some(String::from("Compile Error"))
+        );
+        // f runs before x has a value
+        assert_eq!(
+            engine.run("x = f()\nfunction f() x end").err(),
+            Some(String::from("Global variable x does not exist."))
+        );
+    }
+
+    #[test]
+    fn test_functions_not_kept() {
+        let mut engine = Engine::new();
+        engine.register("twice", |args| Ok(args[0].clone()));
+        let result = engine.run(
+            "g = fn(x) x * 2 end
+             fs = array(1, g)
+             h = twice
+             n = g(3)",
+        );
+        assert_eq!(f64::try_from(result.unwrap().unwrap()), Ok(6.0));
+        assert!(engine.global("g").is_none());
+        assert!(engine.global("fs").is_none());
+        assert!(engine.global("h").is_some());
+        assert_eq!(f64::try_from(engine.global("n").unwrap().clone()), Ok(6.0));
+        assert_eq!(
+            engine.run("g(21)").err(),
+            Some(String::from("Compile Error"))
+        );
+        let result = engine.run("h(n) + 1").unwrap().unwrap();
+        assert_eq!(f64::try_from(result), Ok(7.0));
+    }
+
+    #[test]
+    fn test_quit() {
+        let mut engine = Engine::new();
+        assert!(engine.run("x = 1\nquit(4)\nx = 2").is_ok());
+        assert_eq!(engine.exit_code(), Some(4));
+        assert_eq!(f64::try_from(engine.global("x").unwrap().clone()), Ok(1.0));
+        assert!(engine.run("1").is_ok());
+        assert_eq!(engine.exit_code(), None);
+    }
+}
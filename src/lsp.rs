@@ -0,0 +1,612 @@
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+    panic,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    compiler::{Compiler, Definition},
+    load_script_imports,
+    scanner::{self, Span, TokenType},
+    vm::Vm,
+};
+
+// LSP constants
+const SEVERITY_ERROR: u32 = 1;
+const KIND_FUNCTION: u32 = 3;
+const KIND_VARIABLE: u32 = 6;
+const METHOD_NOT_FOUND: i32 = -32601;
+const TEXT_DOCUMENT_SYNC_FULL: u32 = 1;
+
+/// What's known about an open document from the last time it was compiled
+#[derive(Default)]
+struct Analysis {
+    diagnostics: Vec<Value>,
+    // each definition with the uri of the file it's in and its range there
+    definitions: Vec<(Definition, String, Value)>,
+    tokenized: bool,
+}
+
+struct Document {
+    text: String,
+    analysis: Analysis,
+}
+
+/// A language server for editors, see `vbas lsp`. Messages are passed to handle()
+/// one at a time and the messages to send back are returned.
+pub struct Server {
+    documents: HashMap<String, Document>,
+    shutdown: bool,
+    // set when the client sends exit
+    pub exit_code: Option<i32>,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Server {
+            documents: HashMap::new(),
+            shutdown: false,
+            exit_code: None,
+        }
+    }
+
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = match message["method"].as_str() {
+            Some(method) => method,
+            None => return Vec::new(), // a response to something we sent
+        };
+        let params = &message["params"];
+        let id = &message["id"];
+
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": TEXT_DOCUMENT_SYNC_FULL,
+                    "completionProvider": {},
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                },
+                "serverInfo": { "name": "vbas" },
+            }),
+            "shutdown" => {
+                self.shutdown = true;
+                Value::Null
+            }
+            "exit" => {
+                self.exit_code = Some(if self.shutdown { 0 } else { 1 });
+                return Vec::new();
+            }
+            "textDocument/didOpen" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                return self.update(uri, text.to_string());
+            }
+            "textDocument/didChange" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                // full sync, so the last change is the whole document
+                let text = match params["contentChanges"].as_array().and_then(|c| c.last()) {
+                    Some(change) => change["text"].as_str().unwrap_or_default(),
+                    None => return Vec::new(),
+                };
+                return self.update(uri, text.to_string());
+            }
+            "textDocument/didClose" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                self.documents.remove(uri);
+                return vec![publish_diagnostics(uri, Vec::new())];
+            }
+            "textDocument/completion" => self.completion(params),
+            "textDocument/hover" => self.hover(params),
+            "textDocument/definition" => self.definition(params),
+            _ => {
+                if id.is_null() {
+                    return Vec::new(); // notifications we don't need
+                }
+                return vec![json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": {
+                        "code": METHOD_NOT_FOUND,
+                        "message": format!("{method} is not supported"),
+                    },
+                })];
+            }
+        };
+        vec![json!({ "jsonrpc": "2.0", "id": id, "result": result })]
+    }
+
+    fn update(&mut self, uri: &str, text: String) -> Vec<Value> {
+        // a bug in the compiler shouldn't take the editor's language server down with it
+        let mut analysis =
+            panic::catch_unwind(|| analyse(uri, &text)).unwrap_or_else(|_| Analysis::default());
+        // keep the definitions from before when the document can't be tokenized,
+        // which is most of the time while typing a string
+        if !analysis.tokenized {
+            if let Some(document) = self.documents.remove(uri) {
+                analysis.definitions = document.analysis.definitions;
+            }
+        }
+        let diagnostics = analysis.diagnostics.clone();
+        self.documents
+            .insert(uri.to_string(), Document { text, analysis });
+        vec![publish_diagnostics(uri, diagnostics)]
+    }
+
+    // the document and the word at the position in a request
+    fn word_at(&self, params: &Value) -> Option<(&Document, String)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let document = self.documents.get(uri)?;
+        let line = params["position"]["line"].as_u64()? as usize;
+        let character = params["position"]["character"].as_u64()? as usize;
+        let word = word_at(document.text.lines().nth(line)?, character)?;
+        Some((document, word))
+    }
+
+    fn completion(&self, params: &Value) -> Value {
+        let mut items: Vec<Value> = Vec::new();
+        for native in Vm::NATIVES {
+            items.push(json!({
+                "label": native.1,
+                "kind": KIND_FUNCTION,
+                "detail": native_signature(native.1)
+            }));
+        }
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        if let Some(document) = self.documents.get(uri) {
+            for (definition, _, _) in &document.analysis.definitions {
+                if items.iter().any(|i| i["label"] == definition.name.as_str()) {
+                    continue;
+                }
+                let kind = match definition.params {
+                    Some(_) => KIND_FUNCTION,
+                    None => KIND_VARIABLE,
+                };
+                items.push(json!({
+                    "label": definition.name,
+                    "kind": kind,
+                    "detail": signature(definition)
+                }));
+            }
+        }
+        Value::Array(items)
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let (document, word) = match self.word_at(params) {
+            Some(found) => found,
+            None => return Value::Null,
+        };
+        let signature = match find_definition(document, &word) {
+            Some((definition, _, _)) => signature(definition),
+            None if Vm::NATIVES.iter().any(|n| n.1 == word) => native_signature(&word),
+            None => return Value::Null,
+        };
+        json!({
+            "contents": { "kind": "markdown", "value": format!("```\n{signature}\n```") }
+        })
+    }
+
+    fn definition(&self, params: &Value) -> Value {
+        let (document, word) = match self.word_at(params) {
+            Some(found) => found,
+            None => return Value::Null,
+        };
+        match find_definition(document, &word) {
+            Some((_, uri, range)) => json!({ "uri": uri, "range": range }),
+            None => Value::Null,
+        }
+    }
+}
+
+// a user function is found before a global with the same name
+fn find_definition<'d>(
+    document: &'d Document,
+    name: &str,
+) -> Option<&'d (Definition, String, Value)> {
+    let definitions = &document.analysis.definitions;
+    definitions
+        .iter()
+        .find(|(d, _, _)| d.name == name && d.params.is_some())
+        .or_else(|| definitions.iter().find(|(d, _, _)| d.name == name))
+}
+
+fn signature(definition: &Definition) -> String {
+    match &definition.params {
+        Some(params) => format!("function {}({})", definition.name, params.join(", ")),
+        None => format!("{} (global variable)", definition.name),
+    }
+}
+
+fn native_signature(name: &str) -> String {
+    match Vm::NATIVES.iter().find(|n| n.1 == name) {
+        Some((_, _, _, _, params)) => format!("{name}({params})"),
+        None => format!("{name}()"),
+    }
+}
+
+// the identifier at or just before a character in a line, which counts UTF-16 code
+// units like all LSP positions
+fn word_at(line: &str, character: usize) -> Option<String> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut units = 0;
+    let character = chars
+        .iter()
+        .take_while(|c| {
+            units += c.len_utf16();
+            units <= character
+        })
+        .count();
+    let is_word = |c: &char| c.is_ascii_alphanumeric() || *c == '_' || *c == '@';
+    let mut start = character.min(chars.len());
+    while start > 0 && is_word(&chars[start - 1]) {
+        start -= 1;
+    }
+    let end = start + chars[start..].iter().take_while(|c| is_word(c)).count();
+    if start == end || chars[start].is_numeric() {
+        return None;
+    }
+    Some(chars[start..end].iter().collect())
+}
+
+// Tokenizes and compiles a document and the files it imports
+fn analyse(uri: &str, text: &str) -> Analysis {
+    let mut analysis = Analysis::default();
+    let tokens = match scanner::tokenize(text) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            let range = line_range(text, e.line_number, e.span);
+            analysis.diagnostics.push(diagnostic(range, e.message));
+            return analysis;
+        }
+    };
+    analysis.tokenized = true;
+    let path = uri_to_path(uri);
+    let imports = match load_script_imports(&tokens, path.as_deref()) {
+        Ok(imports) => imports,
+        Err(msg) => {
+            // still compile the document, calls to imported functions will be errors too
+            let range = line_range(text, import_line(&tokens, &msg), Span::default());
+            analysis.diagnostics.push(diagnostic(range, &msg));
+            Vec::new()
+        }
+    };
+
+    let mut instructions = Vec::new();
+    let mut line_numbers = Vec::new();
+    let mut compiler = Compiler::new(&tokens, &mut instructions, &mut line_numbers);
+    compiler.print_errors = false;
+    compiler.source = text;
+    for import in &imports {
+        compiler.compile_import(&import.tokens, &import.name, &import.contents);
+    }
+    compiler.compile();
+
+    for error in &compiler.errors {
+        let diagnostic = match &error.file {
+            None => diagnostic(
+                line_range(text, error.line_number, error.span),
+                &error.message,
+            ),
+            // shown on the import, as the file isn't necessarily open
+            Some(name) => diagnostic(
+                line_range(text, import_line(&tokens, name), Span::default()),
+                &format!("{} on line {} of {name}", error.message, error.line_number),
+            ),
+        };
+        analysis.diagnostics.push(diagnostic);
+    }
+    for definition in &compiler.definitions {
+        let (uri, source) = match &definition.file {
+            Some(name) => match imports.iter().find(|i| &i.name == name) {
+                Some(import) => (path_to_uri(&import.path), import.contents.as_str()),
+                None => continue,
+            },
+            None => (uri.to_string(), text),
+        };
+        let range = token_range(source, definition.line_number, definition.span);
+        analysis.definitions.push((definition.clone(), uri, range));
+    }
+    analysis
+}
+
+// the line of the import that a message mentions, or the first line
+fn import_line(tokens: &[TokenType], message: &str) -> u32 {
+    for pair in tokens.windows(2) {
+        if let [TokenType::Import(_), TokenType::String(t)] = pair {
+            if message.contains(t.lexeme.as_str()) {
+                return t.line_number;
+            }
+        }
+    }
+    1
+}
+
+fn diagnostic(range: Value, message: &str) -> Value {
+    json!({
+        "range": range,
+        "severity": SEVERITY_ERROR,
+        "source": "vbas",
+        "message": message,
+    })
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Value>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    })
+}
+
+// LSP lines and characters count from 0, line numbers and columns from 1. Columns
+// count bytes and characters count UTF-16 code units, so they differ after anything
+// that isn't ASCII.
+fn token_range(text: &str, line_number: u32, span: Span) -> Value {
+    let line = line_number.saturating_sub(1);
+    let line_start = span
+        .start
+        .saturating_sub(span.column.saturating_sub(1) as usize);
+    let units = |from: usize, to: usize| text.get(from..to).map_or(to - from, utf16_len);
+    let start = units(line_start, span.start);
+    let end = start + units(span.start, span.end.max(span.start)).max(1);
+    json!({
+        "start": { "line": line, "character": start },
+        "end": { "line": line, "character": end },
+    })
+}
+
+// the range of a span, or of the whole line when there's no column
+fn line_range(text: &str, line_number: u32, span: Span) -> Value {
+    if span.column > 0 {
+        return token_range(text, line_number, span);
+    }
+    let line = line_number.saturating_sub(1);
+    let length = text.lines().nth(line as usize).map_or(0, utf16_len);
+    json!({
+        "start": { "line": line, "character": 0 },
+        "end": { "line": line, "character": length },
+    })
+}
+
+fn utf16_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Some(PathBuf::from(String::from_utf8_lossy(&decoded).to_string()))
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    uri
+}
+
+// reads one message, None at the end of the input
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = match length {
+        Some(length) => length,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "missing Content-Length",
+            ))
+        }
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    output.flush()
+}
+
+/// Runs the language server over stdin and stdout, returning the exit code
+pub fn run() -> i32 {
+    let mut server = Server::new();
+    let mut input = io::stdin().lock();
+    let mut output = io::stdout().lock();
+    loop {
+        let message = match read_message(&mut input) {
+            Ok(Some(message)) => message,
+            Ok(None) => return 1, // the client went away without sending exit
+            Err(e) => {
+                eprintln!("vbas lsp: {e}");
+                return 1;
+            }
+        };
+        for reply in server.handle(&message) {
+            if let Err(e) = write_message(&mut output, &reply) {
+                eprintln!("vbas lsp: {e}");
+                return 1;
+            }
+        }
+        if let Some(code) = server.exit_code {
+            return code;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_message, uri_to_path, word_at, Server};
+    use serde_json::{json, Value};
+    use std::path::PathBuf;
+
+    const URI: &str = "file:///tmp/vbas%20lsp/test.vbas";
+
+    fn open(server: &mut Server, text: &str) -> Vec<Value> {
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": URI, "languageId": "vbas", "version": 1, "text": text } },
+        }))
+    }
+
+    fn request(server: &mut Server, method: &str, line: u32, character: u32) -> Value {
+        let replies = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": {
+                "textDocument": { "uri": URI },
+                "position": { "line": line, "character": character },
+            },
+        }));
+        replies[0]["result"].clone()
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let mut server = Server::new();
+        let replies = open(&mut server, "x = 1\ny = len()\nz = (1 +\n");
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0]["method"], "textDocument/publishDiagnostics");
+        let diagnostics = replies[0]["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(
+            diagnostics[0]["message"],
+            "len() takes 1 argument but was given 0"
+        );
+        assert_eq!(
+            diagnostics[0]["range"],
+            json!({ "start": { "line": 1, "character": 4 }, "end": { "line": 1, "character": 7 } })
+        );
+
+        let replies = open(&mut server, "print(\"abc)\n");
+        let diagnostics = replies[0]["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0]["range"]["start"]["line"], 0);
+
+        // é is 2 bytes and 1 UTF-16 unit, 😀 is 4 bytes and 2 units
+        let replies = open(&mut server, "s = \"é😀\" + len()\n");
+        let diagnostics = replies[0]["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(
+            diagnostics[0]["range"],
+            json!({ "start": { "line": 0, "character": 12 }, "end": { "line": 0, "character": 15 } })
+        );
+    }
+
+    #[test]
+    fn test_completion_hover_and_definition() {
+        let mut server = Server::new();
+        open(
+            &mut server,
+            "total = 0\nfunction add(a, b)\n  a + b\nend\ntotal = add(total, 2)\n",
+        );
+
+        let items = request(&mut server, "textDocument/completion", 4, 0);
+        let labels: Vec<&str> = items
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|i| i["label"].as_str().unwrap())
+            .collect();
+        assert!(labels.contains(&"print"));
+        assert!(labels.contains(&"add"));
+        assert!(labels.contains(&"total"));
+
+        let hover = request(&mut server, "textDocument/hover", 4, 10);
+        assert_eq!(hover["contents"]["value"], "```\nfunction add(a, b)\n```");
+        let hover = request(&mut server, "textDocument/hover", 2, 2);
+        assert_eq!(hover, Value::Null);
+
+        let location = request(&mut server, "textDocument/definition", 4, 9);
+        assert_eq!(location["uri"], URI);
+        assert_eq!(
+            location["range"]["start"],
+            json!({ "line": 1, "character": 9 })
+        );
+        let location = request(&mut server, "textDocument/definition", 4, 14);
+        assert_eq!(
+            location["range"]["start"],
+            json!({ "line": 0, "character": 0 })
+        );
+
+        open(&mut server, "print(len(\"a\"))\n");
+        let hover = request(&mut server, "textDocument/hover", 0, 7);
+        assert_eq!(hover["contents"]["value"], "```\nlen(value)\n```");
+
+        open(&mut server, "s = \"😀\" t = 2\nt\n");
+        let location = request(&mut server, "textDocument/definition", 1, 0);
+        assert_eq!(
+            location["range"],
+            json!({ "start": { "line": 0, "character": 9 }, "end": { "line": 0, "character": 10 } })
+        );
+    }
+
+    #[test]
+    fn test_messages() {
+        let mut server = Server::new();
+        let replies = server.handle(&json!({ "jsonrpc": "2.0", "id": 7, "method": "unknown" }));
+        assert_eq!(replies[0]["error"]["code"], -32601);
+        server.handle(&json!({ "jsonrpc": "2.0", "id": 8, "method": "shutdown" }));
+        server.handle(&json!({ "jsonrpc": "2.0", "method": "exit" }));
+        assert_eq!(server.exit_code, Some(0));
+
+        let mut input = "Content-Length: 17\r\n\r\n{\"jsonrpc\":\"2.0\"}".as_bytes();
+        assert_eq!(
+            read_message(&mut input).unwrap(),
+            Some(json!({ "jsonrpc": "2.0" }))
+        );
+        assert_eq!(read_message(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn test_words_and_uris() {
+        assert_eq!(word_at("x = len(y)", 5), Some(String::from("len")));
+        assert_eq!(word_at("x = len(y)", 7), Some(String::from("len")));
+        assert_eq!(word_at("x = 12", 5), None);
+        assert_eq!(word_at("", 3), None);
+        assert_eq!(word_at("\"😀\" + len(x)", 10), Some(String::from("len")));
+        assert_eq!(
+            uri_to_path("file:///tmp/a%20b.vbas"),
+            Some(PathBuf::from("/tmp/a b.vbas"))
+        );
+        assert_eq!(uri_to_path("untitled:1"), None);
+    }
+}
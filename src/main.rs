@@ -1,130 +1,498 @@
-mod common;
-mod compiler;
-mod scanner;
-mod vm;
+mod lsp;
+mod repl;
+mod test_runner;
 use colored::Colorize;
-use std::{fs, io, path::PathBuf, process};
-use vm::DebugSettings;
-
-use crate::{compiler::Compiler, vm::Vm};
-use clap::Parser;
+use std::{
+    fs,
+    num::ParseFloatError,
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
+use vbas::{
+    bytecode::{self, Program},
+    compiler::{self, Compiler},
+    diagnostics, formatter,
+    imports::{load_script_imports, Import},
+    optimizer, scanner,
+    vm::{self, DebugSettings, Sandbox, SourceFile, Vm},
+};
+
+use clap::{Parser, Subcommand};
 
 /// Very Basic - A Basic interpreted programming language
 #[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    /// Script to run, either source (.vbas) or compiled with build (.vbc)
     path: Option<std::path::PathBuf>,
 
     /// Output the compiled bytecode to console
     #[arg(short, long)]
     compile: bool,
 
+    /// Optimize the compiled code, with --compile shows the code before and after
+    #[arg(short = 'O', long)]
+    optimize: bool,
+
     /// Set breakpoints to debug code, lines numbers separated by commas
     #[arg(short, long)]
     breakpoints: Option<String>,
+
+    /// Maximum depth of nested function calls before a stack overflow error
+    #[arg(long, default_value_t = vm::DEFAULT_MAX_CALL_DEPTH)]
+    max_call_depth: usize,
+
+    /// Stop the script after running this many instructions
+    #[arg(long, value_name = "COUNT")]
+    max_instructions: Option<u64>,
+
+    /// Stop the script after running for this many seconds
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    timeout: Option<Duration>,
+
+    /// Stop scripts running system commands, using settings or reading and writing
+    /// files, except for what is allowed with the --allow options
+    #[arg(long)]
+    sandbox: bool,
+
+    /// Allow system commands (@command) when sandboxed
+    #[arg(long)]
+    allow_system: bool,
+
+    /// Allow setting_get and setting_set when sandboxed
+    #[arg(long)]
+    allow_settings: bool,
+
+    /// Allow reading files in a directory when sandboxed, can be used more than once
+    #[arg(long, value_name = "DIR")]
+    allow_read: Vec<PathBuf>,
+
+    /// Allow writing files in a directory when sandboxed, can be used more than once
+    #[arg(long, value_name = "DIR")]
+    allow_write: Vec<PathBuf>,
     args_to_script: Vec<String>,
 }
 
+impl Cli {
+    // any --allow option turns the sandbox on, as everything is allowed without it
+    fn sandbox(&self) -> Sandbox {
+        let sandboxed = self.sandbox
+            || self.allow_system
+            || self.allow_settings
+            || !self.allow_read.is_empty()
+            || !self.allow_write.is_empty();
+        if !sandboxed {
+            return Sandbox::allow_all();
+        }
+        Sandbox {
+            allow_all: false,
+            allow_system: self.allow_system,
+            allow_settings: self.allow_settings,
+            read_dirs: self.allow_read.clone(),
+            write_dirs: self.allow_write.clone(),
+        }
+    }
+}
+
+// a number of seconds that fits in a Duration, so not negative, infinite or NaN
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    let seconds: f64 = value.parse().map_err(|e: ParseFloatError| e.to_string())?;
+    Duration::try_from_secs_f64(seconds).map_err(|e| e.to_string())
+}
+
+#[derive(Debug, Subcommand)]
+enum Commands {
+    /// Compile a script to bytecode that can be run later without the source
+    Build {
+        path: PathBuf,
+
+        /// File to write, defaults to the script name with a .vbc extension
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Optimize the compiled code
+        #[arg(short = 'O', long)]
+        optimize: bool,
+    },
+    /// Run a language server over stdin and stdout, for editors
+    Lsp,
+    /// Format scripts with the standard indentation and keywords
+    Fmt {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// List the scripts that aren't formatted instead of changing them, and exit with 1 if there are any
+        #[arg(long)]
+        check: bool,
+    },
+    /// Run the test_ functions in the *_test.vbas files in a directory
+    Test {
+        /// Directory to look in (and the directories under it), or one test file
+        #[arg(default_value = ".")]
+        path: PathBuf,
+    },
+}
+
+/// Settings from the command line that control how a script is run
+struct RunOptions {
+    breakpoints: Option<String>,
+    max_call_depth: usize,
+    // imports are relative to the script, or the current directory if there isn't one
+    script_path: Option<PathBuf>,
+    sandbox: Sandbox,
+    max_instructions: Option<u64>,
+    timeout: Option<Duration>,
+    optimize: bool,
+}
+
+// exit code when a script is stopped by --max-instructions or --timeout, the same
+// as the timeout command uses
+const EXIT_LIMIT_EXCEEDED: i32 = 124;
+
+/// A script that ran to the end, or was stopped by quit()
+#[derive(Debug, PartialEq)]
+struct Finished {
+    value: String, // the value of the last expression, formatted with {:?}
+    exit_code: i32,
+}
+
+fn exit_code(error: &str) -> i32 {
+    if error == "Limit Exceeded" {
+        EXIT_LIMIT_EXCEEDED
+    } else {
+        1
+    }
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions {
+            breakpoints: None,
+            max_call_depth: vm::DEFAULT_MAX_CALL_DEPTH,
+            script_path: None,
+            sandbox: Sandbox::allow_all(),
+            max_instructions: None,
+            timeout: None,
+            optimize: false,
+        }
+    }
+}
+
 fn main() {
     let args = Cli::parse();
+    let sandbox = args.sandbox();
+
+    if let Some(Commands::Build {
+        path,
+        output,
+        optimize,
+    }) = args.command
+    {
+        let output = output.unwrap_or_else(|| path.with_extension("vbc"));
+        if build(&path, &output, optimize).is_err() {
+            process::exit(1);
+        }
+    } else if let Some(Commands::Lsp) = args.command {
+        process::exit(lsp::run());
+    } else if let Some(Commands::Fmt { paths, check }) = args.command {
+        process::exit(fmt(&paths, check));
+    } else if let Some(Commands::Test { path }) = args.command {
+        process::exit(test_runner::run(&path));
+    } else if let Some(file_path) = args.path {
+        let mut config_file = file_path.clone();
 
-    if let Some(file_path) = args.path {
-        let contents =
-            fs::read_to_string(file_path.clone()).expect("Should have been able to read the file");
+        config_file.set_extension("vbas.json");
 
-        let mut config_file = file_path;
+        let options = RunOptions {
+            sandbox,
+            breakpoints: args.breakpoints,
+            max_call_depth: args.max_call_depth,
+            script_path: Some(file_path.clone()),
+            max_instructions: args.max_instructions,
+            timeout: args.timeout,
+            optimize: args.optimize,
+        };
+
+        if file_path.extension().is_some_and(|e| e == "vbc") {
+            let bytes = match fs::read(&file_path) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    eprintln!(
+                        "Could not read {}: {}",
+                        file_path.display(),
+                        e.to_string().red()
+                    );
+                    process::exit(1);
+                }
+            };
+            match interpret_bytecode(&bytes, config_file, &options) {
+                Ok(finished) => process::exit(finished.exit_code),
+                Err(e) => process::exit(exit_code(&e)),
+            }
+        }
 
-        config_file.set_extension("vbas.json");
+        let contents = match fs::read_to_string(&file_path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                eprintln!(
+                    "Could not read {}: {}",
+                    file_path.display(),
+                    e.to_string().red()
+                );
+                process::exit(1);
+            }
+        };
 
         if args.compile {
-            compile(&contents);
-        } else if let Result::Err(_) = interpret(&contents, config_file, args.breakpoints) {
-            process::exit(1);
+            compile(&contents, &options);
+        } else {
+            match interpret(&contents, config_file, &options) {
+                Ok(finished) => process::exit(finished.exit_code),
+                Err(e) => process::exit(exit_code(&e)),
+            }
         }
     } else {
-        println!("{}", "Very Basic Version 0.2".yellow());
-        loop {
-            let mut line = String::new();
-            io::stdin()
-                .read_line(&mut line)
-                .expect("Failed to read line");
-            let result = interpret(&line, PathBuf::from("settings.json"), None);
-            match result {
-                Ok(s) => println!("{}", s.bright_black()),
-                Err(_) => println!(""),
-            }
+        repl::run(args.max_call_depth, sandbox);
+    }
+}
+
+// Tokenizes and compiles a script and the files it imports. Errors have already
+// been printed when this returns Err.
+fn compile_script(contents: &str, options: &RunOptions) -> Result<(Program, Vec<Import>), String> {
+    let tokens = match scanner::tokenize(contents) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            diagnostics::print_scan_error(&e, contents);
+            return Result::Err(String::from("Tokenize Error"));
         }
+    };
+    let imports = match load_script_imports(&tokens, options.script_path.as_deref()) {
+        Ok(imports) => imports,
+        Err(msg) => {
+            eprintln!("Import error: {}", msg.red());
+            return Result::Err(String::from("Import Error"));
+        }
+    };
+    let mut instructions: Vec<compiler::OpCode> = Vec::new();
+    let mut line_numbers: Vec<u32> = Vec::new();
+    let mut compiler = Compiler::new(&tokens, &mut instructions, &mut line_numbers);
+    compiler.source = contents;
+    for import in &imports {
+        compiler.compile_import(&import.tokens, &import.name, &import.contents);
+    }
+    compiler.compile();
+    if compiler.in_error {
+        return Result::Err(String::from("Compile Error"));
+    }
+    let function_names = compiler
+        .functions
+        .iter()
+        .map(|f| (f.2, f.0.clone()))
+        .collect();
+    let global_names = compiler.variables.iter().map(|v| v.name.clone()).collect();
+    let files = std::mem::take(&mut compiler.files);
+    let spans = std::mem::take(&mut compiler.spans);
+
+    let program = Program {
+        instructions,
+        line_numbers,
+        function_names,
+        global_names,
+        files,
+        spans,
+    };
+    Result::Ok((program, imports))
+}
+
+fn run_program(
+    program: &mut Program,
+    source_files: Vec<SourceFile>,
+    config_file: PathBuf,
+    options: &RunOptions,
+) -> Result<Finished, String> {
+    let mut vm = match &options.breakpoints {
+        Some(break_points) => {
+            let test = DebugSettings::new(10, break_points.as_str());
+            Vm::new_debug(&mut program.line_numbers, test)
+        }
+        None => Vm::new(&mut program.line_numbers),
+    };
+
+    vm.config_file = config_file;
+    vm.source_files = source_files;
+    vm.spans = std::mem::take(&mut program.spans);
+    vm.function_names = program.function_names.iter().cloned().collect();
+    vm.global_names = std::mem::take(&mut program.global_names);
+    vm.max_call_depth = options.max_call_depth;
+    vm.sandbox = options.sandbox.clone();
+    vm.max_instructions = options.max_instructions;
+    vm.timeout = options.timeout;
+    let result = vm.run(&program.instructions);
+    if !result {
+        if vm.limit_exceeded() {
+            return Result::Err(String::from("Limit Exceeded"));
+        }
+        return Result::Err(String::from("Runtime Error"));
     }
+
+    let value = match vm.return_value {
+        Some(val) => format!("{:?}", val),
+        None => String::new(),
+    };
+    Result::Ok(Finished {
+        value,
+        exit_code: vm.exit_code.unwrap_or(0),
+    })
 }
 
 fn interpret(
     contents: &str,
     config_file: PathBuf,
-    breakpoints: Option<String>,
-) -> Result<String, String> {
-    let tokens = crate::scanner::tokenize(&contents);
-
-    match tokens {
-        Ok(tokens) => {
-            let mut instructions: Vec<compiler::OpCode> = Vec::new();
-            let mut line_numbers: Vec<u32> = Vec::new();
-            let mut compiler = Compiler::new(&tokens, &mut instructions, &mut line_numbers);
-            compiler.compile();
-            if compiler.in_error {
-                return Result::Err(String::from("Compile Error"));
-            }
+    options: &RunOptions,
+) -> Result<Finished, String> {
+    let (mut program, imports) = compile_script(contents, options)?;
+    if options.optimize {
+        optimizer::optimize(&mut program);
+    }
 
-            let source_lines: Vec<&str> = contents.lines().collect();
+    let source_files = source_files(&program, &imports, contents);
+    run_program(&mut program, source_files, config_file, options)
+}
 
-            //let mut vm = Vm::new(&mut line_numbers);
-            let mut vm = match breakpoints {
-                Some(break_points) => {
-                    let test = DebugSettings::new(10, break_points.as_str());
-                    Vm::new_debug(&mut line_numbers, &source_lines, test)
-                }
-                None => Vm::new(&mut line_numbers),
-            };
+// the source of the script and its imports, for showing errors
+fn source_files<'a>(
+    program: &Program,
+    imports: &'a [Import],
+    contents: &'a str,
+) -> Vec<SourceFile<'a>> {
+    // program.files lists the imports in order, then the main script
+    let sources = imports.iter().map(|i| i.contents.as_str());
+    program
+        .files
+        .iter()
+        .zip(sources.chain(std::iter::once(contents)))
+        .map(|((start, name), source)| SourceFile {
+            start: *start,
+            name: name.clone(),
+            lines: source.lines().collect(),
+        })
+        .collect()
+}
 
-            //let mut vm = Vm::new_debug(&mut line_numbers, &source_lines, test);
+// runs a script compiled with `vbas build`. The source isn't available, so errors
+// only show line numbers.
+fn interpret_bytecode(
+    bytes: &[u8],
+    config_file: PathBuf,
+    options: &RunOptions,
+) -> Result<Finished, String> {
+    let mut program = match bytecode::read(bytes) {
+        Ok(program) => program,
+        Err(msg) => {
+            eprintln!("Bytecode error: {}", msg.red());
+            return Result::Err(String::from("Bytecode Error"));
+        }
+    };
+    let source_files = program
+        .files
+        .iter()
+        .map(|(start, name)| SourceFile {
+            start: *start,
+            name: name.clone(),
+            lines: Vec::new(),
+        })
+        .collect();
+
+    run_program(&mut program, source_files, config_file, options)
+}
 
-            //dbg!(&instructions);
+fn build(path: &Path, output: &Path, optimize: bool) -> Result<(), String> {
+    let contents = fs::read_to_string(path).map_err(|e| {
+        eprintln!("Could not read {}: {}", path.display(), e.to_string().red());
+        String::from("Build Error")
+    })?;
+    let options = RunOptions {
+        script_path: Some(path.to_path_buf()),
+        ..Default::default()
+    };
+    let (mut program, _) = compile_script(&contents, &options)?;
+    if optimize {
+        optimizer::optimize(&mut program);
+    }
+    if let Err(e) = fs::write(output, bytecode::write(&program)) {
+        eprintln!(
+            "Could not write {}: {}",
+            output.display(),
+            e.to_string().red()
+        );
+        return Result::Err(String::from("Build Error"));
+    }
+    Result::Ok(())
+}
 
-            vm.config_file = config_file;
-            let result = vm.run(&instructions);
-            if !result {
-                return Result::Err(String::from("Runtime Error"));
+// Formats each script in place, or with check lists the ones that need formatting.
+// Returns the exit code.
+fn fmt(paths: &[PathBuf], check: bool) -> i32 {
+    let mut exit_code = 0;
+    for path in paths {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                eprintln!("Could not read {}: {}", path.display(), e.to_string().red());
+                exit_code = 1;
+                continue;
             }
-
-            if let Some(val) = vm.return_value {
-                Result::Ok(format!("{:?}", val))
-            } else {
-                Result::Ok(String::new())
+        };
+        let formatted = match formatter::format(&contents) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                eprintln!(
+                    "Format error: {}, line {} of {}",
+                    e.message.red(),
+                    e.line_number,
+                    path.display()
+                );
+                if let Some(excerpt) = diagnostics::source_excerpt(&contents, e.line_number, e.span)
+                {
+                    eprintln!("{excerpt}");
+                }
+                exit_code = 1;
+                continue;
             }
+        };
+        if formatted == contents {
+            continue;
         }
-        Err(msg) => {
-            eprintln!("Tokenize Error: {}", msg.red());
-            Result::Err(String::from("Tokenize Error"))
+        if check {
+            println!("{} is not formatted", path.display());
+            exit_code = 1;
+        } else if let Err(e) = fs::write(path, formatted) {
+            eprintln!(
+                "Could not write {}: {}",
+                path.display(),
+                e.to_string().red()
+            );
+            exit_code = 1;
         }
     }
+    exit_code
 }
 
-fn compile(contents: &str) {
-    let tokens = crate::scanner::tokenize(&contents);
-
-    match tokens {
-        Ok(tokens) => {
-            let mut instructions: Vec<compiler::OpCode> = Vec::new();
-            let mut line_numbers: Vec<u32> = Vec::new();
-            let mut compiler = Compiler::new(&tokens, &mut instructions, &mut line_numbers);
-            compiler.compile();
-            if compiler.in_error {
-                return;
-            }
-
-            compiler::print_instr(instructions);
-        }
-        Err(msg) => {
-            eprintln!("{}", msg.red());
+fn compile(contents: &str, options: &RunOptions) {
+    if let Ok((mut program, _)) = compile_script(contents, options) {
+        if !options.optimize {
+            compiler::print_instr(&program.instructions);
+            return;
         }
+        println!("Before -O:");
+        compiler::print_instr(&program.instructions);
+        optimizer::optimize(&mut program);
+        println!("\nAfter -O:");
+        compiler::print_instr(&program.instructions);
     }
 }
 // *****************************************************
@@ -133,18 +501,70 @@ fn compile(contents: &str) {
 
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
-
-    use crate::interpret;
-
+    use std::{fs, path::PathBuf, time::Duration};
+
+    use crate::{
+        build,
+        bytecode::{self, Program},
+        compile_script,
+        compiler::{OpCode, VarType},
+        interpret, interpret_bytecode, scanner, source_files, Cli, Compiler, RunOptions, Sandbox,
+        Vm,
+    };
+    use clap::{error::ErrorKind, Parser};
+
+    // runs the script with and without -O, which must give the same result
     fn interpret_test(contents: &str) -> String {
-        let result = interpret(contents, PathBuf::from("settings_test.json"), None);
+        let run = |options: &RunOptions| match interpret(
+            contents,
+            PathBuf::from("settings_test.json"),
+            options,
+        ) {
+            Ok(finished) => finished.value,
+            Err(s) => s,
+        };
+        let result = run(&RunOptions::default());
+        let optimized = run(&RunOptions {
+            optimize: true,
+            ..Default::default()
+        });
+        assert_eq!(result, optimized, "the result is different with -O");
+        result
+    }
+
+    // writes the files to a new temp directory and runs the first one
+    fn interpret_files(dir: &str, files: &[(&str, &str)]) -> String {
+        let dir = std::env::temp_dir().join(dir);
+        for (name, contents) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        let options = RunOptions {
+            script_path: Some(dir.join(files[0].0)),
+            ..Default::default()
+        };
+        let result = interpret(files[0].1, PathBuf::from("settings_test.json"), &options);
         match result {
-            Ok(s) => s,
+            Ok(finished) => finished.value,
             Err(s) => s,
         }
     }
 
+    // runs a script that has a runtime error and returns the traceback
+    fn traceback_test(contents: &str, options: &RunOptions) -> Vec<String> {
+        colored::control::set_override(false);
+        let (mut program, imports) = compile_script(contents, options).unwrap();
+        let source_files = source_files(&program, &imports, contents);
+        let mut vm = Vm::new(&mut program.line_numbers);
+        vm.source_files = source_files;
+        vm.function_names = program.function_names.iter().cloned().collect();
+        vm.global_names = std::mem::take(&mut program.global_names);
+        vm.max_call_depth = options.max_call_depth;
+        assert!(!vm.run(&program.instructions), "the script should fail");
+        vm.traceback()
+    }
+
     #[test]
     fn global_var_same_as_fn_name() {
         let code = "len = get_bet()
@@ -224,6 +644,85 @@ mod tests {
         assert_eq!(result, "Boolean(true)");
     }
 
+    #[test]
+    fn array_copies_are_separate() {
+        let code = "a = array(1, 2)
+                    b = a
+                    b[0] = 5
+                    a.push(3)
+                    function change(c)
+                        c[1] = 6
+                        c.push(7)
+                        c
+                    end
+                    array(a, b, change(b), b)";
+        let result = interpret_test(code);
+        assert_eq!(
+            result,
+            "Array([Array([Number(1.0), Number(2.0), Number(3.0)]), \
+             Array([Number(5.0), Number(2.0)]), \
+             Array([Number(5.0), Number(6.0), Number(7.0)]), \
+             Array([Number(5.0), Number(2.0)])])"
+        );
+
+        let code = "if true then
+                        a = array(1, 2)
+                        b = a
+                        a[0] = 5
+                        b.push(3)
+                        array(a, b)
+                    end";
+        let result = interpret_test(code);
+        assert_eq!(
+            result,
+            "Array([Array([Number(5.0), Number(2.0)]), \
+             Array([Number(1.0), Number(2.0), Number(3.0)])])"
+        );
+    }
+
+    #[test]
+    fn subscript_set_value() {
+        // the value of a[i] = x is the array as it was before
+        let code = "function f(x) x[1] = 100 end
+                    f(array(1, 2, 3))";
+        assert_eq!(
+            interpret_test(code),
+            "Array([Number(1.0), Number(2.0), Number(3.0)])"
+        );
+        let code = "a = array(1, 2, 3)
+                    b = (a[0] = 7)
+                    array(a, b)";
+        assert_eq!(
+            interpret_test(code),
+            "Array([Array([Number(7.0), Number(2.0), Number(3.0)]), \
+             Array([Number(1.0), Number(2.0), Number(3.0)])])"
+        );
+    }
+
+    #[test]
+    fn return_value_of_earlier_call() {
+        // g has no value of its own, so it returns the last value, which f gave
+        let code = "function f() 5 end
+                    function g()
+                        if f() == 5 then
+                        end
+                    end
+                    g()";
+        assert_eq!(interpret_test(code), "Number(5.0)");
+    }
+
+    #[test]
+    fn return_without_value() {
+        // nothing has given a value when f returns
+        let code = "function f()
+                        if false then
+                            1
+                        end
+                    end
+                    print(f())";
+        assert_eq!(interpret_test(code), "Runtime Error");
+    }
+
     #[test]
     fn array_slice() {
         let code = "array(1,2,3,4,5,6).slice(1,3)
@@ -245,6 +744,41 @@ mod tests {
         let code = "@dummy_system_call_will_fail()";
         let result = interpret_test(code);
         assert_eq!(result, "Runtime Error");
+        assert_eq!(
+            traceback_test(code, &RunOptions::default()),
+            vec!["  in <script>, line 1: @dummy_system_call_will_fail()"]
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn system_call_exit_code() {
+        let code = "@echo(\"hi\")";
+        assert_eq!(interpret_test(code), "String(\"hi\\n\")");
+        let code = "try
+                        @sh(\"-c\", \"echo oops >&2; exit 3\")
+                    catch e
+                        e[\"message\"]
+                    end";
+        assert_eq!(
+            interpret_test(code),
+            "String(\"System command sh failed with exit code 3: oops\")"
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn exec() {
+        let code = "r = exec(\"sh\", array(\"-c\", \"cat; echo err >&2; exit 2\"), \"input\")
+                    r[\"stdout\"] + \",\" + r[\"stderr\"] + \",\" + str(r[\"code\"])";
+        assert_eq!(interpret_test(code), "String(\"input,err\\n,2\")");
+        let code = "exec(\"echo\")[\"code\"]";
+        assert_eq!(interpret_test(code), "Number(0.0)");
+        let code = "try exec(\"dummy_command_will_fail\") catch e left(e[\"message\"], 36) end";
+        assert_eq!(
+            interpret_test(code),
+            "String(\"Failed to run system command dummy_c\")"
+        );
     }
 
     #[test]
@@ -688,7 +1222,72 @@ mod tests {
 
         test(1)
         ";
+        assert_eq!(interpret_test(code), "Compile Error");
+        // calls through a variable can only be checked when they run
+        let code = "
+        function test(a,b,c)
+            a+b+c
+        end
+
+        f = test
+        f(1)
+        ";
         assert_eq!(interpret_test(code), "Runtime Error");
+        assert_eq!(
+            traceback_test(code, &RunOptions::default()),
+            vec!["  in <script>, line 7: f(1)"]
+        );
+    }
+
+    #[test]
+    fn arity_checked_when_compiling() {
+        let errors = |code: &str| {
+            let tokens = scanner::tokenize(code).unwrap();
+            let mut instructions = Vec::new();
+            let mut line_numbers = Vec::new();
+            let mut compiler = Compiler::new(&tokens, &mut instructions, &mut line_numbers);
+            compiler.compile();
+            compiler
+                .errors
+                .iter()
+                .map(|e| e.message.clone())
+                .collect::<Vec<String>>()
+        };
+        // forward references are checked once the function is defined
+        let code = "add(1)
+                    n = 1
+                    x = n.add()
+                    function add(a, b)
+                        a + b
+                    end
+                    y = add(1, 2) + n.add(3)";
+        assert_eq!(
+            errors(code),
+            vec![
+                "add() takes 2 arguments but was given 1",
+                "add() takes 2 arguments but was given 1",
+            ]
+        );
+        let code = "len()
+                    mid(\"abc\", 1, 2, 3)
+                    \"abc\".left()
+                    filter(array(1))
+                    a = array()
+                    a.push(1, 2)
+                    print(\"a\", true, \"red\")
+                    str(1.5, \"0.0\")
+                    a.push(1)
+                    array()";
+        assert_eq!(
+            errors(code),
+            vec![
+                "len() takes 1 argument but was given 0",
+                "mid() takes 2 to 3 arguments but was given 4",
+                "left() takes 2 arguments but was given 1",
+                "filter() takes at least 2 arguments but was given 1",
+                "push() takes 1 argument but was given 2",
+            ]
+        );
     }
 
     #[test]
@@ -938,6 +1537,128 @@ mod tests {
     fn stack_overflow() {
         let code = "function stack_overflow(x) stack_overflow(x+1) end stack_overflow(1)";
         assert_eq!(interpret_test(code), "Runtime Error");
+        let options = RunOptions {
+            max_call_depth: 50,
+            ..Default::default()
+        };
+        assert_eq!(
+            traceback_test(code, &options),
+            vec![
+                "  in stack_overflow, line 1: function stack_overflow(x) stack_overflow(x+1) end stack_overflow(1)",
+                "    ... repeated 49 more times",
+                "  in <script>, line 1: function stack_overflow(x) stack_overflow(x+1) end stack_overflow(1)",
+            ]
+        );
+    }
+
+    #[test]
+    fn stack_overflow_in_callback() {
+        // each call from reduce runs on the Rust stack, which would overflow first
+        let function = "function r(n)
+                            if n == 0 then
+                                0
+                            else
+                                reduce(array(1), fn(acc, x) r(n - 1) + 1 end, 0)
+                            end
+                        end";
+        assert_eq!(
+            interpret_test(&format!("{function}\nr(50)")),
+            "Number(50.0)"
+        );
+        assert_eq!(
+            interpret_test(&format!("{function}\nr(1000)")),
+            "Runtime Error"
+        );
+        assert_eq!(
+            interpret_test(&format!(
+                "{function}\ntry r(1000) catch e e[\"message\"] end"
+            )),
+            "String(\"stack overflow in function <lambda>, too many calls from inside map, filter etc.\")"
+        );
+    }
+
+    #[test]
+    fn deep_recursion() {
+        let code = "
+        function count(n)
+            if n == 0 then 0 exit end
+            1 + count(n - 1)
+        end
+        count(5000)";
+        assert_eq!(interpret_test(code), "Number(5000.0)");
+    }
+
+    #[test]
+    fn max_call_depth() {
+        let code = "function down(n) down(n+1) end down(1)";
+        let options = RunOptions {
+            max_call_depth: 50,
+            ..Default::default()
+        };
+        let result = interpret(code, PathBuf::from("settings_test.json"), &options);
+        assert_eq!(result, Err(String::from("Runtime Error")));
+    }
+
+    #[test]
+    fn runtime_error_in_nested_call() {
+        let code = "
+        function inner(a)
+            a[5]
+        end
+        function outer(a)
+            x = 1
+            a.inner()
+        end
+        outer(array(1, 2))";
+        assert_eq!(interpret_test(code), "Runtime Error");
+        assert_eq!(
+            traceback_test(code, &RunOptions::default()),
+            vec![
+                "  in inner, line 3: a[5]",
+                "  in outer, line 7: a.inner()",
+                "  in <script>, line 9: outer(array(1, 2))",
+            ]
+        );
+    }
+
+    #[test]
+    fn traceback_collapses_recursion() {
+        let code = "function down(n)
+                        if n == 0 then
+                            array()[1]
+                        end
+                        down(n - 1)
+                    end
+                    down(10)";
+        assert_eq!(
+            traceback_test(code, &RunOptions::default()),
+            vec![
+                "  in down, line 3: array()[1]",
+                "  in down, line 5: down(n - 1)",
+                "    ... repeated 9 more times",
+                "  in <script>, line 7: down(10)",
+            ]
+        );
+    }
+
+    #[test]
+    fn traceback_in_imported_file() {
+        let dir = std::env::temp_dir().join("vbas_import_traceback");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.vbas"), "function first(a)\n    a[0]\nend").unwrap();
+        let code = "import \"lib.vbas\"\nfirst(array())";
+        fs::write(dir.join("main.vbas"), code).unwrap();
+        let options = RunOptions {
+            script_path: Some(dir.join("main.vbas")),
+            ..Default::default()
+        };
+        assert_eq!(
+            traceback_test(code, &options),
+            vec![
+                "  in first, line 2 of lib.vbas: a[0]",
+                "  in <script>, line 2: first(array())",
+            ]
+        );
     }
 
     #[test]
@@ -1209,4 +1930,705 @@ mod tests {
         let code = "replace(\"old\",\"old\",\"new\")";
         assert_eq!(interpret_test(code), "String(\"new\")");
     }
+
+    #[test]
+    fn try_catch() {
+        let code = "
+        function risky(a)
+            x = 1
+            a[10]
+        end
+        result = 0
+        try
+            y = 2
+            risky(array(1, 2))
+            result = 1
+        catch err
+            result = err[\"message\"] + \" \" + str(err[\"line\"])
+        end
+        result";
+        assert_eq!(interpret_test(code), "String(\"Subscript out of range 4\")");
+    }
+
+    #[test]
+    fn try_catch_no_error() {
+        let code = "
+        function safe(x)
+            try
+                if x > 1 then throw(\"too big\") end
+                x
+            catch
+                -1
+            end
+        end
+        safe(1) + safe(5) * 10";
+        assert_eq!(interpret_test(code), "Number(-9.0)");
+    }
+
+    #[test]
+    fn throw_uncaught() {
+        assert_eq!(interpret_test("throw(\"oops\")"), "Runtime Error");
+        let code = "try throw(\"a {1+1}\") catch e e[\"message\"] end";
+        assert_eq!(interpret_test(code), "String(\"a 2\")");
+    }
+
+    #[test]
+    fn assert_natives() {
+        assert_eq!(interpret_test("assert(1 < 2)"), "Boolean(true)");
+        assert_eq!(interpret_test("assert(1 > 2)"), "Runtime Error");
+        assert_eq!(interpret_test("assert(1)"), "Runtime Error");
+        let code = "try assert(false, \"x is {1+1}\") catch e e[\"message\"] end";
+        assert_eq!(interpret_test(code), "String(\"assertion failed: x is 2\")");
+        assert_eq!(
+            interpret_test("assert_eq(array(1, \"a\"), array(1, \"a\"))"),
+            "Boolean(true)"
+        );
+        let code = "try assert_eq(\"1\", 1) catch e e[\"message\"] end";
+        assert_eq!(
+            interpret_test(code),
+            "String(\"assertion failed: \\\"1\\\" is not equal to 1\")"
+        );
+    }
+
+    #[test]
+    fn try_without_catch() {
+        assert_eq!(interpret_test("try 1 end"), "Compile Error");
+    }
+
+    #[test]
+    fn break_and_continue() {
+        let code = "
+        total = 0
+        for i = 1 to 10
+            if i == 3 then continue end
+            for j = 1 to 10
+                x = j
+                if j > 2 then break end
+                total = total + j
+            next
+            if i == 6 then break end
+        next
+        total";
+        assert_eq!(interpret_test(code), "Number(15.0)");
+        let code = "
+        n = 0
+        while n < 10
+            n = n + 1
+            try
+                if n < 5 then continue end
+                break
+            catch
+            end
+        end
+        n";
+        assert_eq!(interpret_test(code), "Number(5.0)");
+    }
+
+    #[test]
+    fn break_outside_loop() {
+        assert_eq!(interpret_test("break"), "Compile Error");
+        assert_eq!(interpret_test("if true then continue end"), "Compile Error");
+    }
+
+    #[test]
+    fn for_zero_iterations() {
+        let code = "
+        function f()
+            a = 1
+            for i = 1 to 0
+                x = 1
+            next
+            a
+        end
+        f()";
+        assert_eq!(interpret_test(code), "Number(1.0)");
+    }
+
+    #[test]
+    fn lambda() {
+        assert_eq!(interpret_test("f = fn(x) x * 2 end f(4)"), "Number(8.0)");
+        assert_eq!(interpret_test("f = fn() 1 end f(4)"), "Runtime Error");
+        assert_eq!(interpret_test("f = fn(x) x"), "Compile Error");
+        let code = "
+        compose = fn(f, g) fn(x) f(g(x)) end end
+        inc = fn(x) x + 1 end
+        compose(inc, inc)(1)";
+        assert_eq!(interpret_test(code), "Number(3.0)");
+    }
+
+    #[test]
+    fn closure_captures_by_value() {
+        let code = "
+        function make()
+            a = 1
+            b = 2
+            f = fn(x)
+                g = fn(y) y + a + b end
+                g(x) * 10
+            end
+            a = 100
+            f
+        end
+        h = make()
+        h(3)";
+        assert_eq!(interpret_test(code), "Number(60.0)");
+        let code = "
+        fns = array()
+        for i = 1 to 3
+            fns.push(fn() i * i end)
+        next
+        fns[2]()";
+        assert_eq!(interpret_test(code), "Number(9.0)");
+    }
+
+    #[test]
+    fn higher_order_functions() {
+        let code = "a = array(5, 3, 9, 1)
+        array(map(a, fn(x) x * 2 end), reduce(a, fn(t, x) t + x end, 0), sortby(a, fn(x, y) y - x end))";
+        assert_eq!(
+            interpret_test(code),
+            "Array([Array([Number(10.0), Number(6.0), Number(18.0), Number(2.0)]), Number(18.0), Array([Number(9.0), Number(5.0), Number(3.0), Number(1.0)])])"
+        );
+        let code = "a = array(5, 3, 9, 1)
+        array(any(a, fn(x) x > 8 end), all(a, fn(x) x > 8 end))";
+        assert_eq!(
+            interpret_test(code),
+            "Array([Boolean(true), Boolean(false)])"
+        );
+        assert_eq!(
+            interpret_test("map(array(1), fn(x, y) x end)"),
+            "Runtime Error"
+        );
+        assert_eq!(
+            interpret_test("any(array(1), fn(x) x end)"),
+            "Runtime Error"
+        );
+    }
+
+    #[test]
+    fn filter_with_function() {
+        let code = "
+        function big(x)
+            x > 4
+        end
+        a = array(5, 3, 9, 1)
+        array(filter(a, big), a.filter(\">\", 4), a.filter(fn(x) x < 4 end))";
+        assert_eq!(
+            interpret_test(code),
+            "Array([Array([Number(5.0), Number(9.0)]), Array([Number(5.0), Number(9.0)]), Array([Number(3.0), Number(1.0)])])"
+        );
+    }
+
+    #[test]
+    fn callback_captures_and_errors() {
+        let code = "
+        function scale(arr, k)
+            map(arr, fn(x) x * k end)
+        end
+        scale(array(1, 2), 3)";
+        assert_eq!(interpret_test(code), "Array([Number(3.0), Number(6.0)])");
+        let code = "
+        try
+            map(array(1, 2), fn(x) throw(\"boom {x}\") end)
+        catch e
+            e[\"message\"]
+        end";
+        assert_eq!(interpret_test(code), "String(\"boom 1\")");
+    }
+
+    #[test]
+    fn import_functions() {
+        let files = [
+            (
+                "main.vbas",
+                "import \"lib/helpers.vbas\"
+                import \"lib/helpers.vbas\"
+                double(greeting)",
+            ),
+            (
+                "lib/helpers.vbas",
+                "import \"math.vbas\"
+                greeting = 21
+                function double(x)
+                    times(x, 2)
+                end",
+            ),
+            ("lib/math.vbas", "function times(a, b) a * b end"),
+        ];
+        assert_eq!(interpret_files("vbas_import", &files), "Number(42.0)");
+    }
+
+    #[test]
+    fn import_errors() {
+        let files = [
+            ("main.vbas", "import \"a.vbas\" 1"),
+            ("a.vbas", "import \"b.vbas\""),
+            ("b.vbas", "import \"main.vbas\""),
+        ];
+        assert_eq!(interpret_files("vbas_import_cycle", &files), "Import Error");
+        let files = [("main.vbas", "import \"missing.vbas\" 1")];
+        assert_eq!(
+            interpret_files("vbas_import_missing", &files),
+            "Import Error"
+        );
+        let files = [
+            ("main.vbas", "import \"a.vbas\" 1"),
+            ("a.vbas", "function f() 1 + end"),
+        ];
+        assert_eq!(
+            interpret_files("vbas_import_compile", &files),
+            "Compile Error"
+        );
+        let files = [
+            ("main.vbas", "function f() import \"a.vbas\" end"),
+            ("a.vbas", "1"),
+        ];
+        assert_eq!(
+            interpret_files("vbas_import_nested", &files),
+            "Compile Error"
+        );
+    }
+
+    #[test]
+    fn map_literal() {
+        let code = "m = {\"name\": \"Boardwalk\", \"price\": 400}
+                    m[\"price\"]";
+        assert_eq!(interpret_test(code), "Number(400.0)");
+        assert_eq!(interpret_test("{}"), "Map({})");
+        assert_eq!(interpret_test("{1: 2}"), "Runtime Error");
+        assert_eq!(interpret_test("{\"a\": 1}[\"b\"]"), "Runtime Error");
+        assert_eq!(interpret_test("{\"a\": 1,}"), "Map({\"a\": Number(1.0)})");
+        assert_eq!(interpret_test("{\"a\": 1 \"b\": 2}"), "Compile Error");
+        assert_eq!(interpret_test("{,,\"a\": 1}"), "Compile Error");
+        assert_eq!(interpret_test("{\"a\": 1,, \"b\": 2}"), "Compile Error");
+    }
+
+    #[test]
+    fn map_to_string() {
+        let code = r#"m = {"name": "Boardwalk", "price": 400, "sold": false}
+                      m["rents"] = array(2, 10)
+                      m["group"] = {"colour": "blue"}
+                      str(m)"#;
+        assert_eq!(
+            interpret_test(code),
+            r#"String("{\"group\": {\"colour\": \"blue\"}, \"name\": \"Boardwalk\", \"price\": 400, \"rents\": [2, 10], \"sold\": false}")"#
+        );
+    }
+
+    #[test]
+    fn map_multi_line() {
+        let code = "m = {
+                        \"a\": array(1, 2),
+                        \"b\": {\"c\": 3}
+                    }
+                    m[\"b\"][\"c\"] + len(m)";
+        assert_eq!(interpret_test(code), "Number(5.0)");
+    }
+
+    #[test]
+    fn map_set_is_copy() {
+        let code = "a = {\"x\": 1}
+                    b = a
+                    b[\"x\"] = 2
+                    b[\"y\"] = 3
+                    a";
+        assert_eq!(interpret_test(code), "Map({\"x\": Number(1.0)})");
+    }
+
+    #[test]
+    fn map_functions() {
+        let code = "m = {\"b\": 2, \"a\": 1}
+                    keys(m)";
+        assert_eq!(
+            interpret_test(code),
+            "Array([String(\"a\"), String(\"b\")])"
+        );
+        let code = "m = {\"b\": 2, \"a\": 1}
+                    m.values()";
+        assert_eq!(interpret_test(code), "Array([Number(1.0), Number(2.0)])");
+        let code = "m = {\"b\": 2, \"a\": 1}
+                    m.haskey(\"a\") and not haskey(remove(m, \"a\"), \"a\")";
+        assert_eq!(interpret_test(code), "Boolean(true)");
+        let code = "m = {\"b\": 2, \"a\": 1}
+                    m.remove(\"a\")
+                    m";
+        assert_eq!(interpret_test(code), "Map({\"b\": Number(2.0)})");
+    }
+
+    #[test]
+    fn map_equality() {
+        let code = "{\"a\": 1, \"b\": array(1,2)} == {\"b\": array(1,2), \"a\": 1}";
+        assert_eq!(interpret_test(code), "Boolean(true)");
+        let code = "{\"a\": 1} <> {\"a\": 2}";
+        assert_eq!(interpret_test(code), "Boolean(true)");
+    }
+
+    #[test]
+    fn colon_outside_map_is_whitespace() {
+        let code = "x = 1: y = {\"x\": x}: y[\"x\"]";
+        assert_eq!(interpret_test(code), "Number(1.0)");
+    }
+
+    #[test]
+    fn run_compiled_bytecode() {
+        let code = "function fact(n)
+                        if n <= 1 then 1 else n * fact(n - 1) end
+                    end
+                    a = array(3, 1, 2).sortby(fn(x, y) x - y end)
+                    try
+                        throw(\"oops\")
+                    catch
+                        a[0] + fact(5)
+                    end";
+        let (program, _) = compile_script(code, &RunOptions::default()).unwrap();
+        let bytes = bytecode::write(&program);
+        let result = interpret_bytecode(
+            &bytes,
+            PathBuf::from("settings_test.json"),
+            &RunOptions::default(),
+        );
+        assert_eq!(result.map(|f| f.value), Ok(String::from("Number(121.0)")));
+
+        let result = interpret_bytecode(
+            b"VBC\x00",
+            PathBuf::from("settings_test.json"),
+            &RunOptions::default(),
+        );
+        assert_eq!(result, Err(String::from("Bytecode Error")));
+    }
+
+    #[test]
+    fn build_missing_file() {
+        let dir = std::env::temp_dir().join("vbas_build_missing");
+        let result = build(&dir.join("missing.vbas"), &dir.join("missing.vbc"), false);
+        assert_eq!(result, Err(String::from("Build Error")));
+    }
+
+    #[test]
+    fn global_used_before_set() {
+        // f runs before x has been given its first value
+        let code = "x = f()
+                    function f() x end";
+        assert_eq!(interpret_test(code), "Runtime Error");
+
+        // the compiler reads the variable before changing it, so these can only come
+        // from bytecode
+        let changes = [
+            vec![OpCode::SubscriptSet(VarType::Global(1))],
+            vec![OpCode::CallNativeMut(0, 1, VarType::Global(1)), OpCode::Pop],
+        ];
+        for change in changes {
+            let mut instructions = vec![
+                OpCode::ConstantNum(0.0),
+                OpCode::ConstantNum(1.0),
+                OpCode::SetGlobal(0),
+            ];
+            instructions.extend(change);
+            let program = Program {
+                line_numbers: vec![1; instructions.len()],
+                instructions,
+                function_names: Vec::new(),
+                global_names: vec![String::from("x"), String::from("y")],
+                files: vec![(0, None)],
+                spans: Vec::new(),
+            };
+            let result = interpret_bytecode(
+                &bytecode::write(&program),
+                PathBuf::from("settings_test.json"),
+                &RunOptions::default(),
+            );
+            assert_eq!(result, Err(String::from("Runtime Error")));
+        }
+    }
+
+    #[test]
+    fn sandbox() {
+        let dir = std::env::temp_dir().join("vbas_sandbox");
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("out.txt");
+        let code = format!(
+            "try
+                write(\"{}\", \"hello\")
+                readlines(\"{}\")[0]
+            catch e
+                e[\"message\"]
+            end",
+            file.display(),
+            file.display()
+        );
+        let run = |sandbox: Sandbox| {
+            let options = RunOptions {
+                sandbox,
+                ..Default::default()
+            };
+            interpret(&code, PathBuf::from("settings_test.json"), &options).map(|f| f.value)
+        };
+
+        let mut sandbox = Sandbox::deny_all();
+        assert_eq!(
+            run(sandbox.clone()),
+            Ok(format!(
+                "String(\"Permission denied: writing {} is not allowed (use --allow-write=<dir>)\")",
+                file.display()
+            ))
+        );
+        sandbox.write_dirs.push(dir.clone());
+        assert_eq!(
+            run(sandbox.clone()),
+            Ok(format!(
+                "String(\"Permission denied: reading {} is not allowed (use --allow-read=<dir>)\")",
+                file.display()
+            ))
+        );
+        sandbox.read_dirs.push(dir.clone());
+        assert_eq!(run(sandbox), Ok(String::from("String(\"hello\")")));
+
+        let options = RunOptions {
+            sandbox: Sandbox::deny_all(),
+            ..Default::default()
+        };
+        let result = interpret(
+            "@echo(\"hi\")",
+            PathBuf::from("settings_test.json"),
+            &options,
+        );
+        assert_eq!(result, Err(String::from("Runtime Error")));
+        let result = interpret(
+            "setting_get(\"a\")",
+            PathBuf::from("settings_test.json"),
+            &options,
+        );
+        assert_eq!(result, Err(String::from("Runtime Error")));
+    }
+
+    #[test]
+    fn sandbox_dir() {
+        let dir = std::env::temp_dir().join("vbas_sandbox_dir");
+        fs::create_dir_all(dir.join("allowed/sub")).unwrap();
+        fs::write(dir.join("allowed/sub/inside.txt"), "").unwrap();
+        fs::write(dir.join("outside.txt"), "").unwrap();
+        let mut sandbox = Sandbox::deny_all();
+        sandbox.read_dirs.push(dir.join("allowed"));
+        let options = RunOptions {
+            sandbox,
+            ..Default::default()
+        };
+        let run = |pattern: &str| {
+            let code = format!("len(dir(\"{}\"))", dir.join(pattern).display());
+            interpret(&code, PathBuf::from("settings_test.json"), &options).map(|f| f.value)
+        };
+        assert_eq!(run("allowed/*/*.txt"), Ok(String::from("Number(1.0)")));
+        // the pattern starts inside the allowed directory but climbs out of it
+        assert_eq!(
+            run("allowed/*/../../*.txt"),
+            Ok(String::from("Number(0.0)"))
+        );
+        assert_eq!(run("*.txt"), Err(String::from("Runtime Error")));
+    }
+
+    #[test]
+    fn instruction_and_time_limits() {
+        let code = "x = 0
+                    while true
+                        try
+                            x = x + 1
+                        catch
+                        end
+                    end";
+        let options = RunOptions {
+            max_instructions: Some(10000),
+            ..Default::default()
+        };
+        let result = interpret(code, PathBuf::from("settings_test.json"), &options);
+        assert_eq!(result, Err(String::from("Limit Exceeded")));
+
+        let options = RunOptions {
+            timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let result = interpret(code, PathBuf::from("settings_test.json"), &options);
+        assert_eq!(result, Err(String::from("Limit Exceeded")));
+
+        // the limit also applies inside callbacks
+        let options = RunOptions {
+            max_instructions: Some(1000),
+            ..Default::default()
+        };
+        let code = "dim(1000).map(fn(x) x + 1 end)";
+        let result = interpret(code, PathBuf::from("settings_test.json"), &options);
+        assert_eq!(result, Err(String::from("Limit Exceeded")));
+        let result = interpret("1 + 2", PathBuf::from("settings_test.json"), &options);
+        assert_eq!(result.map(|f| f.value), Ok(String::from("Number(3.0)")));
+    }
+
+    #[test]
+    fn timeout_option() {
+        let timeout = |seconds: &str| {
+            let option = format!("--timeout={seconds}");
+            Cli::try_parse_from(["vbas", option.as_str(), "script.vbas"])
+                .map(|args| args.timeout)
+                .map_err(|e| e.kind())
+        };
+        assert_eq!(timeout("0.5"), Ok(Some(Duration::from_millis(500))));
+        assert_eq!(timeout("0"), Ok(Some(Duration::ZERO)));
+        // these don't fit in a Duration
+        for seconds in ["inf", "nan", "1e300", "-1", "soon"] {
+            assert_eq!(timeout(seconds), Err(ErrorKind::ValueValidation));
+        }
+    }
+
+    #[test]
+    fn quit() {
+        let run = |code: &str| {
+            interpret(
+                code,
+                PathBuf::from("settings_test.json"),
+                &RunOptions::default(),
+            )
+        };
+        let code = "function check(n)
+                        if n > 2 then quit(n) end
+                        n
+                    end
+                    x = 1
+                    try
+                        array(1, 2, 3, 4).map(check)
+                    catch
+                        x = 2
+                    end
+                    x = 3";
+        let finished = run(code).unwrap();
+        assert_eq!(finished.exit_code, 3);
+        assert_ne!(finished.value, "Number(3.0)");
+        let finished = run("print(\"done\")\nquit()\nprint(\"not printed\")").unwrap();
+        assert_eq!(finished.exit_code, 0);
+        assert_eq!(run("1 + 1").unwrap().exit_code, 0);
+        assert_eq!(run("quit(\"a\")"), Err(String::from("Runtime Error")));
+    }
+
+    // the message and line of each compile error, in the order they're found
+    fn compile_errors(code: &str) -> Vec<(String, u32)> {
+        let tokens = scanner::tokenize(code).unwrap();
+        let mut instructions = Vec::new();
+        let mut line_numbers = Vec::new();
+        let mut compiler = Compiler::new(&tokens, &mut instructions, &mut line_numbers);
+        compiler.print_errors = false;
+        compiler.compile();
+        compiler
+            .errors
+            .iter()
+            .map(|e| (e.message.clone(), e.line_number))
+            .collect()
+    }
+
+    #[test]
+    fn multiple_compile_errors() {
+        let code = "x = (1 + 2
+                    function f(a)
+                        if a > 1 then
+                            print(]
+                        end
+                        a
+                    end
+                    for i = 1 to 3
+                        print(i +)
+                    next
+                    y = missing(f(x))
+                    print(\"ok\")";
+        assert_eq!(
+            compile_errors(code),
+            vec![
+                (String::from("Expected )"), 1),
+                (String::from("Unexpected statement ']'"), 4),
+                (String::from("Unexpected statement ')'"), 9),
+                (String::from("function missing not found"), 11),
+            ]
+        );
+        assert_eq!(interpret_test(code), "Compile Error");
+
+        // a function that fails to compile doesn't affect the ones after it
+        let code = "function f(1)
+                    end
+                    function g()
+                        1
+                    end";
+        assert_eq!(
+            compile_errors(code),
+            vec![(String::from("Function parameter expected"), 1)]
+        );
+    }
+
+    #[test]
+    fn one_error_for_each_mistake() {
+        let cases = [
+            // the end after 'x = 1 +' still finishes f, so g isn't inside it
+            (
+                "function f(a)\n x = 1 +\nend\nfunction g(a) a end\ng(1,2)",
+                vec![
+                    ("Unexpected statement 'end'", 3),
+                    ("g() takes 1 argument but was given 2", 5),
+                ],
+            ),
+            ("z[0] = 1", vec![("variable not found", 1)]),
+            // the block is still parsed after a mistake in its first line
+            (
+                "y = 1\nif y > then\n print(y)\nend\nprint(y)",
+                vec![("Unexpected statement 'then'", 2)],
+            ),
+            ("if y = 1\n print(2)\nend", vec![("If without then", 1)]),
+            (
+                "for = 1 to 3\n print(1)\nnext\nprint(2)",
+                vec![("Invalid use of 'for' statement", 1)],
+            ),
+            (
+                "try\n print(1)\nend\nprint(2)",
+                vec![("try without catch", 1)],
+            ),
+            (
+                "function f(1)\n 2\nend\nf(3)",
+                vec![("Function parameter expected", 1)],
+            ),
+            ("function f(a\n a\nend\nf(1)", vec![("Expected )", 1)]),
+            (
+                "function f(a)\n function g(b)\n  b\n end\n a\nend\nf(1)",
+                vec![("Can't define a function within a function", 2)],
+            ),
+            (
+                "y = match 3\n when then 1\n when 3 4\n  2\n else\n  3\nend\nprint(y)",
+                vec![
+                    ("Unexpected statement 'then'", 2),
+                    ("'then' missing in match", 3),
+                ],
+            ),
+            (
+                "f = fn(1)\n 2\nend\nprint(f(1))",
+                vec![("Function parameter expected", 1)],
+            ),
+            // the functions after one missing its end aren't inside it
+            (
+                "function f(a)\n a\nfunction g(b)\n b\nend\nprint(g(1))\nprint(f(2))",
+                vec![("Function without end", 1)],
+            ),
+            (
+                "function f(val[ue)\n 1\nend\nf(1)",
+                vec![("Function parameter expected", 1)],
+            ),
+            (
+                "for i = 1 to 3\n print(i)\n if next\nprint(1)",
+                vec![("Unexpected statement 'next'", 3)],
+            ),
+            // outside a for loop a next doesn't end the function
+            (
+                "function f()\n next\n 1\nend\nprint(f())",
+                vec![("Unexpected statement 'next'", 2)],
+            ),
+        ];
+        for (code, expected) in cases {
+            let expected: Vec<(String, u32)> = expected
+                .into_iter()
+                .map(|(message, line)| (message.to_string(), line))
+                .collect();
+            assert_eq!(compile_errors(code), expected, "{code}");
+        }
+    }
 }
@@ -0,0 +1,375 @@
+//! Makes compiled code smaller and faster, for `-O`.
+//!
+//! Runs over the instructions between compiling and running a script, without
+//! changing what the script does:
+//!
+//! - constant expressions like `60 * 60 * 24` or `"a" + "b"` are worked out once here
+//!   instead of every time they run
+//! - jumps to the next instruction, and code after a `Return` or `Jump` that nothing
+//!   jumps to, are removed
+//! - adding a number to a local variable (e.g. the counter of a for loop) becomes one
+//!   `AddLocal`
+//!
+//! Instructions are only merged when nothing jumps into the middle of them, so every
+//! jump, function pointer and line number can be moved to where its instruction ends up.
+
+use crate::bytecode::Program;
+use crate::compiler::OpCode;
+
+/// Optimizes a program in place. The passes are repeated until none of them change
+/// anything, as one can make room for another (`1 + 2 * 3` is folded in two goes).
+pub fn optimize(program: &mut Program) {
+    loop {
+        let mut changed = fold_constants(program);
+        changed |= remove_jumps(program);
+        changed |= remove_dead_code(program);
+        changed |= add_locals(program);
+        if !changed {
+            break;
+        }
+    }
+}
+
+// where the jump at index goes, the offsets are from the instruction after it
+fn jump_target(index: usize, op: &OpCode) -> Option<usize> {
+    match op {
+        OpCode::Jump(to_jump) => Some((index as i64 + *to_jump as i64 + 1) as usize),
+        OpCode::JumpIfFalse(to_jump) | OpCode::Try(to_jump) => Some(index + to_jump + 1),
+        _ => None,
+    }
+}
+
+// the instructions that can run without coming from the one before them: the ones
+// jumped to, the start of each function and the start of the program
+fn entry_points(program: &Program) -> Vec<bool> {
+    let mut entry_points = vec![false; program.instructions.len() + 1];
+    entry_points[0] = true;
+    for (index, op) in program.instructions.iter().enumerate() {
+        let target = match op {
+            OpCode::Func(start, _) | OpCode::Closure(start, _, _) | OpCode::Invoke(start, _) => {
+                Some(*start)
+            }
+            _ => jump_target(index, op),
+        };
+        if let Some(target) = target {
+            entry_points[target] = true;
+        }
+    }
+    for (start, _) in &program.function_names {
+        entry_points[*start] = true;
+    }
+    entry_points
+}
+
+// Takes out the instructions marked as removed, then points the jumps, functions and
+// files at where their instructions are now. Returns false if nothing was removed.
+fn remove(program: &mut Program, removed: &[bool]) -> bool {
+    if !removed.contains(&true) {
+        return false;
+    }
+    // where each instruction ends up, or the one after it if it's removed
+    let mut new_index = Vec::with_capacity(removed.len() + 1);
+    let mut count = 0;
+    for is_removed in removed {
+        new_index.push(count);
+        if !is_removed {
+            count += 1;
+        }
+    }
+    new_index.push(count);
+
+    let instructions = std::mem::take(&mut program.instructions);
+    for (index, mut op) in instructions.into_iter().enumerate() {
+        if removed[index] {
+            continue;
+        }
+        if let Some(target) = jump_target(index, &op) {
+            let (from, to) = (new_index[index], new_index[target]);
+            match &mut op {
+                OpCode::Jump(to_jump) => *to_jump = to as i32 - from as i32 - 1,
+                OpCode::JumpIfFalse(to_jump) | OpCode::Try(to_jump) => *to_jump = to - from - 1,
+                _ => (),
+            }
+        }
+        if let OpCode::Func(start, _) | OpCode::Closure(start, _, _) | OpCode::Invoke(start, _) =
+            &mut op
+        {
+            *start = new_index[*start];
+        }
+        program.instructions.push(op);
+    }
+    retain(&mut program.line_numbers, removed);
+    retain(&mut program.spans, removed);
+    for (start, _) in &mut program.function_names {
+        *start = new_index[*start];
+    }
+    for (start, _) in &mut program.files {
+        *start = new_index[*start];
+    }
+    true
+}
+
+fn retain<T>(values: &mut Vec<T>, removed: &[bool]) {
+    let mut index = 0;
+    values.retain(|_| {
+        index += 1;
+        !removed.get(index - 1).copied().unwrap_or(false)
+    });
+}
+
+enum Constant<'c> {
+    Number(f64),
+    Str(&'c str),
+    Bool(bool),
+}
+
+fn constant(op: &OpCode) -> Option<Constant<'_>> {
+    match op {
+        OpCode::ConstantNum(n) => Some(Constant::Number(*n)),
+        OpCode::ConstantStr(s) | OpCode::ConstantString(s) => Some(Constant::Str(s)),
+        OpCode::ConstantBool(b) => Some(Constant::Bool(*b)),
+        _ => None,
+    }
+}
+
+// The value of a op b, if the vm would always get the same answer without an error.
+// Types the vm can't add or compare are left for it to report when the code runs.
+fn fold_binary(a: &OpCode, b: &OpCode, op: &OpCode) -> Option<OpCode> {
+    let folded = match (constant(a)?, constant(b)?) {
+        (Constant::Number(a), Constant::Number(b)) => match op {
+            OpCode::Add => OpCode::ConstantNum(a + b),
+            OpCode::Subtract => OpCode::ConstantNum(a - b),
+            OpCode::Multiply => OpCode::ConstantNum(a * b),
+            OpCode::Divide => OpCode::ConstantNum(a / b),
+            OpCode::Mod => OpCode::ConstantNum(a % b),
+            OpCode::Pow => OpCode::ConstantNum(a.powf(b)),
+            OpCode::And => OpCode::ConstantNum((a as i64 & b as i64) as f64),
+            OpCode::Or => OpCode::ConstantNum((a as i64 | b as i64) as f64),
+            OpCode::GreaterThan => OpCode::ConstantBool(a > b),
+            OpCode::GreaterThanEq => OpCode::ConstantBool(a >= b),
+            OpCode::LessThan => OpCode::ConstantBool(a < b),
+            OpCode::LessThanEq => OpCode::ConstantBool(a <= b),
+            OpCode::Equal => OpCode::ConstantBool(a == b),
+            OpCode::NotEqual => OpCode::ConstantBool(a != b),
+            _ => return None,
+        },
+        (Constant::Str(a), Constant::Str(b)) => match op {
+            OpCode::Add => OpCode::ConstantString(format!("{a}{b}")),
+            OpCode::GreaterThan => OpCode::ConstantBool(a > b),
+            OpCode::GreaterThanEq => OpCode::ConstantBool(a >= b),
+            OpCode::LessThan => OpCode::ConstantBool(a < b),
+            OpCode::LessThanEq => OpCode::ConstantBool(a <= b),
+            OpCode::Equal => OpCode::ConstantBool(a == b),
+            OpCode::NotEqual => OpCode::ConstantBool(a != b),
+            _ => return None,
+        },
+        (Constant::Bool(a), Constant::Bool(b)) => match op {
+            OpCode::And => OpCode::ConstantBool(a && b),
+            OpCode::Or => OpCode::ConstantBool(a || b),
+            OpCode::Equal => OpCode::ConstantBool(a == b),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(folded)
+}
+
+fn fold_unary(a: &OpCode, op: &OpCode) -> Option<OpCode> {
+    let folded = match (constant(a)?, op) {
+        (Constant::Number(n), OpCode::Negate) => OpCode::ConstantNum(-n),
+        (Constant::Number(n), OpCode::Not) => OpCode::ConstantBool(n == 0.0),
+        (Constant::Bool(b), OpCode::Not) => OpCode::ConstantBool(!b),
+        (Constant::Str(s), OpCode::Not) => OpCode::ConstantBool(s.is_empty()),
+        _ => return None,
+    };
+    Some(folded)
+}
+
+// Replaces an operator and the constants it works on with the result. The operands
+// are always the instructions just before the operator, unless something jumps in
+// between them.
+fn fold_constants(program: &mut Program) -> bool {
+    let entry_points = entry_points(program);
+    let code = &mut program.instructions;
+    let mut removed = vec![false; code.len()];
+    let mut index = 0;
+    while index < code.len() {
+        let mut folded = None;
+        if index + 2 < code.len() && !entry_points[index + 1] && !entry_points[index + 2] {
+            folded =
+                fold_binary(&code[index], &code[index + 1], &code[index + 2]).map(|op| (op, 3));
+        }
+        if folded.is_none() && index + 1 < code.len() && !entry_points[index + 1] {
+            folded = fold_unary(&code[index], &code[index + 1]).map(|op| (op, 2));
+        }
+        if let Some((op, len)) = folded {
+            code[index] = op;
+            removed[index + 1..index + len].fill(true);
+            index += len;
+        } else {
+            index += 1;
+        }
+    }
+    remove(program, &removed)
+}
+
+// Jump(0) goes to the next instruction, an if without an else has one
+fn remove_jumps(program: &mut Program) -> bool {
+    let removed: Vec<bool> = program
+        .instructions
+        .iter()
+        .map(|op| matches!(op, OpCode::Jump(0)))
+        .collect();
+    remove(program, &removed)
+}
+
+// code after a Return or Jump can only run if something jumps to it
+fn remove_dead_code(program: &mut Program) -> bool {
+    let entry_points = entry_points(program);
+    let mut removed = vec![false; program.instructions.len()];
+    let mut reachable = true;
+    for (index, op) in program.instructions.iter().enumerate() {
+        reachable |= entry_points[index];
+        removed[index] = !reachable;
+        if matches!(op, OpCode::Return) || matches!(op, OpCode::Jump(to_jump) if *to_jump != 0) {
+            reachable = false;
+        }
+    }
+    remove(program, &removed)
+}
+
+// GetLocal, ConstantNum, Add, SetLocal of the same local becomes AddLocal
+fn add_locals(program: &mut Program) -> bool {
+    let entry_points = entry_points(program);
+    let code = &mut program.instructions;
+    let mut removed = vec![false; code.len()];
+    let mut index = 0;
+    while index + 3 < code.len() {
+        if entry_points[index + 1..index + 4].contains(&true) {
+            index += 1;
+            continue;
+        }
+        if let (OpCode::GetLocal(get), OpCode::ConstantNum(n), OpCode::Add, OpCode::SetLocal(set)) = (
+            &code[index],
+            &code[index + 1],
+            &code[index + 2],
+            &code[index + 3],
+        ) {
+            if get == set {
+                code[index] = OpCode::AddLocal(*get, *n);
+                // errors point at the '+'
+                if let Some(&line_number) = program.line_numbers.get(index + 2) {
+                    program.line_numbers[index] = line_number;
+                }
+                if let Some(&span) = program.spans.get(index + 2) {
+                    program.spans[index] = span;
+                }
+                removed[index + 1..index + 4].fill(true);
+                index += 4;
+                continue;
+            }
+        }
+        index += 1;
+    }
+    remove(program, &removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::optimize;
+    use crate::bytecode::Program;
+    use crate::compiler::{Compiler, OpCode};
+    use crate::scanner::tokenize;
+
+    fn compile(source: &str) -> Program {
+        let tokens = tokenize(source).unwrap();
+        let mut instructions = Vec::new();
+        let mut line_numbers = Vec::new();
+        let mut compiler = Compiler::new(&tokens, &mut instructions, &mut line_numbers);
+        compiler.print_errors = false;
+        compiler.compile();
+        assert!(!compiler.in_error);
+        let function_names = compiler
+            .functions
+            .iter()
+            .map(|f| (f.2, f.0.clone()))
+            .collect();
+        let global_names = compiler.variables.iter().map(|v| v.name.clone()).collect();
+        let files = std::mem::take(&mut compiler.files);
+        let spans = std::mem::take(&mut compiler.spans);
+        Program {
+            instructions,
+            line_numbers,
+            function_names,
+            global_names,
+            files,
+            spans,
+        }
+    }
+
+    fn optimized(source: &str) -> Vec<OpCode> {
+        let mut program = compile(source);
+        optimize(&mut program);
+        assert_eq!(program.line_numbers.len(), program.instructions.len());
+        assert_eq!(program.spans.len(), program.instructions.len());
+        program.instructions
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        assert_eq!(
+            format!("{:?}", optimized("x = 60 * 60 * 24 + -1")),
+            "[ConstantNum(86399.0), SetGlobal(0), Pop]"
+        );
+        assert_eq!(
+            format!(
+                "{:?}",
+                optimized("x = \"a\" + \"b\" + \"c\" == \"abc\" and not false")
+            ),
+            "[ConstantBool(true), SetGlobal(0), Pop]"
+        );
+        // left for the vm to report
+        assert_eq!(
+            format!("{:?}", optimized("1 + \"a\"")),
+            "[ConstantNum(1.0), ConstantStr(\"a\"), Add, Pop]"
+        );
+    }
+
+    #[test]
+    fn test_jumps_and_dead_code() {
+        let code = optimized(
+            "function f(x)
+    if x > 1 then
+        x = 0
+    end
+    while true
+        break
+        x = x + 1
+    end
+    x
+end
+f(2)",
+        );
+        // the if has no else, so its jump over the else goes nowhere
+        assert!(!code.iter().any(|op| matches!(op, OpCode::Jump(0))));
+        // nothing jumps to the code after break
+        assert!(!code
+            .iter()
+            .any(|op| matches!(op, OpCode::Add | OpCode::AddLocal(_, _))));
+        // the function still starts after the jump over it
+        let OpCode::Jump(over) = code[0] else {
+            panic!("expected a jump, got {:?}", code[0]);
+        };
+        assert!(matches!(code[over as usize], OpCode::Return));
+        assert!(code.iter().any(|op| matches!(op, OpCode::Func(1, 1))));
+    }
+
+    #[test]
+    fn test_add_local() {
+        let code =
+            optimized("function f()\n t = 0\n for i = 1 to 10 step 2\n t = t + i\n next\n t\nend");
+        assert!(code
+            .iter()
+            .any(|op| matches!(op, OpCode::AddLocal(1, n) if *n == 2.0)));
+    }
+}
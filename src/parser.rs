@@ -0,0 +1,1142 @@
+//! Builds the tree in ast.rs from a script's tokens.
+//!
+//! A mistake is recorded as a SyntaxError and the rest of the statement it's in is
+//! skipped, so the statements after it are still parsed and their errors found too.
+//! The part of a statement before the mistake is kept, the compiler still looks at it
+//! for the variables and functions it uses.
+
+use crate::ast::{
+    Arm, BinaryOp, Expr, For, Function, If, Literal, Match, Pattern, Stmt, Target, Try, UnaryOp,
+};
+use crate::scanner::{precedence, Span, Token, TokenType};
+
+// what's after the last token, so looking past the end of the tokens is always safe
+static EOF: TokenType = TokenType::Eof;
+
+/// A mistake in the source, found while parsing
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+    pub message: String,
+    pub line_number: u32, // 0 when the error isn't about one line
+    pub span: Span,
+}
+
+pub struct Parser<'t> {
+    tokens: &'t [TokenType],
+    current: usize,
+    pub errors: Vec<SyntaxError>,
+    // set from an error until the end of the statement it's in, so one mistake
+    // doesn't cause a string of errors after it
+    panic_mode: bool,
+    // how many blocks we're in, named functions and imports are only allowed outside them
+    depth: usize,
+    // where the first function defined inside the current one starts, and the number
+    // of errors before it
+    nested_function: Option<(usize, usize)>,
+    // how many for loops we're in, inside one a next ends any block
+    for_loops: usize,
+}
+
+/// Parses a whole script, returning the statements and any errors in them
+pub fn parse(tokens: &[TokenType]) -> (Vec<Stmt<'_>>, Vec<SyntaxError>) {
+    let mut parser = Parser::new(tokens);
+    let mut statements = Vec::new();
+    while !parser.at_end() {
+        if let Some(statement) = parser.statement() {
+            statements.push(statement);
+        }
+    }
+    (statements, parser.errors)
+}
+
+fn get_precedence(token: &TokenType) -> u8 {
+    match token {
+        TokenType::Plus(t)
+        | TokenType::Minus(t)
+        | TokenType::Times(t)
+        | TokenType::Divide(t)
+        | TokenType::GreaterThan(t)
+        | TokenType::LessThan(t)
+        | TokenType::GreaterThanOrEqual(t)
+        | TokenType::LessThanOrEqual(t)
+        | TokenType::Equality(t)
+        | TokenType::NotEquals(t)
+        | TokenType::LeftParan(t)
+        | TokenType::And(t)
+        | TokenType::Or(t)
+        | TokenType::Hat(t)
+        | TokenType::Mod(t)
+        | TokenType::LeftBracket(t)
+        | TokenType::In(t)
+        | TokenType::Dot(t) => t.precedence,
+        _ => precedence::NONE,
+    }
+}
+
+// the entries of a map parsed before an error, and the rest of the one it was in
+fn incomplete_map<'t>(entries: Vec<(Expr<'t>, Expr<'t>)>, rest: Vec<Expr<'t>>) -> Expr<'t> {
+    let mut parts: Vec<Expr<'t>> = entries.into_iter().flat_map(|(k, v)| [k, v]).collect();
+    parts.extend(rest);
+    Expr::Incomplete(parts)
+}
+
+impl<'t> Parser<'t> {
+    pub fn new(tokens: &'t [TokenType]) -> Parser<'t> {
+        Parser {
+            tokens,
+            current: 0,
+            errors: Vec::new(),
+            panic_mode: false,
+            depth: 0,
+            nested_function: None,
+            for_loops: 0,
+        }
+    }
+
+    pub fn at_end(&self) -> bool {
+        matches!(self.peek(), TokenType::Eof)
+    }
+
+    fn peek(&self) -> &'t TokenType {
+        self.tokens.get(self.current).unwrap_or(&EOF)
+    }
+
+    fn peek_next(&self) -> &'t TokenType {
+        self.tokens.get(self.current + 1).unwrap_or(&EOF)
+    }
+
+    // moves over the next token and returns it, staying put at the end of the file
+    fn advance(&mut self) -> &'t TokenType {
+        let token = self.peek();
+        if !matches!(token, TokenType::Eof) {
+            self.current += 1;
+        }
+        token
+    }
+
+    fn skip_eol(&mut self) {
+        while let TokenType::Eol(_) = self.peek() {
+            self.advance();
+        }
+    }
+
+    fn error(&mut self, message: &str, token: &Token) {
+        self.add_error(message, token.line_number, token.span);
+    }
+
+    fn error_message(&mut self, message: &str) {
+        self.add_error(message, 0, Span::default());
+    }
+
+    fn add_error(&mut self, message: &str, line_number: u32, span: Span) {
+        if self.panic_mode {
+            return;
+        }
+        self.errors.push(SyntaxError {
+            message: message.to_string(),
+            line_number,
+            span,
+        });
+        self.panic_mode = true;
+    }
+
+    /// Parses the statement at the current token. Returns None for an empty line, or
+    /// a statement with too many errors to keep any of it.
+    pub fn statement(&mut self) -> Option<Stmt<'t>> {
+        let start = self.current;
+        let statement = match self.peek() {
+            TokenType::Eol(_) => {
+                self.advance();
+                None
+            }
+            TokenType::If(t) => Some(Stmt::If(self.if_statement(t))),
+            TokenType::While(t) => Some(self.while_statement(t)),
+            TokenType::Function(t) => {
+                if let TokenType::LeftParan(_) = self.peek_next() {
+                    Some(Stmt::Expression(self.expression()))
+                } else {
+                    self.def_fn(t)
+                }
+            }
+            TokenType::For(t) => self.for_statement(t),
+            TokenType::Try(t) => self.try_statement(t),
+            TokenType::Import(t) => self.import_statement(t),
+            TokenType::Break(t) => {
+                self.advance();
+                Some(Stmt::Break(t))
+            }
+            TokenType::Continue(t) => {
+                self.advance();
+                Some(Stmt::Continue(t))
+            }
+            TokenType::Return(t) => {
+                self.advance();
+                Some(Stmt::Return(t))
+            }
+            _ => Some(Stmt::Expression(self.expression())),
+        };
+        if self.panic_mode {
+            self.synchronize(start);
+        }
+        statement
+    }
+
+    // Skips the rest of a statement with an error in it. Stops at the end of the line,
+    // or at a keyword that ends a block so the block can finish.
+    fn synchronize(&mut self, start: usize) {
+        self.panic_mode = false;
+        // always move on, or the same statement would fail forever
+        if self.current == start {
+            self.current += 1;
+        }
+        // lines ending in e.g. ')' have no Eol, so also stop at the next line
+        let line_number = self.tokens[self.current - 1]
+            .get_token()
+            .map(|t| t.line_number);
+        while let Some(token) = self.tokens.get(self.current) {
+            match token {
+                TokenType::Eol(_) => {
+                    self.current += 1;
+                    return;
+                }
+                TokenType::End(_)
+                | TokenType::Next(_)
+                | TokenType::Else(_)
+                | TokenType::ElseIf(_)
+                | TokenType::When(_)
+                | TokenType::Catch(_)
+                | TokenType::Eof => return,
+                _ => {
+                    let next_line = token.get_token().map(|t| t.line_number);
+                    if line_number.is_some() && next_line > line_number {
+                        return;
+                    }
+                    self.current += 1;
+                }
+            }
+        }
+    }
+
+    // After a mistake in the line that starts a block, e.g. 'if x = then', skips the
+    // rest of that line. The body and its end are still parsed as part of the block,
+    // so they don't cause more errors. A block cut short by a keyword that ends one,
+    // as in 'if next', stays in panic mode, as its missing end is the same mistake.
+    fn skip_header(&mut self) {
+        if !self.panic_mode {
+            return;
+        }
+        let line_number = self
+            .tokens
+            .get(self.current.wrapping_sub(1))
+            .and_then(|t| t.get_token())
+            .map(|t| t.line_number);
+        loop {
+            match self.peek() {
+                TokenType::Eol(_) | TokenType::Then(_) => {
+                    self.advance();
+                    break;
+                }
+                TokenType::End(_)
+                | TokenType::Next(_)
+                | TokenType::Else(_)
+                | TokenType::ElseIf(_)
+                | TokenType::When(_)
+                | TokenType::Catch(_)
+                | TokenType::Eof => return,
+                token => {
+                    let next_line = token.get_token().map(|t| t.line_number);
+                    if line_number.is_some() && next_line > line_number {
+                        break;
+                    }
+                    self.advance();
+                }
+            }
+        }
+        self.panic_mode = false;
+    }
+
+    // the statements up to the keyword that ends or divides a block. In a for loop a
+    // next ends one too, so an if missing its end doesn't take the loop's next.
+    fn block(&mut self) -> Vec<Stmt<'t>> {
+        let mut statements = Vec::new();
+        loop {
+            match self.peek() {
+                TokenType::Else(_)
+                | TokenType::ElseIf(_)
+                | TokenType::End(_)
+                | TokenType::When(_)
+                | TokenType::Catch(_)
+                | TokenType::Eof => break,
+                TokenType::Next(_) if self.for_loops > 0 => break,
+                _ => statements.extend(self.statement()),
+            }
+        }
+        statements
+    }
+
+    fn scoped_block(&mut self) -> Vec<Stmt<'t>> {
+        self.depth += 1;
+        let statements = self.block();
+        self.depth -= 1;
+        statements
+    }
+
+    fn for_block(&mut self) -> Vec<Stmt<'t>> {
+        let mut statements = Vec::new();
+        loop {
+            match self.peek() {
+                TokenType::Next(_) | TokenType::Eof => break,
+                _ => statements.extend(self.statement()),
+            }
+        }
+        statements
+    }
+
+    fn if_statement(&mut self, token: &'t Token) -> If<'t> {
+        self.advance();
+        let condition = self.expression();
+        let mut statement = If {
+            token,
+            condition,
+            then_branch: Vec::new(),
+            else_branch: None,
+            else_if: None,
+        };
+        match self.peek() {
+            TokenType::Then(_) if !self.panic_mode => {
+                self.advance();
+            }
+            _ => {
+                self.error("If without then", token);
+                self.skip_header();
+            }
+        }
+        statement.then_branch = self.scoped_block();
+        if let TokenType::Else(_) = self.peek() {
+            self.advance();
+            statement.else_branch = Some(self.scoped_block());
+        }
+        match self.peek() {
+            // the elseif finishes with the end of the whole statement
+            TokenType::ElseIf(t) => statement.else_if = Some(Box::new(self.if_statement(t))),
+            TokenType::End(_) => {
+                self.advance();
+            }
+            _ => self.error("If without end", token),
+        }
+        statement
+    }
+
+    fn while_statement(&mut self, token: &'t Token) -> Stmt<'t> {
+        self.advance();
+        let condition = self.expression();
+        self.skip_header();
+        let body = self.scoped_block();
+        if let TokenType::End(_) = self.peek() {
+            self.advance();
+        } else {
+            self.error("while without end", token);
+        }
+        Stmt::While {
+            token,
+            condition,
+            body,
+        }
+    }
+
+    fn for_statement(&mut self, token: &'t Token) -> Option<Stmt<'t>> {
+        self.advance();
+        self.depth += 1;
+        let statement = self.for_loop(token);
+        self.depth -= 1;
+        statement.map(Stmt::For)
+    }
+
+    // for variable = start to end [step [-]number] body next
+    fn for_loop(&mut self, token: &'t Token) -> Option<For<'t>> {
+        let header = self.for_header(token);
+        self.skip_header();
+        self.depth += 1;
+        self.for_loops += 1;
+        let body = self.for_block();
+        self.for_loops -= 1;
+        self.depth -= 1;
+        self.advance(); // over the next
+        let (variable, start, end, step) = header?;
+        Some(For {
+            token,
+            variable,
+            start,
+            end,
+            step,
+            body,
+        })
+    }
+
+    // the variable, start, end and step of a for loop
+    fn for_header(&mut self, token: &'t Token) -> Option<(&'t Token, Expr<'t>, Expr<'t>, f64)> {
+        let TokenType::Identifier(variable) = self.peek() else {
+            self.error("Invalid use of 'for' statement", token);
+            return None;
+        };
+        self.advance();
+        if let TokenType::Equals(_) = self.peek() {
+            self.advance();
+        } else {
+            self.error("Invalid use of 'for' statement", token);
+            return None;
+        }
+        let start = self.expression();
+
+        if let TokenType::To(_) = self.peek() {
+            self.advance();
+        } else {
+            self.error("Invalid use of 'for' statement", token);
+            return None;
+        }
+        let end = self.expression();
+
+        let mut step: f64 = 1.0;
+        if let TokenType::Step(_) = self.peek() {
+            self.advance();
+            let step_down = if let TokenType::Minus(_) = self.peek() {
+                self.advance();
+                true
+            } else {
+                false
+            };
+            let TokenType::Number(number) = self.peek() else {
+                self.error("Step must be a number not equal to zero", token);
+                return None;
+            };
+            self.advance();
+            step = match number.lexeme.parse::<f64>() {
+                Ok(v) => v,
+                Err(_) => {
+                    self.error("Could not parse number", token);
+                    return None;
+                }
+            };
+            if step_down {
+                step = -step;
+            }
+        }
+        if step == 0.0 {
+            self.error("Step cannot be zero", token);
+            return None;
+        }
+        Some((variable, start, end, step))
+    }
+
+    fn try_statement(&mut self, token: &'t Token) -> Option<Stmt<'t>> {
+        self.advance();
+        let body = self.scoped_block();
+        let TokenType::Catch(catch) = self.peek() else {
+            self.error("try without catch", token);
+            if let TokenType::End(_) = self.peek() {
+                self.advance();
+            }
+            return None;
+        };
+        self.advance();
+
+        self.depth += 1;
+        let variable = if let TokenType::Identifier(t) = self.peek() {
+            self.advance();
+            Some(t)
+        } else {
+            None
+        };
+        let handler = self.block();
+        self.depth -= 1;
+
+        if let TokenType::End(_) = self.peek() {
+            self.advance();
+        } else {
+            self.error("try without end", token);
+        }
+        Some(Stmt::Try(Try {
+            token,
+            body,
+            catch,
+            variable,
+            handler,
+        }))
+    }
+
+    fn import_statement(&mut self, token: &'t Token) -> Option<Stmt<'t>> {
+        self.advance();
+        if self.depth > 0 {
+            self.error("import is only allowed at the top level", token);
+            return None;
+        }
+        if let TokenType::String(path) = self.peek() {
+            self.advance();
+            Some(Stmt::Import { token, path })
+        } else {
+            self.error("Expected a file name after import", token);
+            None
+        }
+    }
+
+    fn def_fn(&mut self, token: &'t Token) -> Option<Stmt<'t>> {
+        let start = self.current;
+        self.advance();
+        // a function inside another is still parsed, so its end doesn't finish the
+        // function it's in
+        let nested = self.depth > 0;
+        if nested {
+            self.nested_function
+                .get_or_insert((start, self.errors.len()));
+            self.error("Can't define a function within a function", token);
+        }
+        self.depth += 1;
+        let for_loops = std::mem::take(&mut self.for_loops);
+        let function = self.fn_definition(token);
+        self.for_loops = for_loops;
+        self.depth -= 1;
+        if nested {
+            return None;
+        }
+        function.map(Stmt::Function)
+    }
+
+    // the name, params and body of a function. After a mistake in the first line the
+    // body is still parsed, and the function kept if it has a name and its params.
+    fn fn_definition(&mut self, token: &'t Token) -> Option<Function<'t>> {
+        let errors = self.errors.len();
+        let name = if let TokenType::Identifier(name) = self.peek() {
+            self.advance();
+            Some(name)
+        } else {
+            self.error("missing function name", token);
+            None
+        };
+        let params = if self.panic_mode {
+            None
+        } else if let TokenType::LeftParan(_) = self.peek() {
+            self.advance();
+            Some(self.params(token))
+        } else {
+            self.error("missing '(' after function name", token);
+            None
+        };
+        self.skip_header();
+
+        let outermost = self.depth == 1;
+        if outermost {
+            self.nested_function = None;
+        }
+        let mut body = self.function_body(token, errors).unwrap_or_default();
+        if let TokenType::End(_) = self.peek() {
+            self.advance();
+        } else if let (true, Some((start, errors))) = (outermost, self.nested_function) {
+            // more likely this function is missing its end than the ones after it are
+            // inside it, so they're parsed again on their own
+            self.current = start;
+            self.errors.truncate(errors);
+            self.panic_mode = false;
+            self.error("Function without end", token);
+            // carry on from the next function, not the end of its line
+            self.panic_mode = false;
+            body.clear();
+        } else {
+            self.error("Function without end", token);
+        }
+        // without its body the function is still kept, so calls to it can be checked
+        Some(Function {
+            token,
+            name: Some(name?),
+            params: params?,
+            body,
+        })
+    }
+
+    // the params of a function up to the ')'. A param with a mistake in it still
+    // counts, so calls with the right number of arguments aren't reported too.
+    fn params(&mut self, token: &Token) -> Vec<&'t Token> {
+        let mut params = Vec::new();
+        // in a param that has had a mistake, e.g. the 'ue' in 'val[ue'
+        let mut in_mistake = false;
+        loop {
+            match self.peek() {
+                TokenType::RightParan(_) => {
+                    self.advance();
+                    return params;
+                }
+                TokenType::Comma(_) => {
+                    in_mistake = false;
+                    self.advance();
+                }
+                TokenType::Identifier(param) => {
+                    if !in_mistake {
+                        params.push(param);
+                    }
+                    self.advance();
+                }
+                TokenType::Eol(_) | TokenType::Eof => {
+                    self.error("Expected )", token);
+                    return params;
+                }
+                other => {
+                    self.error("Function parameter expected", token);
+                    let after_param = matches!(
+                        self.tokens.get(self.current.wrapping_sub(1)),
+                        Some(TokenType::Identifier(_))
+                    );
+                    if !in_mistake && !after_param {
+                        params.extend(other.get_token());
+                    }
+                    in_mistake = true;
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    // None if there isn't one. Statements with errors count, those are reported already,
+    // as do mistakes in the first line of the function, errors is the count before it.
+    fn function_body(&mut self, token: &Token, errors: usize) -> Option<Vec<Stmt<'t>>> {
+        let body = self.block();
+        if body.is_empty() && errors == self.errors.len() {
+            self.error("all functions must have a body", token);
+            return None;
+        }
+        Some(body)
+    }
+
+    fn expression(&mut self) -> Expr<'t> {
+        self.parse_precedence(precedence::ASSIGNMENT)
+    }
+
+    fn parse_precedence(&mut self, precedence: u8) -> Expr<'t> {
+        if self.panic_mode {
+            return Expr::Error;
+        }
+
+        let can_assign = precedence <= precedence::ASSIGNMENT;
+        let mut expr = match self.advance() {
+            TokenType::Number(t) => self.number(t),
+            TokenType::String(t) => Expr::Literal {
+                token: t,
+                value: Literal::String(t.lexeme.clone()),
+            },
+            TokenType::Bool(t) => Expr::Literal {
+                token: t,
+                value: Literal::Bool(t.lexeme == "true"),
+            },
+            TokenType::Minus(t) => self.unary(UnaryOp::Negate, t),
+            TokenType::Not(t) => self.unary(UnaryOp::Not, t),
+            TokenType::LeftParan(t) => self.grouping(t),
+            TokenType::LeftBrace(t) => self.map_literal(t),
+            TokenType::Function(t) => self.lambda(t),
+            TokenType::Identifier(t) => self.variable(t, can_assign),
+            TokenType::Match(t) => self.pattern_match(t),
+            TokenType::Eol(t) => {
+                self.error("Unexpected end of line", t);
+                Expr::Error
+            }
+            token => {
+                if let Some(t) = token.get_token() {
+                    let message = format!("Unexpected statement '{}'", t.lexeme);
+                    self.error(&message, t);
+                } else {
+                    self.error_message("Unexpected end of file");
+                }
+                // leave e.g. the end after 'x = 1 +' for the block it belongs to, or
+                // the block would carry on past it
+                if matches!(
+                    token,
+                    TokenType::End(_)
+                        | TokenType::Next(_)
+                        | TokenType::Else(_)
+                        | TokenType::ElseIf(_)
+                        | TokenType::When(_)
+                        | TokenType::Catch(_)
+                        | TokenType::Then(_)
+                        | TokenType::To(_)
+                ) {
+                    self.current -= 1;
+                }
+                Expr::Error
+            }
+        };
+
+        while !self.panic_mode && precedence <= get_precedence(self.peek()) {
+            expr = match self.advance() {
+                TokenType::Plus(t) => self.binary(expr, BinaryOp::Add, t),
+                TokenType::Minus(t) => self.binary(expr, BinaryOp::Subtract, t),
+                TokenType::Times(t) => self.binary(expr, BinaryOp::Multiply, t),
+                TokenType::Divide(t) => self.binary(expr, BinaryOp::Divide, t),
+                TokenType::Hat(t) => self.binary(expr, BinaryOp::Pow, t),
+                TokenType::Mod(t) => self.binary(expr, BinaryOp::Mod, t),
+                TokenType::GreaterThan(t) => self.binary(expr, BinaryOp::GreaterThan, t),
+                TokenType::GreaterThanOrEqual(t) => self.binary(expr, BinaryOp::GreaterThanEq, t),
+                TokenType::LessThan(t) => self.binary(expr, BinaryOp::LessThan, t),
+                TokenType::LessThanOrEqual(t) => self.binary(expr, BinaryOp::LessThanEq, t),
+                TokenType::Equality(t) => self.binary(expr, BinaryOp::Equal, t),
+                TokenType::NotEquals(t) => self.binary(expr, BinaryOp::NotEqual, t),
+                TokenType::And(t) => self.binary(expr, BinaryOp::And, t),
+                TokenType::Or(t) => self.binary(expr, BinaryOp::Or, t),
+                TokenType::LeftParan(t) => self.call(expr, t),
+                TokenType::Dot(t) => self.dot(expr, t),
+                TokenType::LeftBracket(t) => self.subscript(expr, t),
+                TokenType::In(t) => self.in_operator(expr, t),
+                _ => break,
+            };
+        }
+        expr
+    }
+
+    fn number(&mut self, token: &'t Token) -> Expr<'t> {
+        match token.lexeme.parse::<f64>() {
+            Ok(value) => Expr::Literal {
+                token,
+                value: Literal::Number(value),
+            },
+            Err(_) => {
+                self.error("Could not parse number", token);
+                Expr::Error
+            }
+        }
+    }
+
+    fn unary(&mut self, op: UnaryOp, token: &'t Token) -> Expr<'t> {
+        let operand = self.parse_precedence(precedence::UNARY);
+        Expr::Unary {
+            op,
+            token,
+            operand: Box::new(operand),
+        }
+    }
+
+    fn binary(&mut self, left: Expr<'t>, op: BinaryOp, token: &'t Token) -> Expr<'t> {
+        let right = self.parse_precedence(token.precedence + 1);
+        Expr::Binary {
+            op,
+            token,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    fn grouping(&mut self, token: &'t Token) -> Expr<'t> {
+        let expr = self.expression();
+        if let TokenType::RightParan(_) = self.peek() {
+            self.advance();
+        } else {
+            self.error("Expected )", token);
+        }
+        Expr::Grouping(Box::new(expr))
+    }
+
+    fn variable(&mut self, token: &'t Token, can_assign: bool) -> Expr<'t> {
+        // a system call, the name is taken by call()
+        if token.lexeme.starts_with('@') {
+            if let TokenType::LeftParan(_) = self.peek() {
+                return Expr::Variable(token);
+            }
+            self.error("Expect '(' after system call", token);
+            return Expr::Error;
+        }
+
+        // an '=' is always taken, but only assigns where an assignment is allowed
+        let matched_equal = matches!(self.peek(), TokenType::Equals(_));
+        if matched_equal {
+            self.advance();
+        }
+        if can_assign && matched_equal {
+            let value = self.expression();
+            return Expr::Assign {
+                target: Target::Variable(token),
+                value: Box::new(value),
+            };
+        }
+        Expr::Variable(token)
+    }
+
+    // entries are separated by one comma, and there can be a comma after the last one
+    fn map_literal(&mut self, token: &'t Token) -> Expr<'t> {
+        let mut entries = Vec::new();
+        loop {
+            self.skip_eol();
+            match self.peek() {
+                TokenType::RightBrace(_) => {
+                    self.advance();
+                    return Expr::Map { token, entries };
+                }
+                TokenType::Eof => {
+                    self.error("Expected }", token);
+                    return incomplete_map(entries, Vec::new());
+                }
+                _ => (),
+            }
+            let key = self.expression();
+            if let TokenType::Colon(_) = self.peek() {
+                self.advance();
+            } else {
+                self.error("Expected ':' after key in map", token);
+                return incomplete_map(entries, vec![key]);
+            }
+            self.skip_eol();
+            let value = self.expression();
+            if self.panic_mode {
+                return incomplete_map(entries, vec![key, value]);
+            }
+            entries.push((key, value));
+            self.skip_eol();
+            match self.peek() {
+                TokenType::Comma(_) => {
+                    self.advance();
+                }
+                TokenType::RightBrace(_) => (),
+                TokenType::Eof => {
+                    self.error("Expected }", token);
+                    return incomplete_map(entries, Vec::new());
+                }
+                next => {
+                    match next.get_token() {
+                        Some(t) => self.error("Expected ',' or '}'", t),
+                        None => self.error_message("Expected ',' or '}'"),
+                    }
+                    return incomplete_map(entries, Vec::new());
+                }
+            }
+        }
+    }
+
+    // the arguments of a call, after the '('
+    // Err has the arguments parsed before the error
+    fn arguments(&mut self, token: &Token) -> Result<Vec<Expr<'t>>, Vec<Expr<'t>>> {
+        let mut args = Vec::new();
+        loop {
+            match self.peek() {
+                TokenType::RightParan(_) => {
+                    self.advance();
+                    return Ok(args);
+                }
+                TokenType::Comma(_) => {
+                    self.advance();
+                }
+                TokenType::Eof => {
+                    self.error("Expected )", token);
+                    return Err(args);
+                }
+                _ => {
+                    args.push(self.expression());
+                    if self.panic_mode {
+                        return Err(args);
+                    }
+                }
+            }
+        }
+    }
+
+    // when the call can't be parsed, the function being called is still kept
+    fn call(&mut self, callee: Expr<'t>, paren: &'t Token) -> Expr<'t> {
+        let system_call = match &callee {
+            Expr::Variable(name) if name.lexeme.starts_with('@') => Some(*name),
+            // a function by name, or the result of a call or subscript, e.g. adder(1)(2)
+            Expr::Variable(_)
+            | Expr::Call { .. }
+            | Expr::SystemCall { .. }
+            | Expr::MethodCall { .. }
+            | Expr::Subscript { .. }
+            | Expr::Grouping(_) => None,
+            _ => {
+                self.error("Expect funtion name before '('", paren);
+                return callee;
+            }
+        };
+        let args = match self.arguments(paren) {
+            Ok(args) => args,
+            Err(mut args) => {
+                args.insert(0, callee);
+                return Expr::Incomplete(args);
+            }
+        };
+        match system_call {
+            Some(name) => Expr::SystemCall { name, paren, args },
+            None => Expr::Call {
+                callee: Box::new(callee),
+                paren,
+                args,
+            },
+        }
+    }
+
+    // receiver.method(args)
+    fn dot(&mut self, receiver: Expr<'t>, dot: &'t Token) -> Expr<'t> {
+        let TokenType::Identifier(method) = self.advance() else {
+            self.error("Syntax Error - Invalid call target", dot);
+            return receiver;
+        };
+        if let TokenType::LeftParan(_) = self.peek() {
+            self.advance();
+        } else {
+            self.error("Missing '(' in method call", dot);
+            return receiver;
+        }
+        let args = match self.arguments(dot) {
+            Ok(args) => args,
+            Err(mut args) => {
+                args.insert(0, receiver);
+                return Expr::Incomplete(args);
+            }
+        };
+        Expr::MethodCall {
+            receiver: Box::new(receiver),
+            dot,
+            method,
+            args,
+        }
+    }
+
+    // object[index], or variable[index] = value
+    fn subscript(&mut self, object: Expr<'t>, bracket: &'t Token) -> Expr<'t> {
+        let index = self.expression();
+        if !matches!(self.peek(), TokenType::RightBracket(_)) {
+            self.error("Missing ]", bracket);
+            return Expr::Incomplete(vec![object, index]);
+        }
+        if let TokenType::Equals(_) = self.peek_next() {
+            let Expr::Variable(variable) = object else {
+                self.error(
+                    "Cannot set value of subscript on something that is not a variable!",
+                    bracket,
+                );
+                return object;
+            };
+            self.advance(); // over ]
+            self.advance(); // over =
+            let value = self.expression();
+            return Expr::Assign {
+                target: Target::Subscript {
+                    variable,
+                    bracket,
+                    index: Box::new(index),
+                },
+                value: Box::new(value),
+            };
+        }
+        self.advance();
+        Expr::Subscript {
+            object: Box::new(object),
+            bracket,
+            index: Box::new(index),
+        }
+    }
+
+    // value in a, b, c
+    fn in_operator(&mut self, value: Expr<'t>, token: &'t Token) -> Expr<'t> {
+        let mut list = vec![self.expression()];
+        while let TokenType::Comma(_) = self.peek() {
+            self.advance();
+            list.push(self.expression());
+        }
+        Expr::In {
+            value: Box::new(value),
+            token,
+            list,
+        }
+    }
+
+    // fn(params) body end
+    fn lambda(&mut self, token: &'t Token) -> Expr<'t> {
+        if let TokenType::LeftParan(_) = self.peek() {
+            self.advance();
+        } else {
+            self.error("missing '(' after fn", token);
+            return Expr::Error;
+        }
+        let depth = std::mem::replace(&mut self.depth, 1);
+        let for_loops = std::mem::take(&mut self.for_loops);
+        let function = self.lambda_body(token);
+        self.for_loops = for_loops;
+        self.depth = depth;
+        match function {
+            Some(function) => Expr::Lambda(Box::new(function)),
+            None => Expr::Error,
+        }
+    }
+
+    fn lambda_body(&mut self, token: &'t Token) -> Option<Function<'t>> {
+        let errors = self.errors.len();
+        let params = self.params(token);
+        self.skip_header();
+        let body = self.function_body(token, errors);
+        if let TokenType::End(_) = self.peek() {
+            self.advance();
+        } else {
+            self.error("fn without end", token);
+            return None;
+        }
+        Some(Function {
+            token,
+            name: None,
+            params,
+            body: body?,
+        })
+    }
+
+    fn pattern_match(&mut self, token: &'t Token) -> Expr<'t> {
+        let value = self.expression();
+        self.skip_header();
+        let mut arms = Vec::new();
+        loop {
+            self.skip_eol();
+            match self.peek() {
+                TokenType::When(t) => {
+                    self.advance();
+                    let pattern = self.when_pattern(t);
+                    self.skip_header();
+                    let body = self.scoped_block();
+                    // an arm with a mistake in its pattern is left out
+                    if let Some(pattern) = pattern {
+                        arms.push(Arm::When {
+                            token: t,
+                            pattern,
+                            body,
+                        });
+                    }
+                }
+                TokenType::Else(t) => {
+                    self.advance();
+                    let body = self.scoped_block();
+                    arms.push(Arm::Else { token: t, body });
+                }
+                TokenType::End(end) => {
+                    self.advance();
+                    return Expr::Match(Box::new(Match {
+                        token,
+                        value,
+                        arms,
+                        end,
+                    }));
+                }
+                TokenType::Eof => {
+                    self.error("Invalid syntax in match", token);
+                    return Expr::Error;
+                }
+                _ => {
+                    self.error("Invalid syntax in match", token);
+                    self.advance();
+                    self.skip_header();
+                }
+            }
+        }
+    }
+
+    // the pattern of a when arm up to and including its then
+    fn when_pattern(&mut self, token: &'t Token) -> Option<Pattern<'t>> {
+        let op = match self.peek() {
+            TokenType::GreaterThan(_) => Some(BinaryOp::GreaterThan),
+            TokenType::GreaterThanOrEqual(_) => Some(BinaryOp::GreaterThanEq),
+            TokenType::LessThan(_) => Some(BinaryOp::LessThan),
+            TokenType::LessThanOrEqual(_) => Some(BinaryOp::LessThanEq),
+            TokenType::NotEquals(_) | TokenType::Not(_) => Some(BinaryOp::NotEqual),
+            _ => None,
+        };
+        if op.is_some() {
+            self.advance();
+        }
+        let op = op.unwrap_or(BinaryOp::Equal);
+
+        let first = self.expression();
+        let pattern = match self.peek() {
+            TokenType::To(_) => {
+                if op != BinaryOp::Equal {
+                    self.error("Unexpected 'to' in match", token);
+                    return None;
+                }
+                self.advance();
+                Pattern::Between(first, self.expression())
+            }
+            TokenType::Comma(_) => {
+                let mut list = vec![first];
+                while let TokenType::Comma(_) = self.peek() {
+                    self.advance();
+                    list.push(self.expression());
+                }
+                Pattern::In(list)
+            }
+            _ => Pattern::Compare(op, first),
+        };
+
+        if let TokenType::Then(_) = self.peek() {
+            self.advance();
+        } else {
+            self.error("'then' missing in match", token);
+            return None;
+        }
+        if self.panic_mode {
+            return None;
+        }
+        Some(pattern)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+    use crate::ast::{BinaryOp, Expr, Stmt, Target};
+    use crate::scanner::tokenize;
+
+    #[test]
+    fn test_parse() {
+        let tokens = tokenize("x = 1 + 2 * 3\nfor i = 10 to 1 step -2\nprint(i)\nnext").unwrap();
+        let (statements, errors) = parse(&tokens);
+        assert!(errors.is_empty());
+        assert_eq!(statements.len(), 2);
+
+        let Stmt::Expression(Expr::Assign { target, value }) = &statements[0] else {
+            panic!("expected an assignment, got {:?}", statements[0]);
+        };
+        assert!(matches!(target, Target::Variable(t) if t.lexeme == "x"));
+        let Expr::Binary { op, right, .. } = value.as_ref() else {
+            panic!("expected a binary expression, got {value:?}");
+        };
+        assert_eq!(*op, BinaryOp::Add);
+        assert!(matches!(
+            right.as_ref(),
+            Expr::Binary {
+                op: BinaryOp::Multiply,
+                ..
+            }
+        ));
+
+        let Stmt::For(for_loop) = &statements[1] else {
+            panic!("expected a for loop, got {:?}", statements[1]);
+        };
+        assert_eq!(for_loop.variable.lexeme, "i");
+        assert_eq!(for_loop.step, -2.0);
+        assert_eq!(for_loop.body.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let tokens = tokenize("x = (1 + 2\ny = ]\nfunction f(1)\nend\nprint(y)").unwrap();
+        let (statements, errors) = parse(&tokens);
+        let messages: Vec<(&str, u32)> = errors
+            .iter()
+            .map(|e| (e.message.as_str(), e.line_number))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                ("Expected )", 1),
+                ("Unexpected statement ']'", 2),
+                ("Function parameter expected", 3)
+            ]
+        );
+        // the statements with errors are kept as far as they go
+        assert!(matches!(
+            &statements[0],
+            Stmt::Expression(Expr::Assign { .. })
+        ));
+        assert!(matches!(
+            statements.last(),
+            Some(Stmt::Expression(Expr::Call { .. }))
+        ));
+    }
+}
@@ -0,0 +1,351 @@
+use colored::Colorize;
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::{
+    compiler::{Compiler, OpCode, Variable},
+    diagnostics, load_script_imports,
+    scanner::{self, Span, TokenType},
+    vm::{Graphics, Sandbox, SourceFile, ValueType, Vm},
+};
+
+/// An interactive session. Everything compiled so far is kept, so variables and
+/// functions defined in one input can be used in the next.
+pub struct Repl {
+    // the code of every input, each input runs a Vm over it from where its code starts
+    instructions: Vec<OpCode>,
+    line_numbers: Vec<u32>,
+    spans: Vec<Span>,
+    // where each file compiled so far starts, its name and its source
+    sources: Vec<(usize, Option<String>, String)>,
+    // the globals carry over from one input to the next. Values borrow strings from
+    // the Vm's code, so they are copied into owned values when an input finishes.
+    globals: Vec<Option<ValueType<'static>>>,
+    graphics: Graphics,
+    variables: Vec<Variable>,
+    functions: Vec<(String, u8, usize)>,
+    imported: Vec<PathBuf>,
+    max_call_depth: usize,
+    sandbox: Sandbox,
+    // set by quit()
+    exit_code: Option<i32>,
+}
+
+impl Repl {
+    pub fn new(max_call_depth: usize) -> Self {
+        Repl {
+            instructions: Vec::new(),
+            line_numbers: Vec::new(),
+            spans: Vec::new(),
+            sources: Vec::new(),
+            globals: Vec::new(),
+            graphics: Graphics::new(),
+            variables: Vec::new(),
+            functions: Vec::new(),
+            imported: Vec::new(),
+            max_call_depth,
+            sandbox: Sandbox::allow_all(),
+            exit_code: None,
+        }
+    }
+
+    /// Compiles and runs one input, returning the value of the last expression.
+    pub fn eval(&mut self, source: &str, script_path: Option<&Path>) -> Result<String, String> {
+        let tokens = match scanner::tokenize(source) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                diagnostics::print_scan_error(&e, source);
+                return Result::Err(String::from("Tokenize Error"));
+            }
+        };
+        let mut imports = match load_script_imports(&tokens, script_path) {
+            Ok(imports) => imports,
+            Err(msg) => {
+                eprintln!("Import error: {}", msg.red());
+                return Result::Err(String::from("Import Error"));
+            }
+        };
+        imports.retain(|i| !self.imported.contains(&i.path));
+
+        let start = self.instructions.len();
+        let mut compiler = Compiler::new(&tokens, &mut self.instructions, &mut self.line_numbers);
+        compiler.source = source;
+        compiler.spans = std::mem::take(&mut self.spans);
+        compiler.variables = self.variables.clone();
+        compiler.functions = self.functions.clone();
+        for import in &imports {
+            compiler.compile_import(&import.tokens, &import.name, &import.contents);
+        }
+        compiler.compile();
+        let in_error = compiler.in_error;
+        let spans = std::mem::take(&mut compiler.spans);
+        let variables = std::mem::take(&mut compiler.variables);
+        let functions = std::mem::take(&mut compiler.functions);
+        let files = std::mem::take(&mut compiler.files);
+        self.spans = spans;
+        if in_error {
+            // forget everything from this input
+            self.instructions.truncate(start);
+            self.line_numbers.truncate(start);
+            self.spans.truncate(start);
+            return Result::Err(String::from("Compile Error"));
+        }
+        self.variables = variables;
+        self.functions = functions;
+
+        let sources = imports.iter().map(|i| i.contents.clone());
+        for ((file_start, name), text) in files.into_iter().zip(sources.chain([source.to_string()]))
+        {
+            self.sources.push((file_start, name, text));
+        }
+        self.imported.extend(imports.into_iter().map(|i| i.path));
+
+        let mut vm = Vm::new(&mut self.line_numbers);
+        vm.config_file = PathBuf::from("settings.json");
+        vm.max_call_depth = self.max_call_depth;
+        vm.sandbox = self.sandbox.clone();
+        vm.function_names = self.functions.iter().map(|f| (f.2, f.0.clone())).collect();
+        vm.global_names = self.variables.iter().map(|v| v.name.clone()).collect();
+        vm.source_files = self
+            .sources
+            .iter()
+            .map(|(start, name, text)| SourceFile {
+                start: *start,
+                name: name.clone(),
+                lines: text.lines().collect(),
+            })
+            .collect();
+        vm.spans = std::mem::take(&mut self.spans);
+        vm.globals = std::mem::take(&mut self.globals);
+        std::mem::swap(&mut vm.gr, &mut self.graphics);
+
+        let ok = vm.run_from(&self.instructions, start);
+        self.exit_code = vm.exit_code;
+        self.globals = vm
+            .globals
+            .iter()
+            .map(|value| value.as_ref().map(|value| value.detach()))
+            .collect();
+        self.spans = std::mem::take(&mut vm.spans);
+        std::mem::swap(&mut vm.gr, &mut self.graphics);
+        if !ok {
+            return Result::Err(String::from("Runtime Error"));
+        }
+        match &vm.return_value {
+            Some(val) => Result::Ok(format!("{:?}", val)),
+            None => Result::Ok(String::new()),
+        }
+    }
+
+    fn print_vars(&self) {
+        for (index, variable) in self.variables.iter().enumerate() {
+            if let Some(Some(value)) = self.globals.get(index) {
+                println!("{} = {:?}", variable.name, value);
+            }
+        }
+    }
+
+    fn print_funcs(&self) {
+        for (name, arity, _) in self.functions.iter().filter(|f| f.0 != "<lambda>") {
+            println!("{name} ({arity} parameters)");
+        }
+    }
+
+    // handles a line starting with ':'
+    fn command(&mut self, line: &str) {
+        let (command, argument) = match line.split_once(' ') {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+        match command {
+            ":vars" => self.print_vars(),
+            ":funcs" => self.print_funcs(),
+            ":reset" => {
+                let sandbox = self.sandbox.clone();
+                *self = Repl::new(self.max_call_depth);
+                self.sandbox = sandbox;
+            }
+            ":load" => match fs::read_to_string(argument) {
+                Ok(contents) => {
+                    if let Ok(s) = self.eval(&contents, Some(Path::new(argument))) {
+                        println!("{}", s.bright_black());
+                    }
+                }
+                Err(e) => eprintln!("Could not load {argument}: {}", e.to_string().red()),
+            },
+            _ => {
+                println!(":vars           list variables and their values");
+                println!(":funcs          list functions");
+                println!(":load <file>    run a file, keeping its variables and functions");
+                println!(":reset          forget all variables and functions");
+            }
+        }
+    }
+}
+
+// the number of blocks (if, while, for, function etc) that are still waiting for
+// their end or next. Maps spread over several lines count too.
+fn open_blocks(tokens: &[TokenType]) -> i32 {
+    let mut open = 0;
+    let mut previous: Option<&TokenType> = None;
+    for token in tokens {
+        match token {
+            // 'end if' closes one block
+            TokenType::If(_) if matches!(previous, Some(TokenType::End(_))) => (),
+            TokenType::If(_)
+            | TokenType::While(_)
+            | TokenType::For(_)
+            | TokenType::Function(_)
+            | TokenType::Try(_)
+            | TokenType::Match(_)
+            | TokenType::LeftBrace(_) => open += 1,
+            TokenType::End(_) | TokenType::Next(_) | TokenType::RightBrace(_) => open -= 1,
+            _ => (),
+        }
+        previous = Some(token);
+    }
+    open
+}
+
+fn needs_more_input(input: &str) -> bool {
+    match scanner::tokenize(input) {
+        Ok(tokens) => open_blocks(&tokens) > 0,
+        Err(_) => false,
+    }
+}
+
+pub fn run(max_call_depth: usize, sandbox: Sandbox) {
+    println!("{}", "Very Basic Version 0.2".yellow());
+    println!("{}", "Type :help for commands".bright_black());
+    let mut repl = Repl::new(max_call_depth);
+    repl.sandbox = sandbox;
+    let mut input = String::new();
+    loop {
+        print!("{}", if input.is_empty() { "> " } else { "... " });
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        let read = io::stdin()
+            .read_line(&mut line)
+            .expect("Failed to read line");
+        if read == 0 {
+            break;
+        }
+
+        if input.is_empty() && line.trim_start().starts_with(':') {
+            repl.command(line.trim());
+            continue;
+        }
+        // a blank line runs an unfinished block anyway, so a typo can't trap you
+        let blank = line.trim().is_empty();
+        input.push_str(&line);
+        if !blank && needs_more_input(&input) {
+            continue;
+        }
+
+        match repl.eval(&input, None) {
+            Ok(s) => println!("{}", s.bright_black()),
+            Err(_) => println!(),
+        }
+        if let Some(code) = repl.exit_code {
+            process::exit(code);
+        }
+        input.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::{needs_more_input, Repl};
+    use crate::vm::{ValueType, DEFAULT_MAX_CALL_DEPTH};
+
+    #[test]
+    fn test_needs_more_input() {
+        assert!(needs_more_input("function sq(n)\n"));
+        assert!(needs_more_input(
+            "for i = 1 to 3\nif i > 1 then print(i) end\n"
+        ));
+        assert!(needs_more_input("m = {\n"));
+        assert!(!needs_more_input("if x then\n  print(x)\nend if\n"));
+        assert!(!needs_more_input("f = fn(x) x * 2 end\n"));
+    }
+
+    #[test]
+    fn test_state_kept_between_inputs() {
+        let mut repl = Repl::new(DEFAULT_MAX_CALL_DEPTH);
+        assert_eq!(repl.eval("x = 5", None), Ok(String::from("Number(5.0)")));
+        assert!(repl.eval("function sq(n)\n n * n\nend", None).is_ok());
+        assert_eq!(repl.eval("sq(x)", None), Ok(String::from("Number(25.0)")));
+        assert!(repl.eval("s = \"a\"", None).is_ok());
+        assert_eq!(
+            repl.eval("s + \"b\"", None),
+            Ok(String::from("String(\"ab\")"))
+        );
+
+        // nothing from an input that fails to compile is kept
+        assert_eq!(
+            repl.eval("y = 1 function f() 1 end oops()", None),
+            Err(String::from("Compile Error"))
+        );
+        assert_eq!(repl.eval("y", None), Err(String::from("Compile Error")));
+        assert_eq!(repl.eval("sq(2)", None), Ok(String::from("Number(4.0)")));
+    }
+
+    #[test]
+    fn test_runtime_error_keeps_session() {
+        let mut repl = Repl::new(DEFAULT_MAX_CALL_DEPTH);
+        assert!(repl.eval("a = dim(2, 7)\nname = \"vbas\"", None).is_ok());
+        assert_eq!(
+            repl.eval("x = 1 + sq(2)\nfunction sq(n) n * undefined_fn() end", None),
+            Err(String::from("Compile Error"))
+        );
+        assert_eq!(repl.eval("a[5]", None), Err(String::from("Runtime Error")));
+        assert_eq!(repl.eval("a[1]", None), Ok(String::from("Number(7.0)")));
+        assert_eq!(
+            repl.eval("name", None),
+            Ok(String::from("String(\"vbas\")"))
+        );
+        assert_eq!(repl.eval("", None), Ok(String::new()));
+    }
+
+    #[test]
+    fn test_array_changed_in_place() {
+        let mut repl = Repl::new(DEFAULT_MAX_CALL_DEPTH);
+        // the copy of a global kept between inputs isn't shared with anything, so the
+        // next input changes it without copying it again
+        let shared = |repl: &Repl| match &repl.globals[0] {
+            Some(ValueType::Array(a)) => Rc::strong_count(a) > 1,
+            _ => panic!("a should be an array"),
+        };
+        assert!(repl.eval("a = dim(3)", None).is_ok());
+        assert!(!shared(&repl));
+        assert!(repl.eval("for i = 0 to 2\n a[i] = i\nnext", None).is_ok());
+        assert!(!shared(&repl));
+        assert!(repl
+            .eval(
+                "function fill()\n a[1] = 5\n a[2] = 6\n 0\nend\nfill()",
+                None
+            )
+            .is_ok());
+        assert!(!shared(&repl));
+
+        // the value is the array before the change
+        assert_eq!(
+            repl.eval("a[0] = 9", None),
+            Ok(String::from(
+                "Array([Number(0.0), Number(5.0), Number(6.0)])"
+            ))
+        );
+        assert_eq!(
+            repl.eval("a", None),
+            Ok(String::from(
+                "Array([Number(9.0), Number(5.0), Number(6.0)])"
+            ))
+        );
+    }
+}
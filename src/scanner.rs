@@ -11,10 +11,30 @@ pub mod precedence {
     pub const CALL: u8 = 9;
 }
 
+/// Where a token is in the source. Columns start at 1, a column of 0 means the
+/// position isn't known.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Span {
+    pub column: u32,
+    pub start: usize, // byte offsets into the file
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(column: u32, start: usize, len: usize) -> Self {
+        Span {
+            column,
+            start,
+            end: start + len,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Token {
     pub lexeme: String,
     pub line_number: u32,
+    pub span: Span,
     pub precedence: u8,
 }
 
@@ -46,6 +66,9 @@ pub enum TokenType {
     RightParan(Token),
     LeftBracket(Token),
     RightBracket(Token),
+    LeftBrace(Token),
+    RightBrace(Token),
+    Colon(Token),
     Comma(Token),
     Dot(Token),
     Identifier(Token),
@@ -65,6 +88,11 @@ pub enum TokenType {
     In(Token),
     Match(Token),
     When(Token),
+    Try(Token),
+    Catch(Token),
+    Break(Token),
+    Continue(Token),
+    Import(Token),
     Eof,
 }
 
@@ -100,6 +128,9 @@ impl TokenType {
             | TokenType::String(t)
             | TokenType::LeftBracket(t)
             | TokenType::RightBracket(t)
+            | TokenType::LeftBrace(t)
+            | TokenType::RightBrace(t)
+            | TokenType::Colon(t)
             | TokenType::Number(t)
             | TokenType::Hat(t)
             | TokenType::Mod(t)
@@ -111,6 +142,11 @@ impl TokenType {
             | TokenType::In(t)
             | TokenType::Match(t)
             | TokenType::When(t)
+            | TokenType::Try(t)
+            | TokenType::Catch(t)
+            | TokenType::Break(t)
+            | TokenType::Continue(t)
+            | TokenType::Import(t)
             | TokenType::Bool(t) => Some(t),
             _ => None,
         }
@@ -122,6 +158,8 @@ fn continue_line(token_type: Option<&TokenType>) -> bool {
         Some(token_type) => match token_type {
             TokenType::LeftParan(_)
             | TokenType::RightParan(_)
+            | TokenType::LeftBrace(_)
+            | TokenType::Colon(_)
             | TokenType::Comma(_)
             | TokenType::Plus(_) => true,
             _ => false,
@@ -130,66 +168,126 @@ fn continue_line(token_type: Option<&TokenType>) -> bool {
     }
 }
 
-pub fn tokenize(code: &str) -> Result<Vec<TokenType>, &str> {
+// An open '{' is either the start of an interpolated expression inside a string,
+// or the start of a map literal.
+#[derive(PartialEq)]
+enum Brace {
+    Interpolation,
+    Map,
+}
+
+/// An error found while tokenizing, e.g. a string that is never closed
+#[derive(Debug, PartialEq)]
+pub struct ScanError {
+    pub message: &'static str,
+    pub line_number: u32,
+    pub span: Span,
+}
+
+impl std::fmt::Display for ScanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} on line {}", self.message, self.line_number)
+    }
+}
+
+// 0 for the newline itself, as line_start has already moved on to the next line
+fn column(i: usize, line_start: usize) -> u32 {
+    (i + 1).saturating_sub(line_start) as u32
+}
+
+pub fn tokenize(code: &str) -> Result<Vec<TokenType>, ScanError> {
     let mut i = 0;
     let mut line_number = 1;
+    let mut line_start = 0; // where the current line starts, for columns
     let mut tokens: Vec<TokenType> = Vec::new();
-    let mut interpolation = 0;
+    // the '{' of each open brace, so an unclosed one can be reported
+    let mut braces: Vec<(Brace, u32, Span)> = Vec::new();
+
+    let unclosed =
+        |braces: &[(Brace, u32, Span)]| match braces.iter().find(|b| b.0 == Brace::Interpolation) {
+            Some((_, line_number, span)) => Err(ScanError {
+                message: "missing '}' in string interpolation",
+                line_number: *line_number,
+                span: *span,
+            }),
+            None => Ok(()),
+        };
 
     while i < code.len() {
-        let mut current_char = code.chars().nth(i).unwrap();
+        let mut current_char = char_at(code, i).unwrap();
         if current_char == '\n' {
             if !continue_line(tokens.last()) {
                 tokens.push(TokenType::Eol(Token {
                     lexeme: String::new(),
                     line_number,
+                    span: Span::new(column(i, line_start), i, 1),
                     precedence: precedence::NONE,
                 }));
             }
 
             line_number += 1;
+            line_start = i + 1;
         }
 
         if current_char == '\'' {
-            while i < code.len() && code.chars().nth(i).unwrap() != '\n' {
-                i += 1
+            while let Some(c) = char_at(code, i).filter(|c| *c != '\n') {
+                i += c.len_utf8();
             }
             line_number += 1;
+            line_start = i + 1;
         }
         if i >= code.len() {
-            if interpolation > 0 {
-                return Err("missing '}' in string interpolation");
-            }
-            return Ok(tokens);
+            break;
+        }
+
+        // ':' and '}' only mean something inside a map literal, otherwise they are whitespace
+        if (current_char == ':' && braces.last().map(|b| &b.0) != Some(&Brace::Map))
+            || (current_char == '}' && braces.is_empty())
+        {
+            i += 1;
+            continue;
         }
 
+        let start = i;
         // ending interpolation
-        let (token, len) = if interpolation > 0 && current_char == '}' {
-            interpolation -= 1;
-            // )
-            let (t, _) = make_keyword(")", line_number);
-            tokens.push(t);
+        let (token, len) =
+            if braces.last().map(|b| &b.0) == Some(&Brace::Interpolation) && current_char == '}' {
+                braces.pop();
+                // )
+                let (t, _) = make_keyword(")", line_number, column(i, line_start), i);
+                tokens.push(t);
 
-            // +
-            let (t, _) = make_keyword("+", line_number);
-            tokens.push(t);
+                // +
+                let (t, _) = make_keyword("+", line_number, column(i, line_start), i);
+                tokens.push(t);
 
-            current_char = '"';
+                current_char = '"';
 
-            (TokenType::None, 0)
-        } else {
-            make_keyword(&code[i..], line_number)
-        };
+                (TokenType::None, 0)
+            } else {
+                match current_char {
+                    '{' => braces.push((
+                        Brace::Map,
+                        line_number,
+                        Span::new(column(i, line_start), i, 1),
+                    )),
+                    '}' => {
+                        braces.pop();
+                    }
+                    _ => (),
+                }
+                make_keyword(&code[i..], line_number, column(i, line_start), i)
+            };
 
         //let (token, len) = make_keyword(&code[i..], line_number);
         if let TokenType::None = token {
             //Numbers
-            if current_char.is_numeric() {
+            if current_char.is_ascii_digit() {
                 let mut lexeme = String::new();
                 while i < code.len() && (current_char.is_ascii_digit() || current_char == '.') {
                     lexeme.push(current_char);
-                    i += 1;
-                    if let Some(char) = code.chars().nth(i) {
+                    i += current_char.len_utf8();
+                    if let Some(char) = char_at(code, i) {
                         current_char = char;
                     } else {
                         break;
@@ -198,15 +296,18 @@ pub fn tokenize(code: &str) -> Result<Vec<TokenType>, &str> {
                 tokens.push(TokenType::Number(Token {
                     lexeme,
                     line_number,
+                    span: Span::new(column(start, line_start), start, i - start),
                     precedence: precedence::NONE,
                 }));
             // raw string (""")
             } else if start_raw_string(&code[i..]) {
                 let mut lexeme = String::new();
+                let start_line = line_number;
+                let start_column = column(i, line_start);
                 i += 2;
                 loop {
-                    i += 1;
-                    if let Some(char) = code.chars().nth(i) {
+                    i += current_char.len_utf8();
+                    if let Some(char) = char_at(code, i) {
                         current_char = char;
                     } else {
                         break;
@@ -219,47 +320,53 @@ pub fn tokenize(code: &str) -> Result<Vec<TokenType>, &str> {
                     }
                     if current_char == '\n' {
                         line_number += 1;
+                        line_start = i + 1;
                     }
                 }
+                i += 3;
                 tokens.push(TokenType::String(Token {
                     lexeme,
                     line_number,
+                    span: string_span(start_line, line_number, start_column, start, i),
                     precedence: precedence::NONE,
                 }));
 
-                i += 3;
-
             // Strings
             } else if current_char == '"' {
                 let mut lexeme = String::new();
+                let start_line = line_number;
+                let start_column = column(start, line_start);
                 loop {
-                    i += 1;
-                    if let Some(char) = code.chars().nth(i) {
+                    i += current_char.len_utf8();
+                    if let Some(char) = char_at(code, i) {
                         current_char = char;
                     } else {
                         break;
                     }
                     if current_char == '{' {
-                        interpolation += 1;
+                        let brace = Span::new(column(i, line_start), i, 1);
+                        braces.push((Brace::Interpolation, line_number, brace));
 
                         // first bit of string
                         tokens.push(TokenType::String(Token {
                             lexeme: lexeme.clone(),
                             line_number,
+                            span: string_span(start_line, line_number, start_column, start, i),
                             precedence: precedence::NONE,
                         }));
                         // plus
-                        let (t, _) = make_keyword("+", line_number);
+                        let (t, _) = make_keyword("+", line_number, brace.column, i);
                         tokens.push(t);
                         // str
                         tokens.push(TokenType::Identifier(Token {
                             lexeme: String::from("str"),
                             line_number,
+                            span: brace,
                             precedence: precedence::NONE,
                         }));
 
                         // (
-                        let (t, _) = make_keyword("(", line_number);
+                        let (t, _) = make_keyword("(", line_number, brace.column, i);
                         tokens.push(t);
 
                         break;
@@ -272,12 +379,14 @@ pub fn tokenize(code: &str) -> Result<Vec<TokenType>, &str> {
                     }
                     if current_char == '\n' {
                         line_number += 1;
+                        line_start = i + 1;
                     }
                 }
                 if current_char != '{' {
                     tokens.push(TokenType::String(Token {
                         lexeme,
                         line_number,
+                        span: string_span(start_line, line_number, start_column, start, i + 1),
                         precedence: precedence::NONE,
                     }));
                 }
@@ -296,8 +405,8 @@ pub fn tokenize(code: &str) -> Result<Vec<TokenType>, &str> {
                         || current_char.is_numeric())
                 {
                     lexeme.push(current_char);
-                    i += 1;
-                    if let Some(char) = code.chars().nth(i) {
+                    i += current_char.len_utf8();
+                    if let Some(char) = char_at(code, i) {
                         current_char = char;
                     } else {
                         break;
@@ -306,26 +415,41 @@ pub fn tokenize(code: &str) -> Result<Vec<TokenType>, &str> {
                 tokens.push(TokenType::Identifier(Token {
                     lexeme,
                     line_number,
+                    span: Span::new(column(start, line_start), start, i - start),
                     precedence: precedence::NONE,
                 }));
             } else {
-                i += 1;
+                i += current_char.len_utf8();
             }
         } else {
             tokens.push(token);
             i += len;
         }
     }
-    if interpolation > 0 {
-        return Err("missing '}' in string interpolation");
-    }
+    unclosed(&braces)?;
     tokens.push(TokenType::Eof);
     //dbg!(&tokens);
     Ok(tokens)
 }
 
+// A string token's line number is the line it ends on, so the column is only
+// known for strings on one line
+fn string_span(start_line: u32, end_line: u32, column: u32, start: usize, end: usize) -> Span {
+    if start_line == end_line {
+        Span::new(column, start, end - start)
+    } else {
+        Span::new(0, start, end - start)
+    }
+}
+
+// the character at a byte offset. The scanner moves past each character by its
+// length in utf-8, so the offset is always at the start of one.
+fn char_at(code: &str, i: usize) -> Option<char> {
+    code.get(i..)?.chars().next()
+}
+
 fn start_raw_string(code: &str) -> bool {
-    code.len() >= 3 && code[..3] == *"\"\"\""
+    code.starts_with("\"\"\"")
 }
 
 fn end_raw_string(code: &str) -> bool {
@@ -334,11 +458,11 @@ fn end_raw_string(code: &str) -> bool {
     } else {
         true
     };
-    only_three_quotes && code.len() >= 3 && code[..3] == *"\"\"\""
+    only_three_quotes && code.starts_with("\"\"\"")
 }
 
 fn is_word(code: &str, i: usize) -> bool {
-    if let Some(ch) = code.chars().nth(i) {
+    if let Some(ch) = char_at(code, i) {
         !(ch.is_ascii_alphanumeric() || ch == '_' || ch == '@')
     } else {
         true
@@ -347,15 +471,16 @@ fn is_word(code: &str, i: usize) -> bool {
 
 fn match_word(code: &str, word: &str) -> bool {
     let len = word.len();
-    code.len() >= len && &code[..len] == word && is_word(code, len)
+    code.starts_with(word) && is_word(code, len)
 }
 
-fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
+fn make_keyword(code: &str, line_number: u32, column: u32, start: usize) -> (TokenType, usize) {
     if match_word(code, "function") {
         (
             TokenType::Function(Token {
                 lexeme: String::from("function"),
                 line_number,
+                span: Span::new(column, start, 8),
                 precedence: precedence::NONE,
             }),
             8,
@@ -365,6 +490,7 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
             TokenType::ElseIf(Token {
                 lexeme: String::from("elseif"),
                 line_number,
+                span: Span::new(column, start, 6),
                 precedence: precedence::NONE,
             }),
             6,
@@ -374,6 +500,7 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
             TokenType::While(Token {
                 lexeme: String::from("while"),
                 line_number,
+                span: Span::new(column, start, 5),
                 precedence: precedence::NONE,
             }),
             5,
@@ -383,6 +510,7 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
             TokenType::Match(Token {
                 lexeme: String::from("match"),
                 line_number,
+                span: Span::new(column, start, 5),
                 precedence: precedence::NONE, // TODO: this is a guess, change to what makes sense
             }),
             5,
@@ -392,15 +520,57 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
             TokenType::When(Token {
                 lexeme: String::from("when"),
                 line_number,
+                span: Span::new(column, start, 4),
                 precedence: precedence::NONE,
             }),
             4,
         )
+    } else if match_word(code, "catch") {
+        (
+            TokenType::Catch(Token {
+                lexeme: String::from("catch"),
+                line_number,
+                span: Span::new(column, start, 5),
+                precedence: precedence::NONE,
+            }),
+            5,
+        )
+    } else if match_word(code, "break") {
+        (
+            TokenType::Break(Token {
+                lexeme: String::from("break"),
+                line_number,
+                span: Span::new(column, start, 5),
+                precedence: precedence::NONE,
+            }),
+            5,
+        )
+    } else if match_word(code, "continue") {
+        (
+            TokenType::Continue(Token {
+                lexeme: String::from("continue"),
+                line_number,
+                span: Span::new(column, start, 8),
+                precedence: precedence::NONE,
+            }),
+            8,
+        )
+    } else if match_word(code, "import") {
+        (
+            TokenType::Import(Token {
+                lexeme: String::from("import"),
+                line_number,
+                span: Span::new(column, start, 6),
+                precedence: precedence::NONE,
+            }),
+            6,
+        )
     } else if match_word(code, "exit") {
         (
             TokenType::Return(Token {
                 lexeme: String::from("exit"),
                 line_number,
+                span: Span::new(column, start, 4),
                 precedence: precedence::NONE,
             }),
             4,
@@ -410,6 +580,7 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
             TokenType::Function(Token {
                 lexeme: String::from("fn"),
                 line_number,
+                span: Span::new(column, start, 2),
                 precedence: precedence::NONE,
             }),
             2,
@@ -419,6 +590,7 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
             TokenType::Then(Token {
                 lexeme: String::from("then"),
                 line_number,
+                span: Span::new(column, start, 4),
                 precedence: precedence::NONE,
             }),
             4,
@@ -428,6 +600,7 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
             TokenType::Bool(Token {
                 lexeme: String::from("false"),
                 line_number,
+                span: Span::new(column, start, 5),
                 precedence: precedence::NONE,
             }),
             5,
@@ -437,6 +610,7 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
             TokenType::Bool(Token {
                 lexeme: String::from("true"),
                 line_number,
+                span: Span::new(column, start, 4),
                 precedence: precedence::NONE,
             }),
             4,
@@ -446,6 +620,7 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
             TokenType::Else(Token {
                 lexeme: String::from("else"),
                 line_number,
+                span: Span::new(column, start, 4),
                 precedence: precedence::NONE,
             }),
             4,
@@ -455,6 +630,7 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
             TokenType::Next(Token {
                 lexeme: String::from("next"),
                 line_number,
+                span: Span::new(column, start, 4),
                 precedence: precedence::NONE,
             }),
             4,
@@ -464,15 +640,27 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
             TokenType::Step(Token {
                 lexeme: String::from("step"),
                 line_number,
+                span: Span::new(column, start, 4),
                 precedence: precedence::NONE,
             }),
             4,
         )
+    } else if match_word(code, "try") {
+        (
+            TokenType::Try(Token {
+                lexeme: String::from("try"),
+                line_number,
+                span: Span::new(column, start, 3),
+                precedence: precedence::NONE,
+            }),
+            3,
+        )
     } else if match_word(code, "for") {
         (
             TokenType::For(Token {
                 lexeme: String::from("for"),
                 line_number,
+                span: Span::new(column, start, 3),
                 precedence: precedence::NONE,
             }),
             3,
@@ -482,6 +670,7 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
             TokenType::To(Token {
                 lexeme: String::from("to"),
                 line_number,
+                span: Span::new(column, start, 2),
                 precedence: precedence::NONE,
             }),
             2,
@@ -491,6 +680,7 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
             TokenType::And(Token {
                 lexeme: String::from("and"),
                 line_number,
+                span: Span::new(column, start, 3),
                 precedence: precedence::AND,
             }),
             3,
@@ -500,6 +690,7 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
             TokenType::Mod(Token {
                 lexeme: String::from("mod"),
                 line_number,
+                span: Span::new(column, start, 3),
                 precedence: precedence::FACTOR, // mayby up this
             }),
             3,
@@ -509,6 +700,7 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
             TokenType::Not(Token {
                 lexeme: String::from("not"),
                 line_number,
+                span: Span::new(column, start, 3),
                 precedence: precedence::NONE,
             }),
             3,
@@ -518,6 +710,7 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
             TokenType::End(Token {
                 lexeme: String::from("end"),
                 line_number,
+                span: Span::new(column, start, 3),
                 precedence: precedence::NONE,
             }),
             3,
@@ -527,6 +720,7 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
             TokenType::If(Token {
                 lexeme: String::from("if"),
                 line_number,
+                span: Span::new(column, start, 2),
                 precedence: precedence::NONE,
             }),
             2,
@@ -536,6 +730,7 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
             TokenType::Or(Token {
                 lexeme: String::from("or"),
                 line_number,
+                span: Span::new(column, start, 2),
                 precedence: precedence::OR,
             }),
             2,
@@ -545,42 +740,47 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
             TokenType::In(Token {
                 lexeme: String::from("in"),
                 line_number,
+                span: Span::new(column, start, 2),
                 precedence: precedence::COMPARISON,
             }),
             2,
         )
-    } else if code.len() >= 2 && &code[..2] == "<>" {
+    } else if code.starts_with("<>") {
         (
             TokenType::NotEquals(Token {
                 lexeme: String::from("<>"),
                 line_number,
+                span: Span::new(column, start, 2),
                 precedence: precedence::COMPARISON,
             }),
             2,
         )
-    } else if code.len() >= 2 && &code[..2] == "<=" {
+    } else if code.starts_with("<=") {
         (
             TokenType::LessThanOrEqual(Token {
                 lexeme: String::from("<="),
                 line_number,
+                span: Span::new(column, start, 2),
                 precedence: precedence::COMPARISON,
             }),
             2,
         )
-    } else if code.len() >= 2 && &code[..2] == ">=" {
+    } else if code.starts_with(">=") {
         (
             TokenType::GreaterThanOrEqual(Token {
                 lexeme: String::from(">="),
                 line_number,
+                span: Span::new(column, start, 2),
                 precedence: precedence::COMPARISON,
             }),
             2,
         )
-    } else if code.len() >= 2 && &code[..2] == "==" {
+    } else if code.starts_with("==") {
         (
             TokenType::Equality(Token {
                 lexeme: String::from("=="),
                 line_number,
+                span: Span::new(column, start, 2),
                 precedence: precedence::EQUALITY,
             }),
             2,
@@ -588,83 +788,116 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
     } else {
         // Single character tokens
 
-        let single_char = &code[..1];
+        let single_char = code.get(..1).unwrap_or_default();
 
         (
             match single_char {
                 "<" => TokenType::LessThan(Token {
                     lexeme: single_char.to_string(),
                     line_number,
+                    span: Span::new(column, start, 1),
                     precedence: precedence::COMPARISON,
                 }),
                 ">" => TokenType::GreaterThan(Token {
                     lexeme: single_char.to_string(),
                     line_number,
+                    span: Span::new(column, start, 1),
                     precedence: precedence::COMPARISON,
                 }),
                 "=" => TokenType::Equals(Token {
                     lexeme: single_char.to_string(),
                     line_number,
+                    span: Span::new(column, start, 1),
                     precedence: precedence::NONE,
                 }),
                 "+" => TokenType::Plus(Token {
                     lexeme: single_char.to_string(),
                     line_number,
+                    span: Span::new(column, start, 1),
                     precedence: precedence::TERM,
                 }),
                 "-" => TokenType::Minus(Token {
                     lexeme: single_char.to_string(),
                     line_number,
+                    span: Span::new(column, start, 1),
                     precedence: precedence::TERM,
                 }),
                 "*" => TokenType::Times(Token {
                     lexeme: single_char.to_string(),
                     line_number,
+                    span: Span::new(column, start, 1),
                     precedence: precedence::FACTOR,
                 }),
                 "/" => TokenType::Divide(Token {
                     lexeme: single_char.to_string(),
                     line_number,
+                    span: Span::new(column, start, 1),
                     precedence: precedence::FACTOR,
                 }),
                 "^" => TokenType::Hat(Token {
                     lexeme: single_char.to_string(),
                     line_number,
+                    span: Span::new(column, start, 1),
                     precedence: precedence::FACTOR,
                 }),
                 "(" => TokenType::LeftParan(Token {
                     lexeme: single_char.to_string(),
                     line_number,
+                    span: Span::new(column, start, 1),
                     precedence: precedence::CALL,
                 }),
                 ")" => TokenType::RightParan(Token {
                     lexeme: single_char.to_string(),
                     line_number,
+                    span: Span::new(column, start, 1),
                     precedence: precedence::NONE,
                 }),
                 "[" => TokenType::LeftBracket(Token {
                     lexeme: single_char.to_string(),
                     line_number,
+                    span: Span::new(column, start, 1),
                     precedence: precedence::CALL,
                 }),
                 "]" => TokenType::RightBracket(Token {
                     lexeme: single_char.to_string(),
                     line_number,
+                    span: Span::new(column, start, 1),
+                    precedence: precedence::NONE,
+                }),
+                "{" => TokenType::LeftBrace(Token {
+                    lexeme: single_char.to_string(),
+                    line_number,
+                    span: Span::new(column, start, 1),
+                    precedence: precedence::NONE,
+                }),
+                "}" => TokenType::RightBrace(Token {
+                    lexeme: single_char.to_string(),
+                    line_number,
+                    span: Span::new(column, start, 1),
+                    precedence: precedence::NONE,
+                }),
+                ":" => TokenType::Colon(Token {
+                    lexeme: single_char.to_string(),
+                    line_number,
+                    span: Span::new(column, start, 1),
                     precedence: precedence::NONE,
                 }),
                 "," => TokenType::Comma(Token {
                     lexeme: single_char.to_string(),
                     line_number,
+                    span: Span::new(column, start, 1),
                     precedence: precedence::NONE,
                 }),
                 "." => TokenType::Dot(Token {
                     lexeme: single_char.to_string(),
                     line_number,
+                    span: Span::new(column, start, 1),
                     precedence: precedence::CALL,
                 }),
                 ";" => TokenType::End(Token {
                     lexeme: single_char.to_string(),
                     line_number,
+                    span: Span::new(column, start, 1),
                     precedence: precedence::NONE,
                 }),
                 _ => TokenType::None,
@@ -673,3 +906,42 @@ fn make_keyword(code: &str, line_number: u32) -> (TokenType, usize) {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{tokenize, ScanError, Span};
+
+    #[test]
+    fn test_spans() {
+        let tokens = tokenize("x = 12\n\tprint(\"hi {x}\")").unwrap();
+        let spans: Vec<(String, u32, Span)> = tokens
+            .iter()
+            .filter_map(|t| t.get_token())
+            .map(|t| (t.lexeme.clone(), t.line_number, t.span))
+            .collect();
+        assert_eq!(spans[0], (String::from("x"), 1, Span::new(1, 0, 1)));
+        assert_eq!(spans[2], (String::from("12"), 1, Span::new(5, 4, 2)));
+        assert_eq!(spans[3], (String::from("print"), 2, Span::new(2, 8, 5)));
+        // the tokens made for the interpolation point at the brace
+        assert_eq!(spans[7], (String::from("str"), 2, Span::new(12, 18, 1)));
+
+        // columns and offsets count bytes, é is 2 and 😀 is 4
+        let tokens = tokenize("s = \"é😀\" + x ' ü").unwrap();
+        let spans: Vec<Span> = tokens
+            .iter()
+            .filter_map(|t| t.get_token())
+            .map(|t| t.span)
+            .collect();
+        assert_eq!(spans[2], Span::new(5, 4, 8));
+        assert_eq!(spans[4], Span::new(16, 15, 1));
+
+        assert_eq!(
+            tokenize("x = 1\ny = \"{x\"").err(),
+            Some(ScanError {
+                message: "missing '}' in string interpolation",
+                line_number: 2,
+                span: Span::new(6, 11, 1),
+            })
+        );
+    }
+}
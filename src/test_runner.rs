@@ -0,0 +1,187 @@
+use colored::Colorize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{compile_script, interpret, RunOptions};
+
+/// The number of tests that passed and failed
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub passed: usize,
+    pub failed: usize,
+}
+
+// Finds the *_test.vbas files in a directory and the directories under it, in order
+// of their paths. Hidden directories (like .git) are skipped.
+fn find_test_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+        Err(e) => {
+            eprintln!("Could not read {}: {}", dir.display(), e.to_string().red());
+            return;
+        }
+    };
+    entries.sort();
+    for path in entries {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if path.is_dir() {
+            if !name.starts_with('.') {
+                find_test_files(&path, files);
+            }
+        } else if name.ends_with("_test.vbas") {
+            files.push(path);
+        }
+    }
+}
+
+// The test_ functions defined in a script (not the files it imports), in the order
+// they are defined. Compile errors have been printed when this returns Err.
+fn find_tests(contents: &str, options: &RunOptions) -> Result<Vec<String>, String> {
+    let (program, _) = compile_script(contents, options)?;
+    // the main script is compiled after its imports
+    let script_start = program.files.last().map_or(0, |f| f.0);
+    let mut tests: Vec<&(usize, String)> = program
+        .function_names
+        .iter()
+        .filter(|f| f.0 >= script_start && f.1.starts_with("test_"))
+        .collect();
+    tests.sort();
+    Ok(tests.into_iter().map(|f| f.1.clone()).collect())
+}
+
+// paths are shown without the ./ when looking in the current directory
+fn display(path: &Path) -> String {
+    path.strip_prefix(".").unwrap_or(path).display().to_string()
+}
+
+// Runs each test in a file in a Vm of its own. The code outside the functions runs
+// before every test, then the test function is called.
+fn run_file(path: &Path, summary: &mut Summary) {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", path.display(), e.to_string().red());
+            summary.failed += 1;
+            return;
+        }
+    };
+    let options = RunOptions {
+        script_path: Some(path.to_path_buf()),
+        ..Default::default()
+    };
+    let tests = match find_tests(&contents, &options) {
+        Ok(tests) => tests,
+        Err(_) => {
+            println!("test {} ... {}", display(path), "FAILED".red());
+            summary.failed += 1;
+            return;
+        }
+    };
+    for test in tests {
+        let script = format!("{contents}\n{test}()\n");
+        let passed = match interpret(&script, path.with_extension("vbas.json"), &options) {
+            Ok(finished) => finished.exit_code == 0,
+            Err(_) => false,
+        };
+        let result = if passed {
+            summary.passed += 1;
+            "ok".green()
+        } else {
+            summary.failed += 1;
+            "FAILED".red()
+        };
+        println!("test {}::{test} ... {result}", display(path));
+    }
+}
+
+/// Runs the tests in the *_test.vbas files in a directory, or in one file
+pub fn run_tests(path: &Path) -> Summary {
+    let mut files = Vec::new();
+    if path.is_dir() {
+        find_test_files(path, &mut files);
+    } else {
+        files.push(path.to_path_buf());
+    }
+    let mut summary = Summary::default();
+    for file in files {
+        run_file(&file, &mut summary);
+    }
+    summary
+}
+
+/// `vbas test`, returns the exit code
+pub fn run(path: &Path) -> i32 {
+    let summary = run_tests(path);
+    let result = if summary.failed == 0 {
+        "ok".green()
+    } else {
+        "FAILED".red()
+    };
+    println!(
+        "\ntest result: {result}. {} passed; {} failed",
+        summary.passed, summary.failed
+    );
+    if summary.failed == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_tests, Summary};
+    use std::fs;
+
+    #[test]
+    fn test_run_tests() {
+        let dir = std::env::temp_dir().join(format!("vbas_test_runner_{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(
+            dir.join("lib").join("maths.vbas"),
+            "function test_not_in_a_test_file() assert(false) end\nfunction add(a, b) a + b end",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("maths_test.vbas"),
+            r#"import "lib/maths.vbas"
+total = 10
+function test_add()
+    assert_eq(add(1, 2), 3)
+end
+function test_globals_are_reset()
+    total = total + 1
+    assert(total == 11, "total is {total}")
+end
+function test_again()
+    total = total + 1
+    assert_eq(total, 11)
+end
+function test_fails()
+    assert_eq(add("a", "b"), "abc")
+end
+function helper()
+    assert(false)
+end
+"#,
+        )
+        .unwrap();
+        fs::write(dir.join("broken_test.vbas"), "function test_x()\n").unwrap();
+        fs::write(
+            dir.join("notes.vbas"),
+            "function test_skipped() assert(false) end",
+        )
+        .unwrap();
+
+        assert_eq!(
+            run_tests(&dir),
+            Summary {
+                passed: 3,
+                failed: 2
+            }
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
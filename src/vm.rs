@@ -1,27 +1,41 @@
 mod array_functions;
+mod conversions;
 mod functions;
 mod graphics;
+mod map_functions;
+mod sandbox;
 mod string_functions;
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     io::{self, Write},
     path::PathBuf,
     process::Command,
+    rc::Rc,
+    time::{Duration, Instant},
 };
 
 use crate::compiler::{OpCode, Operator, VarType};
+use crate::diagnostics;
+use crate::scanner::Span;
 use colored::Colorize;
+pub use graphics::Graphics;
+pub use sandbox::Sandbox;
 
 #[derive(Debug, Clone)]
 pub enum ValueType<'a> {
     Number(f64),
     Str(&'a str),
     Boolean(bool),
-    String(String),
-    Array(Vec<ValueType<'a>>),
+    // strings and arrays are shared when copied, and an array is only copied when a
+    // shared one is changed (see Rc::make_mut), so passing them around is cheap
+    String(Rc<str>),
+    Array(Rc<Vec<ValueType<'a>>>),
+    Map(BTreeMap<String, ValueType<'a>>),
     Func(usize, u8),
+    Closure(usize, u8, Vec<ValueType<'a>>), // pointer, arity, captured values
     Native(usize),
+    Host(usize), // index into Vm::host_functions
 }
 
 impl ValueType<'_> {
@@ -32,9 +46,54 @@ impl ValueType<'_> {
             ValueType::Str(str) => str.to_string(),
             ValueType::String(str) => str.to_string(),
             ValueType::Array(a) => format!("{:?}", a),
+            ValueType::Map(m) => {
+                let entries: Vec<String> = m
+                    .iter()
+                    .map(|(key, value)| format!("{key:?}: {}", value.literal()))
+                    .collect();
+                format!("{{{}}}", entries.join(", "))
+            }
             _ => String::from("function"),
         }
     }
+
+    // the value as it would be written in a script, with strings quoted, for showing
+    // the values in a map
+    fn literal(&self) -> String {
+        match self {
+            ValueType::Str(s) => format!("{s:?}"),
+            ValueType::String(s) => format!("{s:?}"),
+            ValueType::Array(a) => {
+                let items: Vec<String> = a.iter().map(|v| v.literal()).collect();
+                format!("[{}]", items.join(", "))
+            }
+            _ => self.to_string(),
+        }
+    }
+
+    // copies any strings borrowed from the instructions, so the value can outlive them
+    pub fn detach(&self) -> ValueType<'static> {
+        match self {
+            ValueType::Number(n) => ValueType::Number(*n),
+            ValueType::Str(s) => ValueType::String((*s).into()),
+            ValueType::Boolean(b) => ValueType::Boolean(*b),
+            ValueType::String(s) => ValueType::String(s.clone()),
+            ValueType::Array(a) => {
+                ValueType::Array(Rc::new(a.iter().map(|v| v.detach()).collect()))
+            }
+            ValueType::Map(m) => {
+                ValueType::Map(m.iter().map(|(k, v)| (k.clone(), v.detach())).collect())
+            }
+            ValueType::Func(pointer, arity) => ValueType::Func(*pointer, *arity),
+            ValueType::Closure(pointer, arity, captured) => ValueType::Closure(
+                *pointer,
+                *arity,
+                captured.iter().map(|v| v.detach()).collect(),
+            ),
+            ValueType::Native(index) => ValueType::Native(*index),
+            ValueType::Host(index) => ValueType::Host(*index),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -42,34 +101,66 @@ struct Frame {
     ip: usize,
     frame_pointer: usize,
     offset: u8,
+    function: Option<usize>, // start of the function being run, None for top level code
+}
+
+// A try block that is currently running
+struct Handler {
+    catch_ip: usize,
+    stack_pointer: usize,
+    call_depth: usize,
 }
 
-const MAX_STACK: usize = 128;
+const TOP_LEVEL_FRAME: Frame = Frame {
+    ip: 0,
+    frame_pointer: 0,
+    offset: 0,
+    function: None,
+};
+
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10000;
+
+// A function called back from a native like map() runs in another execute on the
+// Rust stack, which runs out long before max_call_depth, so these are counted too
+const MAX_CALLBACK_DEPTH: usize = 100;
+
+/// The most arguments of a native that takes any number of them, e.g. array()
+pub const VARIADIC: u8 = u8::MAX;
+
+/// A function provided by the program running the script. Arguments are copied so
+/// they don't borrow from the script, and an error becomes a runtime error.
+pub type HostFunction =
+    Box<dyn FnMut(Vec<ValueType<'static>>) -> Result<ValueType<'static>, String>>;
 
 fn system_command<'a>(
-    command: &'a String,
+    command: &str,
     params: Vec<ValueType<'a>>,
-) -> Result<ValueType<'a>, &'a str> {
+    sandbox: &Sandbox,
+) -> Result<ValueType<'a>, String> {
+    let command = command.trim_start_matches('@');
+    sandbox.check_system(command)?;
     let mut args: Vec<String> = Vec::new();
     for param in params {
         args.push(param.to_string());
     }
 
-    let first_char = command.chars().nth(0).unwrap();
-
-    let output = if first_char == '@' {
-        let command = &command[1..];
-        Command::new(command).args(args).output()
-    } else {
-        Command::new(command).args(args).output()
+    let output = match Command::new(command).args(args).output() {
+        Ok(output) => output,
+        Err(e) => return Result::Err(format!("Failed to run system command {command}: {e}")),
     };
-
-    if let Ok(output) = output {
-        let result = String::from_utf8_lossy(&output.stdout).to_string();
-        Result::Ok(ValueType::String(result))
-    } else {
-        Result::Err("Failed to run system command")
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let status = match output.status.code() {
+            Some(code) => format!("exit code {code}"),
+            None => String::from("no exit code"),
+        };
+        return Result::Err(format!(
+            "System command {command} failed with {status}: {}",
+            stderr.trim()
+        ));
     }
+    let result = String::from_utf8_lossy(&output.stdout).to_string();
+    Result::Ok(ValueType::String(result.into()))
 }
 
 fn string_compare<'a>(op: &OpCode, a: &str, b: &str) -> ValueType<'a> {
@@ -84,12 +175,10 @@ fn string_compare<'a>(op: &OpCode, a: &str, b: &str) -> ValueType<'a> {
     }
 }
 
-const EMPTY_ELEMENT: ValueType = ValueType::Boolean(false);
-
 macro_rules! pop {
     ($s:ident, $v:ident) => {
         $s.stack_pointer -= 1;
-        let $v = &$s.stack[$s.stack_pointer];
+        let $v = &$s.take($s.stack_pointer);
     };
 }
 
@@ -100,6 +189,13 @@ macro_rules! pop_mut {
     };
 }
 
+// The source of one file and the index of the first instruction compiled from it
+pub struct SourceFile<'a> {
+    pub start: usize,
+    pub name: Option<String>, // None for the main script
+    pub lines: Vec<&'a str>,
+}
+
 pub struct DebugSettings {
     pub break_points: Vec<u32>,
     pub code_window: u32,
@@ -129,17 +225,44 @@ enum DebugStep {
 }
 
 pub struct Vm<'a> {
-    stack: [ValueType<'a>; MAX_STACK],
+    stack: Vec<ValueType<'a>>,
     stack_pointer: usize,
-    globals: HashMap<u32, ValueType<'a>>,
+    // indexed by the compiler's global variable index, None until the global is set
+    pub globals: Vec<Option<ValueType<'a>>>,
+    // the name of each global, there is a slot in globals for each one
+    pub global_names: Vec<String>,
+    pub function_names: HashMap<usize, String>,
+    pub max_call_depth: usize,
+    // optional limits on how long a script can run, see check_limits
+    pub max_instructions: Option<u64>,
+    pub timeout: Option<Duration>,
+    instruction_count: u64,
+    deadline: Option<Instant>,
+    limit_exceeded: bool,
+    // set by quit(), the exit code the process should end with
+    pub exit_code: Option<i32>,
     pub return_value: Option<ValueType<'a>>,
-    gr: graphics::Graphics,
+    // indexed by OpCode::Host and OpCode::CallHost, in the order of Compiler::host_functions
+    pub host_functions: Vec<HostFunction>,
+    pub sandbox: Sandbox,
+    // what initgraphics and plot have drawn, the REPL keeps it from one input to the next
+    pub gr: Graphics,
     line_numbers: &'a mut Vec<u32>,
-    ip: usize,
+    frame: Frame,
+    call_frames: Vec<Frame>,
+    handlers: Vec<Handler>,
+    // execute stops when a return drops below this many call frames, used by call_function
+    call_boundary: usize,
+    // how many call_bytecode calls are running, see MAX_CALLBACK_DEPTH
+    callback_depth: usize,
+    instructions: Option<&'a Vec<OpCode>>,
     pub config_file: PathBuf,
     in_error: bool,
-    // for debugging
-    source_code: Option<&'a Vec<&'a str>>,
+    error_message: Option<String>,
+    // for error messages and debugging
+    pub source_files: Vec<SourceFile<'a>>,
+    // the span of the code each instruction came from, empty when there is no source
+    pub spans: Vec<Span>,
     debug_settings: Option<DebugSettings>,
     step: DebugStep,
     break_frame: usize,
@@ -148,123 +271,420 @@ pub struct Vm<'a> {
 impl<'a> Vm<'a> {
     pub fn new(line_numbers: &'a mut Vec<u32>) -> Self {
         Vm {
-            stack: [EMPTY_ELEMENT; MAX_STACK],
-            globals: HashMap::new(),
+            stack: Vec::new(),
+            globals: Vec::new(),
+            global_names: Vec::new(),
+            function_names: HashMap::new(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            max_instructions: None,
+            timeout: None,
+            instruction_count: 0,
+            deadline: None,
+            limit_exceeded: false,
+            exit_code: None,
             return_value: Option::None,
-            gr: graphics::Graphics::new(),
+            host_functions: Vec::new(),
+            sandbox: Sandbox::allow_all(),
+            gr: Graphics::new(),
             stack_pointer: 0,
-            ip: 0,
+            frame: TOP_LEVEL_FRAME,
+            call_frames: Vec::new(),
+            handlers: Vec::new(),
+            call_boundary: 0,
+            callback_depth: 0,
+            instructions: None,
             line_numbers,
             config_file: PathBuf::from("settings.txt"),
             in_error: false,
-            source_code: None,
+            error_message: None,
+            source_files: Vec::new(),
+            spans: Vec::new(),
             debug_settings: None,
             step: DebugStep::Continue,
             break_frame: 0,
         }
     }
 
-    pub fn new_debug(
-        line_numbers: &'a mut Vec<u32>,
-        source_code: &'a Vec<&'a str>,
-        settings: DebugSettings,
-    ) -> Self {
+    pub fn new_debug(line_numbers: &'a mut Vec<u32>, settings: DebugSettings) -> Self {
         Vm {
-            stack: [EMPTY_ELEMENT; MAX_STACK],
+            stack: Vec::new(),
             //value_pointers: [NO_POINTER; MAX_STACK],
-            globals: HashMap::new(),
+            globals: Vec::new(),
+            global_names: Vec::new(),
+            function_names: HashMap::new(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            max_instructions: None,
+            timeout: None,
+            instruction_count: 0,
+            deadline: None,
+            limit_exceeded: false,
+            exit_code: None,
             return_value: Option::None,
-            gr: graphics::Graphics::new(),
+            host_functions: Vec::new(),
+            sandbox: Sandbox::allow_all(),
+            gr: Graphics::new(),
             stack_pointer: 0,
-            ip: 0,
+            frame: TOP_LEVEL_FRAME,
+            call_frames: Vec::new(),
+            handlers: Vec::new(),
+            call_boundary: 0,
+            callback_depth: 0,
+            instructions: None,
             line_numbers,
             config_file: PathBuf::from("settings.txt"),
             in_error: false,
-            source_code: Some(source_code),
+            error_message: None,
+            source_files: Vec::new(),
+            spans: Vec::new(),
             debug_settings: Some(settings),
             step: DebugStep::Continue,
             break_frame: 0,
         }
     }
 
+    // function, name, and the fewest and most arguments it takes, not counting the
+    // variable the method is called on
     pub const MUT_NATIVES: [(
         fn(array: &mut ValueType<'a>, params: Vec<ValueType<'a>>) -> Result<ValueType<'a>, &'a str>,
-        &str,
+        &'static str,
+        u8,
+        u8,
     ); 3] = [
-        (array_functions::push_mut, "push"),
-        (array_functions::slice, "slice"),
-        (array_functions::filter, "filter"),
+        (array_functions::push_mut, "push", 1, 1),
+        (array_functions::slice, "slice", 2, 2),
+        (map_functions::remove_mut, "remove", 1, 1),
     ];
 
+    // function, name, the fewest and most arguments it takes (the compiler checks
+    // calls against these) and its parameters, which the language server shows
     pub const NATIVES: [(
         fn(Vec<ValueType<'a>>, &mut Vm<'a>) -> Result<ValueType<'a>, &'a str>,
-        &str,
-    ); 44] = [
-        (functions::print, "print"),
-        (functions::input, "input"),
-        (array_functions::array, "array"),
-        (functions::len, "len"),
-        (functions::seconds, "seconds"),
-        (functions::dir, "dir"),
-        (functions::readlines, "readlines"),
-        (functions::random, "rand"),
-        (functions::rgb, "rgb"),
-        (string_functions::mid, "mid"),
-        (string_functions::left, "left"),
-        (functions::floor, "floor"),
-        (string_functions::str, "str"),
-        (functions::write, "write"),
-        (functions::append, "append"),
-        (functions::chr, "chr"),
-        (functions::val, "val"),
-        (string_functions::right, "right"),
-        (string_functions::ucase, "ucase"),
-        (string_functions::lcase, "lcase"),
-        (string_functions::instr, "instr"),
-        (string_functions::split, "split"),
-        (string_functions::replace, "replace"),
-        (functions::command, "command"),
-        (functions::now, "now"),
-        (functions::window, "window"),
-        (functions::plot, "plot"),
-        (functions::clear_graphics, "cleargraphics"),
-        (functions::init_graphics, "initgraphics"),
-        (functions::setting_set, "setting_set"),
-        (functions::setting_get, "setting_get"),
-        (functions::stack, "stack"),
-        (array_functions::sort, "sort"),
-        (array_functions::push, "push"),
-        (array_functions::dim, "dim"),
-        (array_functions::max, "max"),
-        (array_functions::find, "find"),
-        (array_functions::shuffle, "shuffle"),
-        (functions::sqrt, "sqrt"),
-        (functions::date_add, "dateadd"),
-        (functions::round, "round"),
-        (functions::clear, "clear"),
-        (functions::asc, "asc"),
-        (functions::sleep, "sleep"),
+        &'static str,
+        u8,
+        u8,
+        &'static str,
+    ); 59] = [
+        (
+            functions::print,
+            "print",
+            1,
+            5,
+            "string, [newline=true], [colour=\"\"]",
+        ),
+        (functions::input, "input", 0, 1, "[prompt=\"\"]"),
+        (
+            array_functions::array,
+            "array",
+            0,
+            VARIADIC,
+            "[element],...",
+        ),
+        (functions::len, "len", 1, 1, "value"),
+        (functions::seconds, "seconds", 0, 0, ""),
+        (functions::dir, "dir", 1, 1, "pattern"),
+        (functions::readlines, "readlines", 1, 1, "filename"),
+        (functions::random, "rand", 0, 0, ""),
+        (functions::rgb, "rgb", 3, 3, "red, green, blue"),
+        (
+            string_functions::mid,
+            "mid",
+            2,
+            3,
+            "string, start, [length]",
+        ),
+        (string_functions::left, "left", 2, 2, "string, length"),
+        (functions::floor, "floor", 1, 1, "number"),
+        (string_functions::str, "str", 1, 2, "value, [format_string]"),
+        (functions::write, "write", 2, 2, "filename, text"),
+        (functions::append, "append", 2, 2, "filename, text"),
+        (functions::chr, "chr", 1, 1, "ascii_value"),
+        (functions::val, "val", 1, 1, "string"),
+        (string_functions::right, "right", 2, 2, "string, length"),
+        (string_functions::ucase, "ucase", 1, 1, "string"),
+        (string_functions::lcase, "lcase", 1, 1, "string"),
+        (
+            string_functions::instr,
+            "instr",
+            2,
+            4,
+            "string1, string2, [start], [compare]",
+        ),
+        (
+            string_functions::split,
+            "split",
+            2,
+            3,
+            "string, delimiter, [remove_empty=false]",
+        ),
+        (
+            string_functions::replace,
+            "replace",
+            3,
+            3,
+            "string, search, replace",
+        ),
+        (functions::command, "command", 0, 0, ""),
+        (functions::now, "now", 0, 0, ""),
+        (functions::window, "window", 0, 0, ""),
+        (functions::plot, "plot", 3, 3, "x, y, colour"),
+        (functions::clear_graphics, "cleargraphics", 0, 0, ""),
+        (
+            functions::init_graphics,
+            "initgraphics",
+            2,
+            2,
+            "width, height",
+        ),
+        (functions::setting_set, "setting_set", 2, 2, "key, value"),
+        (functions::setting_get, "setting_get", 1, 1, "key"),
+        (functions::stack, "stack", 0, 0, ""),
+        (array_functions::sort, "sort", 1, 1, "array"),
+        (array_functions::push, "push", 2, 2, "array, val"),
+        (array_functions::dim, "dim", 1, 2, "size, [value]"),
+        (array_functions::max, "max", 1, 1, "array"),
+        (array_functions::find, "find", 2, 2, "array, item"),
+        (array_functions::shuffle, "shuffle", 1, 1, "array"),
+        (functions::sqrt, "sqrt", 1, 1, "num"),
+        (
+            functions::date_add,
+            "dateadd",
+            3,
+            3,
+            "date, interval, number",
+        ),
+        (functions::round, "round", 2, 2, "num, precision"),
+        (functions::clear, "clear", 0, 0, ""),
+        (functions::asc, "asc", 1, 1, "string"),
+        (functions::sleep, "sleep", 1, 1, "milliseconds"),
+        (map_functions::keys, "keys", 1, 1, "map"),
+        (map_functions::values, "values", 1, 1, "map"),
+        (map_functions::haskey, "haskey", 2, 2, "map, key"),
+        (map_functions::remove, "remove", 2, 2, "map, key"),
+        (functions::throw, "throw", 1, 1, "message"),
+        (
+            array_functions::filter,
+            "filter",
+            2,
+            VARIADIC,
+            "array, function",
+        ),
+        (array_functions::map, "map", 2, 2, "array, function"),
+        (
+            array_functions::reduce,
+            "reduce",
+            3,
+            3,
+            "array, function, initial",
+        ),
+        (array_functions::sortby, "sortby", 2, 2, "array, function"),
+        (array_functions::any, "any", 2, 2, "array, function"),
+        (array_functions::all, "all", 2, 2, "array, function"),
+        (functions::exec, "exec", 1, 3, "command, [args], [stdin]"),
+        (functions::quit, "quit", 0, 1, "[code=0]"),
+        (functions::assert, "assert", 1, 2, "cond, [msg]"),
+        (functions::assert_eq, "assert_eq", 2, 2, "a, b"),
     ];
 
+    /// The message of the error that stopped the script
+    pub fn error_message(&self) -> Option<&str> {
+        self.error_message.as_deref()
+    }
+
+    // raises a runtime error if the sandbox doesn't allow something a native is about
+    // to do. The error is already set, so the native returns the empty message.
+    pub fn permit(&mut self, check: Result<(), String>) -> Result<(), &'a str> {
+        check.map_err(|message| {
+            self.runtime_error(&message);
+            ""
+        })
+    }
+
+    // the REPL compiles each input straight into the line numbers of its Vm
+    pub fn line_numbers_mut(&mut self) -> &mut Vec<u32> {
+        self.line_numbers
+    }
+
     pub fn debug_stack(&mut self) {
-        dbg!(&self.stack[0..self.stack_pointer + 1]);
+        dbg!(&self.stack[0..self.stack_pointer]);
+    }
+
+    // a global that is read before it is set, e.g. by a function called while the
+    // global's first value is worked out
+    fn global_error(&mut self, index: usize) {
+        let message = match self.global_names.get(index) {
+            Some(name) => format!("Global variable {name} does not exist."),
+            None => format!("Global variable {index} does not exist."),
+        };
+        self.runtime_error(&message);
     }
 
-    fn runtime_error(&mut self, message: &str) {
-        let line_number = self.line_numbers[self.ip];
-        eprintln!("Runtime error: {} in line {line_number}", message.red());
+    pub fn runtime_error(&mut self, message: &str) {
+        if self.in_error {
+            return;
+        }
+        self.error_message = Some(message.to_string());
         self.in_error = true;
     }
 
+    fn report_error(&self) {
+        let message = match &self.error_message {
+            Some(message) => message.as_str(),
+            None => "",
+        };
+        let location = self.location(self.frame.ip);
+        eprintln!("Runtime error: {} in {location}", message.red());
+        let ip = self.frame.ip;
+        let line_number = self.line_numbers[ip];
+        let line = self
+            .source_file(ip)
+            .and_then(|file| file.lines.get((line_number as usize).checked_sub(1)?));
+        if let Some(line) = line {
+            let span = self.spans.get(ip).copied().unwrap_or_default();
+            eprintln!("{}", diagnostics::excerpt(line, line_number, span));
+        }
+        eprintln!("Traceback (most recent call first):");
+        for line in self.traceback() {
+            eprintln!("{line}");
+        }
+    }
+
+    /// The call stack where the last runtime error happened, innermost frame first.
+    /// Calls made from the same line over and over (i.e. recursion) are collapsed into
+    /// one line.
+    pub fn traceback(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let frames = std::iter::once(&self.frame).chain(self.call_frames.iter().rev());
+        let mut previous = String::new();
+        let mut repeated = 0;
+        for frame in frames {
+            let line = self.describe_frame(frame);
+            if line == previous {
+                repeated += 1;
+                continue;
+            }
+            if repeated > 0 {
+                lines.push(format!("    ... repeated {repeated} more times"));
+                repeated = 0;
+            }
+            lines.push(line.clone());
+            previous = line;
+        }
+        if repeated > 0 {
+            lines.push(format!("    ... repeated {repeated} more times"));
+        }
+        lines
+    }
+
+    fn describe_frame(&self, frame: &Frame) -> String {
+        let name = match frame.function {
+            Some(pointer) => self.function_name(pointer),
+            None => "<script>",
+        };
+        let line_number = self.line_numbers[frame.ip];
+        let source = match self.source_file(frame.ip) {
+            Some(file) if line_number > 0 => match file.lines.get(line_number as usize - 1) {
+                Some(line) => line.trim(),
+                None => "",
+            },
+            _ => "",
+        };
+        let location = self.location(frame.ip);
+        format!("  in {name}, {location}: {}", source.bright_black())
+    }
+
+    fn source_file(&self, ip: usize) -> Option<&SourceFile<'a>> {
+        self.source_files.iter().rev().find(|f| f.start <= ip)
+    }
+
+    // the line of an instruction, plus the file name if it came from an imported file
+    fn location(&self, ip: usize) -> String {
+        let line_number = self.line_numbers[ip];
+        match self.source_file(ip) {
+            Some(SourceFile {
+                name: Some(name), ..
+            }) => format!("line {line_number} of {name}"),
+            _ => format!("line {line_number}"),
+        }
+    }
+
     fn push(&mut self, value: ValueType<'a>) {
-        if self.stack_pointer >= MAX_STACK {
-            self.runtime_error("Stack Overflow");
-            return;
+        if self.stack_pointer < self.stack.len() {
+            self.stack[self.stack_pointer] = value;
+        } else {
+            self.stack.push(value);
         }
-        self.stack[self.stack_pointer] = value;
         self.stack_pointer += 1;
     }
 
+    // moves a value out of the stack, so the dead slot doesn't keep an array shared
+    // and make the next change to it copy the whole array
+    fn take(&mut self, slot: usize) -> ValueType<'a> {
+        std::mem::replace(&mut self.stack[slot], ValueType::Boolean(false))
+    }
+
+    fn function_name(&self, pointer: usize) -> &str {
+        match self.function_names.get(&pointer) {
+            Some(name) => name,
+            None => "<unknown>",
+        }
+    }
+
+    /// Stops the script at any depth of function calls. It unwinds like an error,
+    /// but try blocks don't catch it and run returns true.
+    pub fn quit(&mut self, code: i32) {
+        self.exit_code = Some(code);
+        self.in_error = true;
+    }
+
+    /// True if the script was stopped by max_instructions or timeout
+    pub fn limit_exceeded(&self) -> bool {
+        self.limit_exceeded
+    }
+
+    // stops a script that has run too many instructions or for too long. These errors
+    // can't be caught, otherwise a try block inside an endless loop would never stop.
+    fn check_limits(&mut self) -> bool {
+        self.instruction_count += 1;
+        if let Some(max) = self.max_instructions {
+            if self.instruction_count > max {
+                self.runtime_error(&format!("Instruction limit of {max} exceeded"));
+                self.limit_exceeded = true;
+                return false;
+            }
+        }
+        // reading the clock is slow compared to an instruction, so only check it now and then
+        if let Some(deadline) = self.deadline {
+            if self.instruction_count.is_multiple_of(1024) && Instant::now() >= deadline {
+                let seconds = self.timeout.unwrap_or_default().as_secs_f64();
+                self.runtime_error(&format!("Time limit of {seconds}s exceeded"));
+                self.limit_exceeded = true;
+                return false;
+            }
+        }
+        true
+    }
+
+    fn check_call_depth(&mut self, depth: usize, pointer: usize) -> bool {
+        if depth >= self.max_call_depth {
+            let message = format!("stack overflow in function {}", self.function_name(pointer));
+            self.runtime_error(&message);
+            return false;
+        }
+        true
+    }
+
+    fn check_callback_depth(&mut self, pointer: usize) -> bool {
+        if self.callback_depth >= MAX_CALLBACK_DEPTH {
+            let message = format!(
+                "stack overflow in function {}, too many calls from inside map, filter etc.",
+                self.function_name(pointer)
+            );
+            self.runtime_error(&message);
+            return false;
+        }
+        true
+    }
+
     fn comparison(&mut self, op: &OpCode) -> bool {
         //dbg!(&self.stack[0..self.stack_pointer]);
         pop!(self, b);
@@ -300,22 +720,52 @@ impl<'a> Vm<'a> {
             }
             ValueType::Str(a) => match b {
                 ValueType::Str(b) => string_compare(&op, a, b), // ValueType::Boolean(a == b),
-                ValueType::String(b) => string_compare(&op, a, b.as_str()), //ValueType::Boolean(a == b),
+                ValueType::String(b) => string_compare(&op, a, b), //ValueType::Boolean(a == b),
                 _ => ValueType::Boolean(false),
             },
             ValueType::String(a) => match b {
-                ValueType::Str(b) => string_compare(&op, a.as_str(), b), //ValueType::Boolean(a == b),
-                ValueType::String(b) => string_compare(&op, a.as_str(), b.as_str()), //ValueType::Boolean(a == b),
+                ValueType::Str(b) => string_compare(&op, a, b), //ValueType::Boolean(a == b),
+                ValueType::String(b) => string_compare(&op, a, b), //ValueType::Boolean(a == b),
                 _ => ValueType::Boolean(false),
             },
             ValueType::Boolean(a) => match b {
                 ValueType::Boolean(b) => ValueType::Boolean(a == b),
                 _ => ValueType::Boolean(false),
             },
+            ValueType::Array(_) | ValueType::Map(_) => match op {
+                OpCode::Equal => ValueType::Boolean(Self::values_equal(a, b)),
+                OpCode::NotEqual => ValueType::Boolean(!Self::values_equal(a, b)),
+                _ => ValueType::Boolean(false),
+            },
             _ => ValueType::Boolean(false),
         }
     }
 
+    // element by element equality, used for arrays and maps. Map values can be arrays,
+    // so arrays are compared this way too, and `==` on two arrays agrees with how they
+    // compare inside a map
+    pub(crate) fn values_equal(a: &ValueType, b: &ValueType) -> bool {
+        match (a, b) {
+            (ValueType::Array(a), ValueType::Array(b)) => {
+                a.len() == b.len()
+                    && a.iter()
+                        .zip(b.iter())
+                        .all(|(a, b)| Self::values_equal(a, b))
+            }
+            (ValueType::Map(a), ValueType::Map(b)) => {
+                a.len() == b.len()
+                    && a.iter().all(|(key, a)| match b.get(key) {
+                        Some(b) => Self::values_equal(a, b),
+                        None => false,
+                    })
+            }
+            _ => matches!(
+                Self::do_comparison(&OpCode::Equal, a, b),
+                ValueType::Boolean(true)
+            ),
+        }
+    }
+
     // fn do_comparison(a: &ValueType, b: &ValueType) -> Result<bool, &'a str> {
     //     let result = match a {
     //         ValueType::Number(a) => {
@@ -446,6 +896,10 @@ impl<'a> Vm<'a> {
                 self.runtime_error("'not' invalid for an array");
                 return false;
             }
+            ValueType::Map(_) => {
+                self.runtime_error("'not' invalid for a map");
+                return false;
+            }
             _ => {
                 self.runtime_error("'not' invalid opertation for this type");
                 return false;
@@ -480,7 +934,7 @@ impl<'a> Vm<'a> {
                         return false;
                     }
                 };
-                ValueType::String(joined.clone())
+                ValueType::String(joined.into())
             }
             ValueType::String(a) => {
                 let joined = match b {
@@ -491,7 +945,7 @@ impl<'a> Vm<'a> {
                         return false;
                     }
                 };
-                ValueType::String(joined.clone())
+                ValueType::String(joined.into())
             }
             ValueType::Boolean(_) => {
                 self.runtime_error("Cannot add a boolean");
@@ -502,6 +956,10 @@ impl<'a> Vm<'a> {
                 self.runtime_error("Cannot add an array");
                 return false;
             }
+            ValueType::Map(_) => {
+                self.runtime_error("Cannot add a map");
+                return false;
+            }
             _ => {
                 self.runtime_error("Cannot add this type");
                 return false;
@@ -512,11 +970,45 @@ impl<'a> Vm<'a> {
         true
     }
 
+    fn subscript_set(
+        target: &mut ValueType<'a>,
+        index: ValueType<'a>,
+        value: ValueType<'a>,
+    ) -> Result<(), &'static str> {
+        match target {
+            ValueType::Array(a) => {
+                let index = match index {
+                    ValueType::Number(n) => n as usize,
+                    _ => return Err("Subscript index must be a number"),
+                };
+                if index >= a.len() {
+                    return Err("Subscript out of range");
+                }
+                // the array is copied first if another variable still shares it
+                Rc::make_mut(a)[index] = value;
+            }
+            ValueType::Map(ref mut m) => {
+                let key = match index {
+                    ValueType::Str(_) | ValueType::String(_) => index.to_string(),
+                    _ => return Err("Map key must be a string"),
+                };
+                m.insert(key, value);
+            }
+            _ => return Err("Subscript set only works on arrays and maps"),
+        }
+        Ok(())
+    }
+
     fn debug(&mut self, ip: usize, frame: &Frame, frame_index: usize) {
         let settings = self.debug_settings.as_ref().unwrap();
 
         let mut break_line: u32 = 0;
-        let source_lines = self.source_code.unwrap();
+        let file_index = match self.source_files.iter().rposition(|f| f.start <= ip) {
+            Some(index) => index,
+            None => return,
+        };
+        // breakpoints are line numbers in the main script
+        let in_main_script = self.source_files[file_index].name.is_none();
         let step_into = match self.step {
             DebugStep::StepOver => {
                 if self.line_numbers[ip] > 0 {
@@ -525,7 +1017,7 @@ impl<'a> Vm<'a> {
                 false
             }
             DebugStep::Continue => {
-                if settings.break_points.contains(&self.line_numbers[ip]) {
+                if in_main_script && settings.break_points.contains(&self.line_numbers[ip]) {
                     break_line = self.line_numbers[ip];
                     self.break_frame = frame_index;
                 }
@@ -546,6 +1038,7 @@ impl<'a> Vm<'a> {
         {
             self.break_frame = frame_index;
             let code_window = self.debug_settings.as_ref().unwrap().code_window;
+            let source_lines = &self.source_files[file_index].lines;
             let start = if break_line > code_window {
                 break_line - code_window
             } else {
@@ -560,6 +1053,9 @@ impl<'a> Vm<'a> {
             };
 
             println!("<--------CODE------------>");
+            if let Some(name) = &self.source_files[file_index].name {
+                println!("{}", name.bright_black());
+            }
             for i in start..end {
                 if (i + 1) == break_line {
                     println!(">{:5.0} {}", i + 1, source_lines[i as usize].yellow());
@@ -588,24 +1084,181 @@ impl<'a> Vm<'a> {
     }
 
     pub fn run(&mut self, instructions: &'a Vec<OpCode>) -> bool {
+        self.run_from(instructions, 0)
+    }
+
+    // runs the top level code starting at the given instruction, the REPL uses this
+    // to run only the code compiled for the latest input
+    pub fn run_from(&mut self, instructions: &'a Vec<OpCode>, start: usize) -> bool {
         //dbg!(&instructions);
-        if instructions.len() == 0 {
+        // the REPL runs every input on the same Vm, so clear what an earlier run left
+        self.stack_pointer = 0;
+        self.return_value = None;
+        self.in_error = false;
+        self.error_message = None;
+        self.limit_exceeded = false;
+        if instructions.len() <= start {
             return true;
         }
-        let mut call_frames: Vec<Frame> = Vec::new();
-        let main_frame = Frame {
-            ip: 0,
-            frame_pointer: 0,
-            offset: 0,
+        self.call_frames.clear();
+        self.handlers.clear();
+        self.call_boundary = 0;
+        self.callback_depth = 0;
+        self.instructions = Some(instructions);
+        if self.globals.len() < self.global_names.len() {
+            self.globals.resize(self.global_names.len(), None);
+        }
+        self.frame = TOP_LEVEL_FRAME;
+        self.frame.ip = start;
+        self.instruction_count = 0;
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        while !self.execute(instructions) {
+            if self.exit_code.is_some() {
+                self.stack_pointer = 0;
+                return true;
+            }
+            if !self.catch_error() {
+                self.report_error();
+                return false;
+            }
+        }
+        //dbg!(&self.stack[0..self.stack_pointer]);
+        assert!(self.stack_pointer == 0);
+
+        true
+    }
+
+    // Calls a function value from a native, e.g. the callback passed to map(arr, f)
+    pub fn call_function(
+        &mut self,
+        function: &ValueType<'a>,
+        args: Vec<ValueType<'a>>,
+    ) -> Result<ValueType<'a>, &'a str> {
+        match function {
+            ValueType::Native(index) => Vm::NATIVES[*index].0(args, self),
+            ValueType::Host(index) => match self.call_host(*index, args) {
+                Ok(value) => Ok(value),
+                Err(message) => {
+                    self.runtime_error(&message);
+                    Err("")
+                }
+            },
+            ValueType::Func(pointer, arity) => {
+                self.call_bytecode(*pointer, *arity, args, Vec::new())
+            }
+            ValueType::Closure(pointer, arity, captured) => {
+                self.call_bytecode(*pointer, *arity, args, captured.clone())
+            }
+            _ => Err("Uncallable target"),
+        }
+    }
+
+    fn call_host(
+        &mut self,
+        index: usize,
+        args: Vec<ValueType<'a>>,
+    ) -> Result<ValueType<'a>, String> {
+        let args = args.iter().map(|v| v.detach()).collect();
+        (self.host_functions[index])(args)
+    }
+
+    fn call_bytecode(
+        &mut self,
+        pointer: usize,
+        arity: u8,
+        args: Vec<ValueType<'a>>,
+        captured: Vec<ValueType<'a>>,
+    ) -> Result<ValueType<'a>, &'a str> {
+        let instructions = if let Some(instructions) = self.instructions {
+            instructions
+        } else {
+            return Err("Cannot call a function before the program is running");
+        };
+        if arity as usize != args.len() {
+            return Err("Incorrect number of parameters");
+        }
+        if !self.check_call_depth(self.call_frames.len(), pointer)
+            || !self.check_callback_depth(pointer)
+        {
+            return Err("");
+        }
+
+        // lay the stack out the same way OpCode::Call does
+        self.push(ValueType::Func(pointer, arity));
+        let frame_pointer = self.stack_pointer;
+        for value in args.into_iter().chain(captured) {
+            self.push(value);
+        }
+        self.call_frames.push(self.frame);
+        self.frame = Frame {
+            ip: pointer,
+            frame_pointer,
+            offset: 1,
+            function: Some(pointer),
         };
-        let mut frame = main_frame;
+
+        let boundary = std::mem::replace(&mut self.call_boundary, self.call_frames.len());
+        self.callback_depth += 1;
+        while !self.execute(instructions) {
+            // only try blocks inside the called function can catch here
+            let inside =
+                matches!(self.handlers.last(), Some(h) if h.call_depth >= self.call_boundary);
+            if !inside || !self.catch_error() {
+                self.call_boundary = boundary;
+                self.callback_depth -= 1;
+                return Err("");
+            }
+        }
+        self.call_boundary = boundary;
+        self.callback_depth -= 1;
+
+        pop!(self, value);
+        Ok(value.clone())
+    }
+
+    // jump to the innermost catch block, with the error on top of the stack.
+    // returns false if there is no try block to catch the error.
+    fn catch_error(&mut self) -> bool {
+        if self.limit_exceeded || self.exit_code.is_some() {
+            return false;
+        }
+        let handler = if let Some(handler) = self.handlers.pop() {
+            handler
+        } else {
+            return false;
+        };
+        let line_number = self.line_numbers[self.frame.ip];
+        let message = self.error_message.take().unwrap_or_default();
+
+        // unwind back to the function that contains the try block
+        if self.call_frames.len() > handler.call_depth {
+            self.frame = self.call_frames[handler.call_depth];
+            self.call_frames.truncate(handler.call_depth);
+        }
+        self.stack_pointer = handler.stack_pointer;
+
+        let mut error = BTreeMap::new();
+        error.insert(String::from("message"), ValueType::String(message.into()));
+        error.insert(String::from("line"), ValueType::Number(line_number as f64));
+        self.push(ValueType::Map(error));
+
+        self.frame.ip = handler.catch_ip;
+        self.in_error = false;
+        true
+    }
+
+    fn execute(&mut self, instructions: &'a [OpCode]) -> bool {
         //let mut function_to_call = ValueType::Boolean(false);
         loop {
+            if (self.max_instructions.is_some() || self.deadline.is_some()) && !self.check_limits()
+            {
+                return false;
+            }
             if let Some(_) = self.debug_settings {
-                self.debug(frame.ip, &frame, call_frames.len());
+                let frame = self.frame;
+                self.debug(self.frame.ip, &frame, self.call_frames.len());
             }
-            let instr = &instructions[frame.ip];
-            self.ip = frame.ip;
+            let instr = &instructions[self.frame.ip];
             match instr {
                 OpCode::ConstantNum(num) => {
                     self.push(ValueType::Number(*num));
@@ -617,6 +1270,9 @@ impl<'a> Vm<'a> {
                 OpCode::ConstantStr(str) => {
                     self.push(ValueType::Str(str));
                 }
+                OpCode::ConstantString(str) => {
+                    self.push(ValueType::String(str.as_str().into()));
+                }
                 OpCode::Subtract => {
                     if !self.binary(&instr) {
                         return false;
@@ -690,15 +1346,14 @@ impl<'a> Vm<'a> {
                 OpCode::SetGlobal(name) => {
                     let v = &self.stack[self.stack_pointer - 1];
                     //pop!(self, v);
-                    self.globals.insert(*name, v.clone());
+                    self.globals[*name as usize] = Some(v.clone());
                 }
                 OpCode::GetGlobal(name) => {
-                    if let Some(value) = self.globals.get(name) {
+                    if let Some(Some(value)) = self.globals.get(*name as usize) {
                         //dbg!(&value);
                         self.push(value.to_owned());
                     } else {
-                        let message = format!("Global variable {name} does not exist.");
-                        self.runtime_error(&message);
+                        self.global_error(*name as usize);
                         return false;
                     }
                 }
@@ -706,7 +1361,7 @@ impl<'a> Vm<'a> {
                     let mut result = ValueType::Boolean(false);
                     let a = &self.stack[self.stack_pointer - *argc as usize - 1];
                     //dbg!(argc);
-                    //dbg!(frame.frame_pointer);
+                    //dbg!(self.frame.frame_pointer);
                     //dbg!(&a);
                     for i in 0..*argc {
                         let b = &self.stack[self.stack_pointer - i as usize - 1];
@@ -750,13 +1405,22 @@ impl<'a> Vm<'a> {
                     //pop_pointer!(self, p);
                     pop_mut!(self, p);
 
+                    if let VarType::Local(_) | VarType::Global(_) = variable {
+                        // the variable is changed rather than this copy of it, which would
+                        // otherwise make the variable's array look shared and be copied
+                        *p = ValueType::Boolean(false);
+                    }
                     let result = match variable {
                         VarType::Local(index) => {
-                            func(&mut self.stack[frame.frame_pointer + *index], args)
-                        }
-                        VarType::Global(index) => {
-                            func(self.globals.get_mut(&(*index as u32)).unwrap(), args)
+                            func(&mut self.stack[self.frame.frame_pointer + *index], args)
                         }
+                        VarType::Global(index) => match self.globals.get_mut(*index) {
+                            Some(Some(value)) => func(value, args),
+                            _ => {
+                                self.global_error(*index);
+                                return false;
+                            }
+                        },
                         _ => func(/*&mut self.stack[self.stack_pointer - 1]*/ p, args),
                     };
 
@@ -775,7 +1439,7 @@ impl<'a> Vm<'a> {
                         args.insert(0, v.clone());
                     }
 
-                    let result = system_command(name, args);
+                    let result = system_command(name, args, &self.sandbox);
 
                     match result {
                         Ok(value) => self.push(value),
@@ -789,22 +1453,47 @@ impl<'a> Vm<'a> {
                     //function_to_call = ValueType::Func(*ptr, *arity);
                     self.push(ValueType::Func(*ptr, *arity));
                 }
+                OpCode::Closure(ptr, arity, captures) => {
+                    let start = self.stack_pointer - *captures as usize;
+                    let captured = self.stack[start..self.stack_pointer].to_vec();
+                    self.stack_pointer = start;
+                    self.push(ValueType::Closure(*ptr, *arity, captured));
+                }
                 OpCode::Native(index) => {
                     //function_to_call = ValueType::Native(*index);
                     self.push(ValueType::Native(*index));
                 }
+                OpCode::Host(index) => {
+                    self.push(ValueType::Host(*index));
+                }
+                OpCode::CallHost(index, argc) => {
+                    let start = self.stack_pointer - *argc as usize;
+                    let args = self.stack[start..self.stack_pointer].to_vec();
+                    self.stack_pointer = start;
+                    match self.call_host(*index, args) {
+                        Ok(value) => self.push(value),
+                        Err(message) => {
+                            self.runtime_error(&message);
+                            return false;
+                        }
+                    }
+                }
                 OpCode::Invoke(pointer, argc) => {
-                    call_frames.push(frame); // save current frame
+                    if !self.check_call_depth(self.call_frames.len(), *pointer) {
+                        return false;
+                    }
+                    self.call_frames.push(self.frame); // save current frame
 
-                    frame = Frame {
+                    self.frame = Frame {
                         ip: pointer - 1,
                         frame_pointer: self.stack_pointer - *argc as usize,
                         offset: 0,
+                        function: Some(*pointer),
                     };
                 }
                 OpCode::Call(argc) => {
                     //dbg!(&self.stack[0..self.stack_pointer]);
-                    //dbg!(frame.frame_pointer);
+                    //dbg!(self.frame.frame_pointer);
                     let argc = *argc as usize;
                     let func = &self.stack[self.stack_pointer - argc - 1];
                     //dbg!(&function_to_call);
@@ -814,13 +1503,41 @@ impl<'a> Vm<'a> {
                                 self.runtime_error("Incorrect number of parameters");
                                 return false;
                             }
-                            call_frames.push(frame); // save current frame
+                            let pointer = *pointer;
+                            if !self.check_call_depth(self.call_frames.len(), pointer) {
+                                return false;
+                            }
+                            self.call_frames.push(self.frame); // save current frame
+
+                            self.frame = Frame {
+                                ip: pointer - 1,
+                                frame_pointer: self.stack_pointer - argc,
+                                offset: 1,
+                                function: Some(pointer),
+                            };
+                        }
+                        ValueType::Closure(pointer, arity, captured) => {
+                            if *arity as usize != argc {
+                                self.runtime_error("Incorrect number of parameters");
+                                return false;
+                            }
+                            let pointer = *pointer;
+                            let captured = captured.clone();
+                            if !self.check_call_depth(self.call_frames.len(), pointer) {
+                                return false;
+                            }
+                            self.call_frames.push(self.frame); // save current frame
 
-                            frame = Frame {
+                            self.frame = Frame {
                                 ip: pointer - 1,
                                 frame_pointer: self.stack_pointer - argc,
                                 offset: 1,
+                                function: Some(pointer),
                             };
+                            // captured values sit after the params as locals
+                            for value in captured {
+                                self.push(value);
+                            }
                         }
                         ValueType::Native(index) => {
                             let mut args: Vec<ValueType> = Vec::new();
@@ -842,6 +1559,19 @@ impl<'a> Vm<'a> {
                                 }
                             }
                         }
+                        ValueType::Host(index) => {
+                            let index = *index;
+                            let start = self.stack_pointer - argc;
+                            let args = self.stack[start..self.stack_pointer].to_vec();
+                            self.stack_pointer = start - 1; // the args and the function
+                            match self.call_host(index, args) {
+                                Ok(value) => self.push(value),
+                                Err(message) => {
+                                    self.runtime_error(&message);
+                                    return false;
+                                }
+                            }
+                        }
                         _ => {
                             self.runtime_error("Uncallable target");
                             return false;
@@ -849,8 +1579,8 @@ impl<'a> Vm<'a> {
                     }
                 }
                 OpCode::Pop => {
-                    pop!(self, v);
-                    self.return_value = Some(v.clone());
+                    self.stack_pointer -= 1;
+                    self.return_value = Some(self.take(self.stack_pointer));
                 }
                 OpCode::Pop2 => {
                     //dbg!(&self.stack[0..self.stack_pointer]);
@@ -871,7 +1601,7 @@ impl<'a> Vm<'a> {
                             let mut in_result = ValueType::Boolean(false);
                             let a = &self.stack[self.stack_pointer - *argc as usize - 1];
                             //dbg!(argc);
-                            //dbg!(frame.frame_pointer);
+                            //dbg!(self.frame.frame_pointer);
                             //dbg!(&a);
                             for i in 0..*argc {
                                 let b = &self.stack[self.stack_pointer - i as usize - 1];
@@ -905,19 +1635,35 @@ impl<'a> Vm<'a> {
                     }
                 }
                 OpCode::GetLocal(i) => {
-                    self.push(self.stack[i + frame.frame_pointer].clone());
+                    self.push(self.stack[i + self.frame.frame_pointer].clone());
                 }
                 OpCode::SetLocal(i) => {
                     let value = self.stack[self.stack_pointer - 1].clone();
-                    self.stack[i + frame.frame_pointer] = value;
+                    self.stack[i + self.frame.frame_pointer] = value;
+                }
+                // the same as GetLocal, ConstantNum, Add, SetLocal
+                OpCode::AddLocal(i, num) => {
+                    let local = i + self.frame.frame_pointer;
+                    if let ValueType::Number(value) = self.stack[local] {
+                        self.stack[local] = ValueType::Number(value + num);
+                        self.push(ValueType::Number(value + num));
+                    } else {
+                        // do the Add the long way, so errors are the same
+                        self.push(self.stack[local].clone());
+                        self.push(ValueType::Number(*num));
+                        if !self.add() {
+                            return false;
+                        }
+                        self.stack[local] = self.stack[self.stack_pointer - 1].clone();
+                    }
                 }
                 OpCode::DefineLocal(i) => {
                     //dbg!(i);
                     let value = self.stack[self.stack_pointer - 1].clone();
-                    if i + frame.frame_pointer >= self.stack_pointer - 1 {
+                    if i + self.frame.frame_pointer >= self.stack_pointer - 1 {
                         self.push(value);
                     } else {
-                        self.stack[i + frame.frame_pointer] = value;
+                        self.stack[i + self.frame.frame_pointer] = value;
                     }
                 }
                 // do a define local
@@ -926,7 +1672,7 @@ impl<'a> Vm<'a> {
                     //if let Some(result) = self.stack.pop() {
                     if let ValueType::Boolean(val) = result {
                         if !val {
-                            frame.ip += to_jump;
+                            self.frame.ip += to_jump;
                         }
                     } else {
                         self.runtime_error("boolean value expected");
@@ -935,25 +1681,52 @@ impl<'a> Vm<'a> {
                     //}
                 }
                 OpCode::Jump(to_jump) => {
-                    let current: i32 = frame.ip.try_into().unwrap();
+                    let current: i32 = self.frame.ip.try_into().unwrap();
                     let new_ip: usize = (current + to_jump).try_into().unwrap();
-                    frame.ip = new_ip;
+                    self.frame.ip = new_ip;
+                }
+                OpCode::Try(to_jump) => {
+                    self.handlers.push(Handler {
+                        catch_ip: self.frame.ip + to_jump + 1,
+                        stack_pointer: self.stack_pointer,
+                        call_depth: self.call_frames.len(),
+                    });
+                }
+                OpCode::EndTry => {
+                    self.handlers.pop();
                 }
                 OpCode::Return => {
                     //dbg!(&self.stack[0..self.stack_pointer]);
-                    //dbg!(&self.stack[frame.frame_pointer..self.stack_pointer]);
+                    //dbg!(&self.stack[self.frame.frame_pointer..self.stack_pointer]);
                     //dbg!(&self.value_pointers[0..self.stack_pointer]);
                     // pop the frame
                     // if no frames left, then break
-                    if let Some(value) = call_frames.pop() {
+                    if !self.call_frames.is_empty() && self.return_value.is_none() {
+                        self.runtime_error("Function didn't return a value!");
+                        return false;
+                    }
+                    if let Some(value) = self.call_frames.pop() {
                         // get rid of any local variables on the stack
-                        self.stack_pointer = frame.frame_pointer - frame.offset as usize; //-1 for the func() valuetype
+                        self.stack_pointer = self.frame.frame_pointer - self.frame.offset as usize; //-1 for the func() valuetype
 
                         // set the call frame
-                        frame = value;
+                        self.frame = value;
+
+                        // forget any try blocks we are returning out of
+                        while let Some(handler) = self.handlers.last() {
+                            if handler.call_depth <= self.call_frames.len() {
+                                break;
+                            }
+                            self.handlers.pop();
+                        }
 
                         let val = self.return_value.clone();
                         self.push(val.unwrap());
+
+                        // back in the native that called this function
+                        if self.call_frames.len() < self.call_boundary {
+                            return true;
+                        }
                     } else {
                         // we are exiting from the top level code. Clear the stack.
                         self.stack_pointer = 0;
@@ -984,85 +1757,85 @@ impl<'a> Vm<'a> {
                             self.runtime_error("Subscript index must be a number");
                             return false;
                         }
+                    } else if let ValueType::Map(m) = array {
+                        let key = match index {
+                            ValueType::Str(_) | ValueType::String(_) => index.to_string(),
+                            _ => {
+                                self.runtime_error("Map key must be a string");
+                                return false;
+                            }
+                        };
+                        if let Some(val) = m.get(&key) {
+                            self.push(val.clone());
+                        } else {
+                            let message = format!("Key '{key}' not found in map");
+                            self.runtime_error(&message);
+                            return false;
+                        }
                     } else {
                         //dbg!(&array);
                         //dbg!(index);
-                        self.runtime_error("Subscript get only works on arrays");
+                        self.runtime_error("Subscript get only works on arrays and maps");
                         return false;
                     }
                 }
+                OpCode::Map(len) => {
+                    let mut map = BTreeMap::new();
+                    let start = self.stack_pointer - *len as usize * 2;
+                    for i in 0..*len as usize {
+                        let key = &self.stack[start + i * 2];
+                        let key = match key {
+                            ValueType::Str(_) | ValueType::String(_) => key.to_string(),
+                            _ => {
+                                self.runtime_error("Map key must be a string");
+                                return false;
+                            }
+                        };
+                        map.insert(key, self.stack[start + i * 2 + 1].clone());
+                    }
+                    self.stack_pointer = start;
+                    self.push(ValueType::Map(map));
+                }
                 OpCode::FuncPlaceholder(_, _) | OpCode::InvokePlaceholder(_, _) => {
                     panic!("Placeholder op code not replaced!");
                 }
                 OpCode::SubscriptSet(vartype) => {
-                    let mut stack = self.stack.iter();
-                    let index = stack.nth(self.stack_pointer - 2).unwrap();
-                    let value = stack.nth(0).unwrap();
-
                     self.stack_pointer -= 2;
-
-                    let index = match index {
-                        ValueType::Number(n) => *n as usize,
-                        _ => {
-                            self.runtime_error("Subscript index must be a number");
-                            return false;
-                        }
-                    };
-                    let value = match value {
-                        ValueType::Str(x) => ValueType::Str(x),
-                        ValueType::Array(x) => ValueType::Array(x.to_vec()),
-                        ValueType::Boolean(x) => ValueType::Boolean(*x),
-                        ValueType::Number(x) => ValueType::Number(*x),
-                        ValueType::String(x) => ValueType::String(x.to_string()),
-                        ValueType::Func(a, b) => ValueType::Func(*a, *b),
-                        ValueType::Native(a) => ValueType::Native(*a),
-                    };
-                    match vartype {
+                    let index = self.take(self.stack_pointer);
+                    let value = self.take(self.stack_pointer + 1);
+                    let result = match vartype {
                         VarType::Local(i) => {
                             let i = *i;
-                            let array_value = &mut self.stack[i + frame.frame_pointer];
+                            let array_value = &mut self.stack[i + self.frame.frame_pointer];
                             //dbg!(&array_value);
-                            if let ValueType::Array(ref mut a) = array_value {
-                                // self.stack[*i] {
-                                a[index] = value.clone();
-                                //self.push(value);
-                            } else {
-                                //dbg!(i);
-                                self.runtime_error("Subscript set local only works on arrays");
-                                return false;
-                            }
+                            Self::subscript_set(array_value, index, value)
                         }
-                        VarType::Global(s) => {
-                            let s = *s as u32;
-                            let array = self.globals.get_mut(&s).unwrap();
-                            if let ValueType::Array(ref mut a) = array {
-                                a[index] = value.clone();
-                                //self.push(value);
-                            } else {
-                                self.runtime_error("Subscript set global only works on arrays");
+                        VarType::Global(s) => match self.globals.get_mut(*s) {
+                            Some(Some(array)) => Self::subscript_set(array, index, value),
+                            _ => {
+                                self.global_error(*s);
                                 return false;
                             }
-                        }
-                        VarType::None => {
-                            self.runtime_error("No variable specified for Subscript set ");
-                            return false;
-                        }
+                        },
+                        VarType::None => Err("No variable specified for Subscript set "),
+                    };
+                    if let Err(message) = result {
+                        self.runtime_error(message);
+                        return false;
                     }
                 }
             }
-            frame.ip += 1;
-            if frame.ip >= instructions.len() {
+            self.frame.ip += 1;
+            if self.frame.ip >= instructions.len() {
                 break;
             }
             if self.in_error {
                 return false;
             }
-            //dbg!(frame.ip);
+            //dbg!(self.frame.ip);
             //dbg!(&instr);
             //dbg!(&self.stack[0..self.stack_pointer]);
         }
-        //dbg!(&self.stack[0..self.stack_pointer]);
-        assert!(self.stack_pointer == 0);
 
         true
     }
@@ -1,6 +1,7 @@
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 use std::cmp::Ordering;
+use std::rc::Rc;
 
 use super::{ValueType, Vm};
 
@@ -35,12 +36,12 @@ fn filter_comparison(op: &str, a: &ValueType, b: &ValueType) -> bool {
         }
         ValueType::Str(a) => match b {
             ValueType::Str(b) => string_compare(&op, a, b), // ValueType::Boolean(a == b),
-            ValueType::String(b) => string_compare(&op, a, b.as_str()), //ValueType::Boolean(a == b),
+            ValueType::String(b) => string_compare(&op, a, b), //ValueType::Boolean(a == b),
             _ => false,
         },
         ValueType::String(a) => match b {
-            ValueType::Str(b) => string_compare(&op, a.as_str(), b), //ValueType::Boolean(a == b),
-            ValueType::String(b) => string_compare(&op, a.as_str(), b.as_str()), //ValueType::Boolean(a == b),
+            ValueType::Str(b) => string_compare(&op, a, b), //ValueType::Boolean(a == b),
+            ValueType::String(b) => string_compare(&op, a, b), //ValueType::Boolean(a == b),
             _ => false,
         },
         ValueType::Boolean(a) => match b {
@@ -87,7 +88,7 @@ pub fn array<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType
     for value in params {
         array.push(value)
     }
-    Ok(ValueType::Array(array))
+    Ok(ValueType::Array(array.into()))
 }
 
 pub fn dim<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
@@ -108,31 +109,173 @@ pub fn dim<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'
     for _ in 1..size {
         array.push(value.to_owned())
     }
-    Ok(ValueType::Array(array))
+    Ok(ValueType::Array(array.into()))
 }
 
-pub fn filter<'a>(
-    array: &mut ValueType<'a>,
-    params: Vec<ValueType<'a>>,
-) -> Result<ValueType<'a>, &'a str> {
+pub fn filter<'a>(params: Vec<ValueType<'a>>, vm: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
     if params.len() < 2 {
         return Err("Incorrect number of parameters passed to filter()");
     }
-    //let mut iter = params.iter();
-    //let operator = iter.next().unwrap().to_string();
-    //let value = iter.next().unwrap();
-    let filtered = if let ValueType::Array(vec) = array {
-        vec.into_iter().filter(|x| filter_multi_comp(&params, x))
+    let vec = if let ValueType::Array(vec) = &params[0] {
+        vec
     } else {
         return Err("Incorrect parameters passed to filter()");
     };
     let mut result: Vec<ValueType> = Vec::new();
+    if is_callable(&params[1]) {
+        for item in vec.iter() {
+            if predicate(vm, &params[1], item, "filter")? {
+                result.push(item.clone());
+            }
+        }
+    } else {
+        let comparisons = params[1..].to_vec();
+        for item in vec.iter() {
+            if filter_multi_comp(&comparisons, item) {
+                result.push(item.clone());
+            }
+        }
+    }
+
+    Ok(ValueType::Array(result.into()))
+}
+
+fn is_callable(value: &ValueType) -> bool {
+    matches!(
+        value,
+        ValueType::Func(_, _)
+            | ValueType::Closure(_, _, _)
+            | ValueType::Native(_)
+            | ValueType::Host(_)
+    )
+}
 
-    for item in filtered {
-        result.push(item.clone());
+fn predicate<'a>(
+    vm: &mut Vm<'a>,
+    function: &ValueType<'a>,
+    item: &ValueType<'a>,
+    name: &str,
+) -> Result<bool, &'a str> {
+    match vm.call_function(function, vec![item.clone()])? {
+        ValueType::Boolean(b) => Ok(b),
+        _ => {
+            let message = format!("The function passed to {name}() must return a boolean");
+            vm.runtime_error(&message);
+            Err("")
+        }
+    }
+}
+
+// gets the array and function for map, any, all etc
+fn array_and_function<'a, 'b>(
+    params: &'b [ValueType<'a>],
+    message: &'a str,
+) -> Result<(&'b [ValueType<'a>], &'b ValueType<'a>), &'a str> {
+    if let [ValueType::Array(vec), function, ..] = params {
+        if is_callable(function) {
+            return Ok((vec.as_slice(), function));
+        }
+    }
+    Err(message)
+}
+
+pub fn map<'a>(params: Vec<ValueType<'a>>, vm: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
+    let (vec, function) = array_and_function(
+        &params,
+        "Incorrect parameters passed to map(array, function)",
+    )?;
+    let mut result: Vec<ValueType> = Vec::with_capacity(vec.len());
+    for item in vec {
+        result.push(vm.call_function(function, vec![item.clone()])?);
     }
+    Ok(ValueType::Array(result.into()))
+}
 
-    Ok(ValueType::Array(result))
+pub fn reduce<'a>(params: Vec<ValueType<'a>>, vm: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
+    let (vec, function) = array_and_function(
+        &params,
+        "Incorrect parameters passed to reduce(array, function, initial)",
+    )?;
+    let mut result = if let Some(initial) = params.get(2) {
+        initial.clone()
+    } else {
+        return Err("Incorrect parameters passed to reduce(array, function, initial)");
+    };
+    for item in vec {
+        result = vm.call_function(function, vec![result, item.clone()])?;
+    }
+    Ok(result)
+}
+
+pub fn any<'a>(params: Vec<ValueType<'a>>, vm: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
+    let (vec, function) = array_and_function(
+        &params,
+        "Incorrect parameters passed to any(array, function)",
+    )?;
+    for item in vec {
+        if predicate(vm, function, item, "any")? {
+            return Ok(ValueType::Boolean(true));
+        }
+    }
+    Ok(ValueType::Boolean(false))
+}
+
+pub fn all<'a>(params: Vec<ValueType<'a>>, vm: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
+    let (vec, function) = array_and_function(
+        &params,
+        "Incorrect parameters passed to all(array, function)",
+    )?;
+    for item in vec {
+        if !predicate(vm, function, item, "all")? {
+            return Ok(ValueType::Boolean(false));
+        }
+    }
+    Ok(ValueType::Boolean(true))
+}
+
+pub fn sortby<'a>(params: Vec<ValueType<'a>>, vm: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
+    let (vec, function) = array_and_function(
+        &params,
+        "Incorrect parameters passed to sortby(array, function)",
+    )?;
+    let result = merge_sort(vec.to_vec(), vm, function)?;
+    Ok(ValueType::Array(result.into()))
+}
+
+// A stable merge sort. The std sort isn't used as a user comparator can fail part way through
+fn merge_sort<'a>(
+    mut items: Vec<ValueType<'a>>,
+    vm: &mut Vm<'a>,
+    function: &ValueType<'a>,
+) -> Result<Vec<ValueType<'a>>, &'a str> {
+    if items.len() <= 1 {
+        return Ok(items);
+    }
+    let right = items.split_off(items.len() / 2);
+    let left = merge_sort(items, vm, function)?;
+    let right = merge_sort(right, vm, function)?;
+
+    let mut result: Vec<ValueType> = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        let order = vm.call_function(function, vec![a.clone(), b.clone()])?;
+        let right_first = match order {
+            ValueType::Number(n) => n > 0.0,
+            _ => {
+                vm.runtime_error("The function passed to sortby() must return a number");
+                return Err("");
+            }
+        };
+        if right_first {
+            result.extend(right.next());
+        } else {
+            result.extend(left.next());
+        }
+    }
+    result.extend(left);
+    result.extend(right);
+    Ok(result)
 }
 
 pub fn push<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
@@ -142,9 +285,9 @@ pub fn push<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<
     let array = &params[0];
     let value = params[1].clone();
     if let ValueType::Array(vec) = array {
-        let mut result = vec.clone();
+        let mut result = vec.to_vec();
         result.push(value);
-        return Ok(ValueType::Array(result));
+        return Ok(ValueType::Array(result.into()));
     }
     Err("Incorrect parameters passed to  push(array, value)")
 }
@@ -155,9 +298,9 @@ pub fn shuffle<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueTy
     }
     let array = &params[0];
     if let ValueType::Array(vec) = array {
-        let mut result = vec.clone();
+        let mut result = vec.to_vec();
         result.shuffle(&mut thread_rng());
-        return Ok(ValueType::Array(result));
+        return Ok(ValueType::Array(result.into()));
     }
     Err("Incorrect parameters passed to shuffle(array)")
 }
@@ -166,16 +309,17 @@ pub fn push_mut<'a>(
     array: &mut ValueType<'a>,
     params: Vec<ValueType<'a>>,
 ) -> Result<ValueType<'a>, &'a str> {
-    if params.len() < 1 {
+    if params.is_empty() {
         return Err("Incorrect parameters passed to push(array, value)");
     }
-    let value = params.iter().next();
+    let value = params.first();
     if let Some(val) = value {
         //let array = &mut params[0];
         //let v = val.clone();
-        if let ValueType::Array(ref mut vec) = array {
-            vec.push(val.clone());
-            //let mut result = vec.clone();
+        if let ValueType::Array(vec) = array {
+            // copies the array first if it is shared with another variable
+            Rc::make_mut(vec).push(val.clone());
+            //let mut result = vec.to_vec();
             //result.push(value);
             return Ok(ValueType::Boolean(true));
             //return Ok(ValueType::Array(vec.clone()));
@@ -209,22 +353,22 @@ pub fn slice<'a>(
         return Err("Incorrect parameters passed to slice(start, finish). Start and finish must be greater than zero.");
     }
     dbg!(&array);
-    if let ValueType::Array(ref mut vec) = array {
+    if let ValueType::Array(vec) = array {
         dbg!(&vec);
         let sliced = &vec[*start as usize..*finish as usize];
         dbg!(sliced);
-        return Ok(ValueType::Array(sliced.to_owned()));
+        Ok(ValueType::Array(sliced.to_vec().into()))
     } else {
-        return Err("Incorrect parameters passed to slice(start, finish)");
-    };
+        Err("Incorrect parameters passed to slice(start, finish)")
+    }
 }
 
 pub fn sort<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
     if let Some(param) = params.first() {
         if let ValueType::Array(vec) = param {
-            let mut result = vec.clone();
+            let mut result = vec.to_vec();
             result.sort_by(|a, b| compare(a, b));
-            return Ok(ValueType::Array(result));
+            return Ok(ValueType::Array(result.into()));
         }
     }
     Err("Incorrect parameters passed to sort(array)")
@@ -0,0 +1,161 @@
+// Conversions between ValueType and Rust types, for programs embedding Very Basic
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+};
+
+use super::ValueType;
+
+impl From<f64> for ValueType<'_> {
+    fn from(value: f64) -> Self {
+        ValueType::Number(value)
+    }
+}
+
+impl From<i64> for ValueType<'_> {
+    fn from(value: i64) -> Self {
+        ValueType::Number(value as f64)
+    }
+}
+
+impl From<bool> for ValueType<'_> {
+    fn from(value: bool) -> Self {
+        ValueType::Boolean(value)
+    }
+}
+
+impl From<String> for ValueType<'_> {
+    fn from(value: String) -> Self {
+        ValueType::String(value.into())
+    }
+}
+
+impl<'a> From<&'a str> for ValueType<'a> {
+    fn from(value: &'a str) -> Self {
+        ValueType::Str(value)
+    }
+}
+
+impl<'a, T: Into<ValueType<'a>>> From<Vec<T>> for ValueType<'a> {
+    fn from(value: Vec<T>) -> Self {
+        ValueType::Array(Rc::new(value.into_iter().map(|v| v.into()).collect()))
+    }
+}
+
+impl<'a, T: Into<ValueType<'a>>> From<HashMap<String, T>> for ValueType<'a> {
+    fn from(value: HashMap<String, T>) -> Self {
+        ValueType::Map(value.into_iter().map(|(k, v)| (k, v.into())).collect())
+    }
+}
+
+impl<'a, T: Into<ValueType<'a>>> From<BTreeMap<String, T>> for ValueType<'a> {
+    fn from(value: BTreeMap<String, T>) -> Self {
+        ValueType::Map(value.into_iter().map(|(k, v)| (k, v.into())).collect())
+    }
+}
+
+fn type_name(value: &ValueType) -> &'static str {
+    match value {
+        ValueType::Number(_) => "number",
+        ValueType::Str(_) | ValueType::String(_) => "string",
+        ValueType::Boolean(_) => "boolean",
+        ValueType::Array(_) => "array",
+        ValueType::Map(_) => "map",
+        _ => "function",
+    }
+}
+
+fn expected(what: &str, value: &ValueType) -> String {
+    format!("Expected {what} but got {}", type_name(value))
+}
+
+impl TryFrom<ValueType<'_>> for f64 {
+    type Error = String;
+
+    fn try_from(value: ValueType) -> Result<Self, Self::Error> {
+        match value {
+            ValueType::Number(n) => Ok(n),
+            _ => Err(expected("a number", &value)),
+        }
+    }
+}
+
+impl TryFrom<ValueType<'_>> for bool {
+    type Error = String;
+
+    fn try_from(value: ValueType) -> Result<Self, Self::Error> {
+        match value {
+            ValueType::Boolean(b) => Ok(b),
+            _ => Err(expected("a boolean", &value)),
+        }
+    }
+}
+
+impl TryFrom<ValueType<'_>> for String {
+    type Error = String;
+
+    fn try_from(value: ValueType) -> Result<Self, Self::Error> {
+        match value {
+            ValueType::Str(s) => Ok(s.to_string()),
+            ValueType::String(s) => Ok(s.to_string()),
+            _ => Err(expected("a string", &value)),
+        }
+    }
+}
+
+impl<'a, T: TryFrom<ValueType<'a>, Error = String>> TryFrom<ValueType<'a>> for Vec<T> {
+    type Error = String;
+
+    fn try_from(value: ValueType<'a>) -> Result<Self, Self::Error> {
+        match value {
+            ValueType::Array(a) => Rc::unwrap_or_clone(a)
+                .into_iter()
+                .map(T::try_from)
+                .collect(),
+            _ => Err(expected("an array", &value)),
+        }
+    }
+}
+
+impl<'a, T: TryFrom<ValueType<'a>, Error = String>> TryFrom<ValueType<'a>> for BTreeMap<String, T> {
+    type Error = String;
+
+    fn try_from(value: ValueType<'a>) -> Result<Self, Self::Error> {
+        match value {
+            ValueType::Map(m) => m
+                .into_iter()
+                .map(|(k, v)| Ok((k, T::try_from(v)?)))
+                .collect(),
+            _ => Err(expected("a map", &value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::vm::ValueType;
+
+    #[test]
+    fn test_round_trip() {
+        let value = ValueType::from(vec![1.5, 2.0]);
+        assert_eq!(Vec::<f64>::try_from(value), Ok(vec![1.5, 2.0]));
+
+        let mut map = BTreeMap::new();
+        map.insert(String::from("a"), vec![String::from("b")]);
+        let value = ValueType::from(map.clone());
+        assert_eq!(BTreeMap::<String, Vec<String>>::try_from(value), Ok(map));
+
+        assert_eq!(
+            String::try_from(ValueType::from("hi")),
+            Ok(String::from("hi"))
+        );
+        assert_eq!(bool::try_from(ValueType::from(true)), Ok(true));
+        assert_eq!(
+            f64::try_from(ValueType::from("hi")),
+            Err(String::from("Expected a number but got string"))
+        );
+    }
+}
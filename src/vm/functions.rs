@@ -5,10 +5,12 @@ use glob::glob;
 use hex;
 use rand;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     env,
     fs::{read_to_string, File, OpenOptions},
     io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
     thread,
     time::SystemTime,
 };
@@ -83,7 +85,7 @@ pub fn print<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType
             std::io::stdout().flush().unwrap();
         }
 
-        Result::Ok(ValueType::String(s))
+        Result::Ok(ValueType::String(s.into()))
     } else {
         Err("No parameters passed to function")
     }
@@ -103,11 +105,135 @@ pub fn input<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType
     }
     let mut input = String::new();
     match io::stdin().read_line(&mut input) {
-        Ok(_) => Result::Ok(ValueType::String(String::from(input.trim_end()))),
+        Ok(_) => Result::Ok(ValueType::String(input.trim_end().into())),
         Err(_) => Err("Could not read from terminal"),
     }
 }
 
+pub fn throw<'a>(params: Vec<ValueType<'a>>, vm: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
+    if let Some(val) = params.first() {
+        // record the message here as the error returned must be a static string
+        vm.runtime_error(&val.to_string());
+        Err("")
+    } else {
+        Err("No parameters passed to function throw(message)")
+    }
+}
+
+// Parameters: 0 = command, 1(array) = arguments, 2 = text to send to stdin
+pub fn exec<'a>(params: Vec<ValueType<'a>>, vm: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
+    let command = match params.first() {
+        Some(command) => command.to_string(),
+        None => return Err("No parameters passed to function exec(cmd, [args], [stdin])"),
+    };
+    let args: Vec<String> = match params.get(1) {
+        Some(ValueType::Array(args)) => args.iter().map(|a| a.to_string()).collect(),
+        Some(_) => return Err("Parameter 2 of exec(cmd, [args], [stdin]) must be an array"),
+        None => Vec::new(),
+    };
+    let stdin = params.get(2).map(|s| s.to_string());
+    vm.permit(vm.sandbox.check_system(&command))?;
+
+    let mut child = match Command::new(&command)
+        .args(args)
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            vm.runtime_error(&format!("Failed to run system command {command}: {e}"));
+            return Err("");
+        }
+    };
+    // write stdin from another thread, so a command that fills its stdout before
+    // reading all of stdin can't block us both
+    let writer = match (child.stdin.take(), stdin) {
+        (Some(mut pipe), Some(text)) => {
+            Some(thread::spawn(move || pipe.write_all(text.as_bytes())))
+        }
+        _ => None,
+    };
+    let output = child.wait_with_output();
+    if let Some(writer) = writer {
+        let _ = writer.join();
+    }
+    let output = match output {
+        Ok(output) => output,
+        Err(e) => {
+            vm.runtime_error(&format!("Failed to run system command {command}: {e}"));
+            return Err("");
+        }
+    };
+
+    let mut result = BTreeMap::new();
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    // killed by a signal, there is no exit code
+    let code = output.status.code().unwrap_or(-1);
+    result.insert(String::from("stdout"), ValueType::String(stdout.into()));
+    result.insert(String::from("stderr"), ValueType::String(stderr.into()));
+    result.insert(String::from("code"), ValueType::Number(code as f64));
+    Ok(ValueType::Map(result))
+}
+
+pub fn quit<'a>(params: Vec<ValueType<'a>>, vm: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
+    let code = match params.first() {
+        Some(ValueType::Number(code)) => *code as i32,
+        Some(_) => return Err("Parameter 1 of quit([code]) must be a number"),
+        None => 0,
+    };
+    vm.quit(code);
+    Err("")
+}
+
+// Parameters: 0(bool) = condition, 1 = message if the condition is false
+pub fn assert<'a>(params: Vec<ValueType<'a>>, vm: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
+    match params.first() {
+        Some(ValueType::Boolean(true)) => Ok(ValueType::Boolean(true)),
+        Some(ValueType::Boolean(false)) => {
+            let message = match params.get(1) {
+                Some(message) => format!("assertion failed: {}", message.to_string()),
+                None => String::from("assertion failed"),
+            };
+            vm.runtime_error(&message);
+            Err("")
+        }
+        _ => Err("Parameter 1 of assert(cond, [msg]) must be a boolean"),
+    }
+}
+
+pub fn assert_eq<'a>(
+    params: Vec<ValueType<'a>>,
+    vm: &mut Vm<'a>,
+) -> Result<ValueType<'a>, &'a str> {
+    let (a, b) = match (params.first(), params.get(1)) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return Err("assert_eq(a, b) takes 2 parameters"),
+    };
+    if Vm::values_equal(a, b) {
+        return Ok(ValueType::Boolean(true));
+    }
+    // quote strings, so "1" and 1 can be told apart
+    let describe = |value: &ValueType| match value {
+        ValueType::Str(s) => format!("{s:?}"),
+        ValueType::String(s) => format!("{s:?}"),
+        value => value.to_string(),
+    };
+    let message = format!(
+        "assertion failed: {} is not equal to {}",
+        describe(a),
+        describe(b)
+    );
+    vm.runtime_error(&message);
+    Err("")
+}
+
 pub fn stack<'a>(_params: Vec<ValueType<'a>>, vm: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
     vm.debug_stack();
     Ok(ValueType::Boolean(true))
@@ -117,9 +243,9 @@ pub fn command<'a>(_params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueT
     let mut array: Vec<ValueType> = Vec::new();
     let args: Vec<String> = env::args().collect();
     for value in args {
-        array.push(ValueType::String(value))
+        array.push(ValueType::String(value.into()))
     }
-    Ok(ValueType::Array(array))
+    Ok(ValueType::Array(array.into()))
 }
 
 pub fn seconds<'a>(_params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
@@ -134,7 +260,7 @@ pub fn now<'a>(_params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<
     let now: DateTime<Local> = now.into();
     let now = now.to_rfc3339();
 
-    Ok(ValueType::String(now))
+    Ok(ValueType::String(now.into()))
 }
 
 pub fn date_add<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
@@ -181,18 +307,20 @@ pub fn date_add<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueT
 
     let result = result.unwrap().to_rfc3339();
 
-    Ok(ValueType::String(result))
+    Ok(ValueType::String(result.into()))
 }
 
 pub fn len<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
     if let Some(val) = params.first() {
         let len = match val {
             ValueType::Array(v) => v.len(),
+            ValueType::Map(m) => m.len(),
             ValueType::Str(s) => s.len(),
             ValueType::String(s) => s.len(),
             ValueType::Number(_) => 8,
             ValueType::Boolean(_) => 1,
             ValueType::Func(_, arity) => *arity as usize,
+            ValueType::Closure(_, arity, _) => *arity as usize,
             _ => 0
             //ValueType::Struct(s) => s.len(),
             //ValueType::PointerG(_) => panic!("Pointers not implemented"),
@@ -205,21 +333,35 @@ pub fn len<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'
     }
 }
 
-pub fn dir<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
+// the part of a glob pattern before any wildcards
+fn glob_dir(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|c| !c.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+        .collect()
+}
+
+pub fn dir<'a>(params: Vec<ValueType<'a>>, vm: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
     if let Some(val) = params.first() {
         let pattern = val.to_string();
+        vm.permit(vm.sandbox.check_read(&glob_dir(&pattern)))?;
         let file = glob(pattern.as_str());
         match file {
             Ok(paths) => {
                 let mut array: Vec<ValueType> = Vec::new();
                 for file in paths {
                     if let Ok(file) = file {
+                        // a pattern like allowed/*/../../* can match files outside
+                        // the directory checked above
+                        if vm.sandbox.check_read(&file).is_err() {
+                            continue;
+                        }
                         let s = file.to_string_lossy();
                         let y = String::from(s);
-                        array.push(ValueType::String(y));
+                        array.push(ValueType::String(y.into()));
                     }
                 }
-                return Ok(ValueType::Array(array));
+                return Ok(ValueType::Array(array.into()));
             }
             Err(_) => {
                 return Err("can't read");
@@ -276,7 +418,7 @@ pub fn chr<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'
                 let num = *num as u8;
                 if num.is_ascii() {
                     let ch = num as char;
-                    return Ok(ValueType::String(ch.to_string()));
+                    return Ok(ValueType::String(ch.to_string().into()));
                 }
             }
         }
@@ -299,22 +441,20 @@ pub fn asc<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'
     Ok(ValueType::Number(0.0))
 }
 
-pub fn readlines<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
+pub fn readlines<'a>(
+    params: Vec<ValueType<'a>>,
+    vm: &mut Vm<'a>,
+) -> Result<ValueType<'a>, &'a str> {
     if let Some(param) = params.first() {
         let filename = param.to_string();
+        vm.permit(vm.sandbox.check_read(Path::new(&filename)))?;
 
         let result = read_to_string(filename);
         let lines: Vec<ValueType> = match result {
-            Ok(text) => text
-                .lines()
-                .map(|x| ValueType::String(String::from(x)))
-                .collect(),
-            Err(_) => ""
-                .lines()
-                .map(|x| ValueType::String(String::from(x)))
-                .collect(),
+            Ok(text) => text.lines().map(|x| ValueType::String(x.into())).collect(),
+            Err(_) => "".lines().map(|x| ValueType::String(x.into())).collect(),
         };
-        Ok(ValueType::Array(lines))
+        Ok(ValueType::Array(lines.into()))
     } else {
         Err("No parameters passed to readlines()")
     }
@@ -327,6 +467,7 @@ pub fn setting_set<'a>(
     if params.len() < 2 {
         return Err("Incorrect number of parameters passed to setting_set(key, value)");
     }
+    vm.permit(vm.sandbox.check_settings())?;
 
     let result = read_to_string(&vm.config_file);
     let mut settings: HashMap<&str, String> = HashMap::new();
@@ -352,11 +493,11 @@ pub fn setting_set<'a>(
             let result = file.write(json.as_bytes());
             return match result {
                 Ok(_) => Ok(ValueType::Boolean(true)),
-                Err(msg) => Ok(ValueType::String(msg.to_string())),
+                Err(msg) => Ok(ValueType::String(msg.to_string().into())),
             };
         }
         Err(msg) => {
-            return Ok(ValueType::String(msg.to_string()));
+            return Ok(ValueType::String(msg.to_string().into()));
         }
     }
 }
@@ -368,6 +509,7 @@ pub fn setting_get<'a>(
     if params.len() < 1 {
         return Err("Incorrect number of parameters passed to setting_get(key)");
     }
+    vm.permit(vm.sandbox.check_settings())?;
 
     let result = read_to_string(&vm.config_file);
     let settings: HashMap<&str, String>;
@@ -385,10 +527,10 @@ pub fn setting_get<'a>(
         ""
     };
 
-    Ok(ValueType::String(value.to_string()))
+    Ok(ValueType::String(value.into()))
 }
 
-pub fn write<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
+pub fn write<'a>(params: Vec<ValueType<'a>>, vm: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
     let mut params_iter = params.iter();
     let p1 = params_iter.next();
     let p2 = params_iter.next();
@@ -398,6 +540,7 @@ pub fn write<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType
     if let Some(param) = p1 {
         let filename = param.to_string();
         let contents = p2.unwrap().to_string();
+        vm.permit(vm.sandbox.check_write(Path::new(&filename)))?;
 
         let data_file = File::create(filename);
 
@@ -406,11 +549,11 @@ pub fn write<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType
                 let result = file.write(contents.as_bytes());
                 return match result {
                     Ok(_) => Ok(ValueType::Boolean(true)),
-                    Err(msg) => Ok(ValueType::String(msg.to_string())),
+                    Err(msg) => Ok(ValueType::String(msg.to_string().into())),
                 };
             }
             Err(msg) => {
-                return Ok(ValueType::String(msg.to_string()));
+                return Ok(ValueType::String(msg.to_string().into()));
             }
         }
     } else {
@@ -418,7 +561,7 @@ pub fn write<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType
     }
 }
 
-pub fn append<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
+pub fn append<'a>(params: Vec<ValueType<'a>>, vm: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
     let mut params_iter = params.iter();
     let p1 = params_iter.next();
     let p2 = params_iter.next();
@@ -428,6 +571,7 @@ pub fn append<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueTyp
     if let Some(param) = p1 {
         let filename = param.to_string();
         let contents = p2.unwrap().to_string();
+        vm.permit(vm.sandbox.check_write(Path::new(&filename)))?;
 
         let data_file = OpenOptions::new().append(true).create(true).open(filename);
 
@@ -436,11 +580,11 @@ pub fn append<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueTyp
                 let result = file.write(contents.as_bytes());
                 return match result {
                     Ok(_) => Ok(ValueType::Boolean(true)),
-                    Err(msg) => Ok(ValueType::String(msg.to_string())),
+                    Err(msg) => Ok(ValueType::String(msg.to_string().into())),
                 };
             }
             Err(msg) => {
-                return Ok(ValueType::String(msg.to_string()));
+                return Ok(ValueType::String(msg.to_string().into()));
             }
         }
     } else {
@@ -530,7 +674,7 @@ pub fn rgb<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'
     }
 
     let hex_string = format!("#{}", hex::encode([r, g, b]));
-    Ok(ValueType::String(hex_string))
+    Ok(ValueType::String(hex_string.into()))
 }
 
 pub fn window<'a>(_params: Vec<ValueType<'a>>, vm: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
@@ -9,6 +9,12 @@ pub struct Graphics {
     height: i32,
 }
 
+impl Default for Graphics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Graphics {
     pub fn new() -> Self {
         Graphics {
@@ -0,0 +1,67 @@
+use std::rc::Rc;
+
+use super::{ValueType, Vm};
+
+fn map_key<'a>(key: &ValueType) -> Result<String, &'a str> {
+    match key {
+        ValueType::Str(_) | ValueType::String(_) => Ok(key.to_string()),
+        _ => Err("Map key must be a string"),
+    }
+}
+
+pub fn keys<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
+    if let Some(ValueType::Map(map)) = params.first() {
+        let keys = map
+            .keys()
+            .map(|k| ValueType::String(k.as_str().into()))
+            .collect();
+        return Ok(ValueType::Array(Rc::new(keys)));
+    }
+    Err("Incorrect parameters passed to keys(map)")
+}
+
+pub fn values<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
+    if let Some(ValueType::Map(map)) = params.first() {
+        let values = map.values().cloned().collect();
+        return Ok(ValueType::Array(Rc::new(values)));
+    }
+    Err("Incorrect parameters passed to values(map)")
+}
+
+pub fn haskey<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
+    if params.len() < 2 {
+        return Err("Incorrect number of parameters passed to haskey(map, key)");
+    }
+    if let ValueType::Map(map) = &params[0] {
+        let key = map_key(&params[1])?;
+        return Ok(ValueType::Boolean(map.contains_key(&key)));
+    }
+    Err("Incorrect parameters passed to haskey(map, key)")
+}
+
+pub fn remove<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
+    if params.len() < 2 {
+        return Err("Incorrect number of parameters passed to remove(map, key)");
+    }
+    if let ValueType::Map(map) = &params[0] {
+        let key = map_key(&params[1])?;
+        let mut result = map.clone();
+        result.remove(&key);
+        return Ok(ValueType::Map(result));
+    }
+    Err("Incorrect parameters passed to remove(map, key)")
+}
+
+pub fn remove_mut<'a>(
+    map: &mut ValueType<'a>,
+    params: Vec<ValueType<'a>>,
+) -> Result<ValueType<'a>, &'a str> {
+    if params.is_empty() {
+        return Err("Incorrect number of parameters passed to remove(key)");
+    }
+    if let ValueType::Map(ref mut map) = map {
+        let key = map_key(&params[0])?;
+        return Ok(ValueType::Boolean(map.remove(&key).is_some()));
+    }
+    Err("Incorrect parameters passed to remove(key)")
+}
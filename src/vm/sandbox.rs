@@ -0,0 +1,133 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// What a script is allowed to do outside of the VM: run system commands, use
+/// settings and read or write files.
+#[derive(Clone, Debug)]
+pub struct Sandbox {
+    /// When true there are no restrictions and the other fields are ignored
+    pub allow_all: bool,
+    pub allow_system: bool,
+    pub allow_settings: bool,
+    pub read_dirs: Vec<PathBuf>,
+    pub write_dirs: Vec<PathBuf>,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox::allow_all()
+    }
+}
+
+impl Sandbox {
+    pub fn allow_all() -> Self {
+        Sandbox {
+            allow_all: true,
+            ..Sandbox::deny_all()
+        }
+    }
+
+    pub fn deny_all() -> Self {
+        Sandbox {
+            allow_all: false,
+            allow_system: false,
+            allow_settings: false,
+            read_dirs: Vec::new(),
+            write_dirs: Vec::new(),
+        }
+    }
+
+    pub fn check_system(&self, command: &str) -> Result<(), String> {
+        if self.allow_all || self.allow_system {
+            return Ok(());
+        }
+        Err(format!(
+            "Permission denied: running system command {command} is not allowed (use --allow-system)"
+        ))
+    }
+
+    pub fn check_settings(&self) -> Result<(), String> {
+        if self.allow_all || self.allow_settings {
+            return Ok(());
+        }
+        Err(String::from(
+            "Permission denied: settings are not allowed (use --allow-settings)",
+        ))
+    }
+
+    pub fn check_read(&self, path: &Path) -> Result<(), String> {
+        if self.allow_all || is_inside(path, &self.read_dirs) {
+            return Ok(());
+        }
+        Err(format!(
+            "Permission denied: reading {} is not allowed (use --allow-read=<dir>)",
+            path.display()
+        ))
+    }
+
+    pub fn check_write(&self, path: &Path) -> Result<(), String> {
+        if self.allow_all || is_inside(path, &self.write_dirs) {
+            return Ok(());
+        }
+        Err(format!(
+            "Permission denied: writing {} is not allowed (use --allow-write=<dir>)",
+            path.display()
+        ))
+    }
+}
+
+fn is_inside(path: &Path, dirs: &[PathBuf]) -> bool {
+    let path = match resolve(path) {
+        Some(path) => path,
+        None => return false,
+    };
+    dirs.iter()
+        .filter_map(|dir| fs::canonicalize(dir).ok())
+        .any(|dir| path.starts_with(dir))
+}
+
+// The absolute path with symlinks and '..' resolved. Files being written may not
+// exist yet, so the nearest existing directory is resolved and the rest added on.
+fn resolve(path: &Path) -> Option<PathBuf> {
+    let absolute = env::current_dir().ok()?.join(path);
+    let mut existing = absolute.as_path();
+    let mut rest = Vec::new();
+    loop {
+        if let Ok(mut resolved) = fs::canonicalize(existing) {
+            resolved.extend(rest.iter().rev());
+            return Some(resolved);
+        }
+        // file_name is None for '..', which can't be resolved without the directory
+        rest.push(existing.file_name()?);
+        existing = existing.parent()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Sandbox;
+    use std::{env, fs, path::Path};
+
+    #[test]
+    fn test_paths() {
+        let dir = env::temp_dir().join("vbas_sandbox_test");
+        fs::create_dir_all(dir.join("allowed")).unwrap();
+        let mut sandbox = Sandbox::deny_all();
+        sandbox.write_dirs.push(dir.join("allowed"));
+
+        assert!(sandbox.check_write(&dir.join("allowed/new.txt")).is_ok());
+        assert!(sandbox.check_write(&dir.join("allowed/new/a.txt")).is_ok());
+        assert!(sandbox.check_write(&dir.join("other.txt")).is_err());
+        assert!(sandbox
+            .check_write(&dir.join("allowed/../other.txt"))
+            .is_err());
+        assert!(sandbox
+            .check_write(&dir.join("allowed/new/../../other.txt"))
+            .is_err());
+        assert!(sandbox.check_read(&dir.join("allowed/new.txt")).is_err());
+        assert!(sandbox.check_system("ls").is_err());
+        assert!(Sandbox::allow_all().check_read(Path::new("/")).is_ok());
+    }
+}
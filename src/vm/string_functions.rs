@@ -21,7 +21,7 @@ pub fn mid<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'
         return Err("Parameter 'start' of mid(string, start[,length]) must be a number");
     };
     if start >= string.len() {
-        return Ok(ValueType::String(String::from("")));
+        return Ok(ValueType::String("".into()));
     }
 
     if let Some(param) = params.get(2) {
@@ -41,12 +41,12 @@ pub fn mid<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'
         }
 
         let result = &string[start..start + length];
-        return Ok(ValueType::String(String::from(result)));
+        return Ok(ValueType::String(result.into()));
     }
 
     let result = &string[start..];
 
-    Ok(ValueType::String(String::from(result)))
+    Ok(ValueType::String(result.into()))
 }
 
 pub fn left<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
@@ -63,10 +63,10 @@ pub fn left<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<
         return Err("Parameter 'length' of left(string, length) must be a number");
     };
     if start >= string.len() {
-        return Ok(ValueType::String(string));
+        return Ok(ValueType::String(string.into()));
     }
 
-    Ok(ValueType::String(String::from(&string[..start])))
+    Ok(ValueType::String(string[..start].into()))
 }
 
 pub fn right<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
@@ -83,10 +83,10 @@ pub fn right<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType
         return Err("Parameter 'length' of right(string, length) must be a number");
     };
     if length >= string.len() {
-        return Ok(ValueType::String(string));
+        return Ok(ValueType::String(string.into()));
     }
     let start = string.len() - length;
-    Ok(ValueType::String(String::from(&string[start..])))
+    Ok(ValueType::String(string[start..].into()))
 }
 
 pub fn vb_format_num(format: String, number: f64) -> (String, f64) {
@@ -129,7 +129,7 @@ pub fn str<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'
         }
     }
 
-    Ok(ValueType::String(string))
+    Ok(ValueType::String(string.into()))
 }
 
 pub fn lcase<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
@@ -138,7 +138,7 @@ pub fn lcase<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType
     }
     let string = params[0].to_string().to_lowercase();
 
-    Ok(ValueType::String(string))
+    Ok(ValueType::String(string.into()))
 }
 
 pub fn ucase<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
@@ -147,7 +147,7 @@ pub fn ucase<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType
     }
     let string = params[0].to_string().to_uppercase();
 
-    Ok(ValueType::String(string))
+    Ok(ValueType::String(string.into()))
 }
 
 pub fn instr<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
@@ -218,7 +218,7 @@ pub fn replace<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueTy
     let rep = params[2].to_string();
 
     let result = str.replace(&search, rep.as_str());
-    Ok(ValueType::String(result))
+    Ok(ValueType::String(result.into()))
 }
 
 pub fn split<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType<'a>, &'a str> {
@@ -253,11 +253,11 @@ pub fn split<'a>(params: Vec<ValueType<'a>>, _: &mut Vm<'a>) -> Result<ValueType
     let result: Vec<ValueType> = if remove_empty {
         parts
             .filter(|x| !x.is_empty())
-            .map(|x| ValueType::String(x.to_string()))
+            .map(|x| ValueType::String(x.into()))
             .collect()
     } else {
-        parts.map(|x| ValueType::String(x.to_string())).collect()
+        parts.map(|x| ValueType::String(x.into())).collect()
     };
 
-    Ok(ValueType::Array(result))
+    Ok(ValueType::Array(result.into()))
 }